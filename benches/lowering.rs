@@ -0,0 +1,60 @@
+//! Compares the naive push/pop lowering with the stack-cached one on straight-line arithmetic
+//! and on arithmetic inside `if` blocks, where the cache is spilled at every boundary.
+//!
+//! Run with `cargo bench --bench lowering`.
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn main() -> anyhow::Result<()> {
+    use std::time::Instant;
+    use wa::{context::Context, jit::Jit, native::Lowering, parse::parse_ops, tokenise::Tokeniser};
+
+    const RUNS: u32 = 200;
+    let cases = [
+        ("arithmetic", "20 30 + 2 * 5 - 3 /% + 7 swap - dup * drop\n"),
+        (
+            "blocks",
+            "1 bool if 4 5 + 6 * 1 bool if 2 swap - end drop end\n",
+        ),
+        ("display", "1 2 + . 3 4 * .\n"),
+    ];
+
+    println!(
+        "{:<12} {:<14} {:>12} {:>12}",
+        "program", "lowering", "code bytes", "per run"
+    );
+    for (name, body) in cases {
+        let source = body.repeat(2000);
+        let tokens = Tokeniser::new(source.as_bytes()).collect::<Vec<_>>();
+        let prog = parse_ops(tokens, name)?;
+        let mut times = vec![];
+        for lowering in [Lowering::Naive, Lowering::StackCached] {
+            let jit = Jit::with_lowering(name, &prog, lowering)?;
+            let mut ctx = Context::new(std::io::empty(), std::io::sink(), std::io::sink());
+            jit.run(&mut ctx)?;
+            let start = Instant::now();
+            for _ in 0..RUNS {
+                jit.run(&mut ctx)?;
+            }
+            let per_run = start.elapsed() / RUNS;
+            times.push(per_run);
+            println!(
+                "{name:<12} {:<14} {:>12} {:>12?}",
+                format!("{lowering:?}"),
+                jit.code_len(),
+                per_run
+            );
+        }
+        let speedup = times[0].as_secs_f64() / times[1].as_secs_f64();
+        println!(
+            "{name:<12} {:<14} {:>25}",
+            "speedup",
+            format!("{speedup:.2}x")
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+fn main() {
+    println!("the JIT is only available on x86-64 Linux");
+}
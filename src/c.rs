@@ -0,0 +1,181 @@
+use std::fmt::Write;
+
+use crate::{
+    check::{check, Fault},
+    ops::{Op, Op1_0, Op1_2, Op2_1, Op2_2},
+    parse::Program,
+    tokenise::Span,
+};
+
+const PRELUDE: &str = r#"#include <inttypes.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+static int64_t stack[STACK_SIZE];
+static size_t sp = 0;
+
+static inline void fail(const char *at, const char *msg) {
+    fflush(stdout);
+    fprintf(stderr, "Error: %s: %s\n", at, msg);
+    exit(1);
+}
+
+static inline void need(const char *at, size_t n) {
+    if (sp < n) {
+        fflush(stdout);
+        fprintf(stderr, "Error: %s: Stack Underflow, expected at least %zu element(s), got %zu\n", at, n, sp);
+        exit(1);
+    }
+}
+
+static inline void push(int64_t v) { stack[sp++] = v; }
+static inline int64_t pop(void) { return stack[--sp]; }
+
+/* arithmetic wraps on overflow, as it does in every other backend */
+static inline int64_t add(int64_t a, int64_t b) { return (int64_t)((uint64_t)a + (uint64_t)b); }
+static inline int64_t sub(int64_t a, int64_t b) { return (int64_t)((uint64_t)a - (uint64_t)b); }
+static inline int64_t mul(int64_t a, int64_t b) { return (int64_t)((uint64_t)a * (uint64_t)b); }
+
+static inline int64_t divisor(const char *at, int64_t b) {
+    if (b == 0) fail(at, "division by zero");
+    return b;
+}
+
+static inline int64_t quot(int64_t a, int64_t b) { return b == -1 ? sub(0, a) : a / b; }
+static inline int64_t rem(int64_t a, int64_t b) { return b == -1 ? 0 : a % b; }
+
+static inline int cond(const char *at) {
+    int64_t c = pop();
+    if (c != 0 && c != 1) {
+        fflush(stdout);
+        fprintf(stderr, "Error: %s: expected bool, got %" PRId64 "\n", at, c);
+        exit(1);
+    }
+    return c == 1;
+}
+
+static inline void balanced(const char *at, size_t entry) {
+    if (sp != entry) {
+        fflush(stdout);
+        fprintf(stderr, "Error: %s: conditional execution must not alter stack length. expected: %zu, got: %zu\n", at, entry, sp);
+        exit(1);
+    }
+}
+"#;
+
+/// `s` as a C string literal.
+fn literal(s: &str) -> String {
+    let mut out = String::from("\"");
+    for b in s.bytes() {
+        match b {
+            b'"' | b'\\' => {
+                out.push('\\');
+                out.push(b as char);
+            }
+            b' '..=b'~' => out.push(b as char),
+            b => {
+                let _ = write!(out, "\\{b:03o}");
+            }
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Translates `program` into a single self-contained C file.
+///
+/// The data stack is a fixed array sized by [`check`], and `if` blocks become nested C `if`
+/// statements. Every op checks its inputs at runtime and reports errors exactly as the
+/// interpreter would, so the output doubles as a cross-check of the other backends.
+pub fn emit(file_name: impl AsRef<str>, Program { ops, .. }: &Program) -> String {
+    let file_name = file_name.as_ref();
+    let checked = check(ops);
+    let at = |i: usize| literal(&ops[i].idx.as_stamp(file_name));
+
+    let mut out = format!("#define STACK_SIZE {}\n", checked.max_depth.max(1));
+    out.push_str(PRELUDE);
+    out.push_str("\nint main(void) {\n");
+    // the op blamed for leftover data is the last one executed
+    let last = match ops.len() {
+        0 => literal(""),
+        n => at(n - 1),
+    };
+    let _ = writeln!(out, "    const char *last = {last};");
+
+    let mut blocks = vec![];
+    for (i, Span { token: op, .. }) in ops.iter().enumerate() {
+        let indent = "    ".repeat(blocks.len() + 1);
+        let (n_in, _) = op.arity();
+        if n_in > 0 {
+            let _ = writeln!(out, "{indent}need({}, {n_in});", at(i));
+        }
+        let stmt = match *op {
+            // `-9223372036854775808` would parse as the negation of an out of range literal
+            Op::Push(isize::MIN) => "push(INT64_MIN);".to_string(),
+            Op::Push(n) => format!("push(INT64_C({n}));"),
+            Op::Intr1_0(Op1_0::Display) => r#"printf("%" PRId64 "\n", pop());"#.to_string(),
+            Op::Intr1_0(Op1_0::Drop) => "sp--;".to_string(),
+            Op::Intr1_2(Op1_2::Duplicate) => "push(stack[sp - 1]);".to_string(),
+            Op::Intr2_1(op_id) => {
+                let expr = match op_id {
+                    Op2_1::Add => "add(t, t1)".to_string(),
+                    Op2_1::Sub => "sub(t, t1)".to_string(),
+                    Op2_1::Mul => "mul(t, t1)".to_string(),
+                    Op2_1::Div => format!("quot(t, divisor({}, t1))", at(i)),
+                    Op2_1::Mod => format!("rem(t, divisor({}, t1))", at(i)),
+                    Op2_1::Equ => "t == t1".to_string(),
+                    Op2_1::Less => "t < t1".to_string(),
+                    Op2_1::Greater => "t > t1".to_string(),
+                    Op2_1::LessEqu => "t <= t1".to_string(),
+                    Op2_1::GreaterEqu => "t >= t1".to_string(),
+                };
+                format!("{{ int64_t t = pop(), t1 = pop(); push({expr}); }}")
+            }
+            Op::Intr2_2(Op2_2::DivMod) => format!(
+                "{{ int64_t t = pop(), t1 = divisor({}, pop()); push(rem(t, t1)); push(quot(t, t1)); }}",
+                at(i)
+            ),
+            Op::Intr2_2(Op2_2::Swap) => {
+                "{ int64_t t = pop(), t1 = pop(); push(t); push(t1); }".to_string()
+            }
+            Op::If(target) => {
+                let _ = writeln!(out, "{indent}if (cond({})) {{", at(i));
+                let _ = writeln!(out, "{indent}    size_t entry{i} = sp;");
+                blocks.push((i, target.0 == ops.len()));
+                continue;
+            }
+            Op::End => match blocks.pop() {
+                Some((entry, closes_program)) => {
+                    let _ = writeln!(out, "{indent}balanced({}, entry{entry});", at(i));
+                    let indent = &indent[4..];
+                    match closes_program {
+                        true => {
+                            let _ = writeln!(out, "{indent}}} else {{");
+                            let _ = writeln!(out, "{indent}    last = {};", at(entry));
+                            let _ = writeln!(out, "{indent}}}");
+                        }
+                        false => {
+                            let _ = writeln!(out, "{indent}}}");
+                        }
+                    }
+                    continue;
+                }
+                None => format!("fail({}, \"Unbalanced END expr\");", at(i)),
+            },
+            // the type is only known statically, and `need` above checks the value is there
+            Op::Cast(_) => continue,
+            Op::Assert(_) | Op::AssertStack(_) => unreachable!("assertions only parse inside test blocks"),
+        };
+        let _ = writeln!(out, "{indent}{stmt}");
+    }
+
+    // every run that gets this far leaves the depth the checker found
+    if let Some(remaining @ 1..) = checked.exit_depth {
+        let msg = literal(&Fault::Leftover { remaining }.to_string());
+        let _ = writeln!(out, "    fail(last, {msg});");
+    }
+    out.push_str("    return 0;\n");
+    out.push_str("}\n");
+    out
+}
@@ -0,0 +1,212 @@
+use crate::{ops::Op, tokenise::Span};
+
+/// A runtime error the interpreter is certain to raise if execution reaches the op.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    Underflow {
+        need: usize,
+        got: usize,
+    },
+    Unbalanced {
+        expected: usize,
+        got: usize,
+    },
+    UnbalancedEnd,
+    /// Data left on the stack once the last op has run, which is raised at the end of the
+    /// program rather than at an op, so it is never in [`Checked::faults`].
+    Leftover {
+        remaining: usize,
+    },
+}
+
+impl std::fmt::Display for Fault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Fault::Underflow { need, got } => write!(
+                f,
+                "Stack Underflow, expected at least {need} element(s), got {got}"
+            ),
+            Fault::Unbalanced { expected, got } => write!(
+                f,
+                "conditional execution must not alter stack length. expected: {expected}, got: {got}"
+            ),
+            Fault::UnbalancedEnd => write!(f, "Unbalanced END expr"),
+            Fault::Leftover { remaining } => write!(
+                f,
+                "Unhandled data on the stack. {remaining} element(s) remaining after last operation"
+            ),
+        }
+    }
+}
+
+/// The statically known stack depth at every op.
+///
+/// Control flow is limited to `if`/`end`, where a skipped block resumes at the depth it was
+/// entered with and a taken block must end there too, so the depth on entry to each op is the
+/// same on every path that reaches it. Stack faults are therefore fully decided before execution.
+#[derive(Debug, Clone)]
+pub struct Checked {
+    /// Depth on entry to each op, `None` where every path to the op faults first.
+    pub depths: Vec<Option<usize>>,
+    pub faults: Vec<Option<Fault>>,
+    /// Depth once the last op has run, `None` if the program always faults.
+    pub exit_depth: Option<usize>,
+    pub max_depth: usize,
+}
+
+impl Checked {
+    /// Depth on entry to the op at `ip`, where `ip == ops.len()` is the end of the program.
+    pub fn depth_at(&self, ip: usize) -> Option<usize> {
+        match ip == self.depths.len() {
+            true => self.exit_depth,
+            false => self.depths[ip],
+        }
+    }
+}
+
+pub fn check(ops: &[Span<Op>]) -> Checked {
+    let mut depths = Vec::with_capacity(ops.len());
+    let mut faults = vec![None; ops.len()];
+    let mut incoming = vec![None; ops.len() + 1];
+    let mut blocks: Vec<Option<usize>> = vec![];
+    let mut max_depth = 0;
+
+    let mut fallthrough = Some(0);
+    for (i, Span { token: op, .. }) in ops.iter().enumerate() {
+        let depth = incoming[i].or(fallthrough);
+        depths.push(depth);
+        let (n_in, n_out) = op.arity();
+
+        fallthrough = match (op, depth) {
+            (Op::End, depth) => match (blocks.pop(), depth) {
+                (_, None) => None,
+                (None, Some(_)) => {
+                    faults[i] = Some(Fault::UnbalancedEnd);
+                    None
+                }
+                (Some(entry), Some(d)) => {
+                    if entry != Some(d) {
+                        faults[i] = entry.map(|expected| Fault::Unbalanced { expected, got: d });
+                    }
+                    entry
+                }
+            },
+            (Op::If(_), None) => {
+                blocks.push(None);
+                None
+            }
+            (_, None) => None,
+            (_, Some(d)) if d < n_in => {
+                faults[i] = Some(Fault::Underflow { need: n_in, got: d });
+                if let Op::If(_) = op {
+                    blocks.push(None);
+                }
+                None
+            }
+            (Op::If(target), Some(d)) => {
+                blocks.push(Some(d - 1));
+                if let Some(to) = incoming.get_mut(target.0) {
+                    *to = Some(d - 1);
+                }
+                Some(d - 1)
+            }
+            (_, Some(d)) => Some(d - n_in + n_out),
+        };
+        max_depth = max_depth
+            .max(depth.unwrap_or(0))
+            .max(fallthrough.unwrap_or(0));
+    }
+
+    Checked {
+        depths,
+        faults,
+        exit_depth: incoming[ops.len()].or(fallthrough),
+        max_depth,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ops::Op1_0, parse::parse_str, tokenise::TokenIdx};
+
+    fn checked(src: &str) -> Checked {
+        check(&parse_str(src).unwrap().ops)
+    }
+
+    #[test]
+    fn tracks_the_depth_at_every_op() {
+        let c = checked("1 2 + 3 swap . .");
+        assert_eq!(
+            c.depths,
+            [0, 1, 2, 1, 2, 2, 1].map(Some),
+            "depth on entry to each op"
+        );
+        assert_eq!(c.faults, [None; 7]);
+        assert_eq!(c.exit_depth, Some(0));
+        assert_eq!(c.max_depth, 2);
+        assert_eq!(c.depth_at(2), Some(2));
+        assert_eq!(c.depth_at(7), Some(0), "the end of the program");
+    }
+
+    #[test]
+    fn joins_the_paths_around_a_block() {
+        let c = checked("5 1 if 2 3 + drop end 4");
+        assert_eq!(c.depths, [0, 1, 2, 1, 2, 3, 2, 1, 1].map(Some));
+        assert_eq!(c.exit_depth, Some(2));
+        assert_eq!(c.max_depth, 3);
+        // a block that closes the program reaches its end by skipping too
+        let c = checked("5 0 if 6 drop end");
+        assert_eq!(c.depth_at(6), Some(1));
+    }
+
+    #[test]
+    fn finds_underflows_and_what_they_cut_off() {
+        let c = checked("1 + 2 .");
+        assert_eq!(c.faults[1], Some(Fault::Underflow { need: 2, got: 1 }));
+        assert_eq!(c.depths, [Some(0), Some(1), None, None]);
+        assert_eq!(c.exit_depth, None);
+        assert_eq!(c.depth_at(4), None);
+        // a block whose `if` underflows is never entered
+        let c = checked("if 1 . end 2");
+        assert_eq!(c.faults[0], Some(Fault::Underflow { need: 1, got: 0 }));
+        assert_eq!(c.faults[4], None);
+        assert_eq!(c.depths[4], None);
+    }
+
+    #[test]
+    fn finds_blocks_that_change_the_depth() {
+        let c = checked("1 if 2 end 3 .");
+        assert_eq!(
+            c.faults[3],
+            Some(Fault::Unbalanced {
+                expected: 0,
+                got: 1
+            })
+        );
+        assert_eq!(c.faults.iter().flatten().count(), 1);
+        // skipping the block still reaches the rest at the depth it was entered with
+        assert_eq!(c.depths[4], Some(0));
+        assert_eq!(c.exit_depth, Some(0));
+    }
+
+    #[test]
+    fn finds_an_end_without_an_if() {
+        let ops = [Op::Push(1), Op::End, Op::Intr1_0(Op1_0::Drop)].map(|token| Span {
+            idx: TokenIdx::default(),
+            token,
+        });
+        let c = check(&ops);
+        assert_eq!(c.faults, [None, Some(Fault::UnbalancedEnd), None]);
+        assert_eq!(c.depths, [Some(0), Some(1), None]);
+        assert_eq!(c.max_depth, 1);
+    }
+
+    #[test]
+    fn formats_leftover_data() {
+        assert_eq!(
+            Fault::Leftover { remaining: 2 }.to_string(),
+            "Unhandled data on the stack. 2 element(s) remaining after last operation"
+        );
+    }
+}
@@ -0,0 +1,24 @@
+use std::io::{Read, Write};
+
+/// I/O handles that intrinsics run against, owned by the interpreter for the duration of a run.
+pub struct Context<'a> {
+    pub stdin: Box<dyn Read + 'a>,
+    pub stdout: Box<dyn Write + 'a>,
+    pub stderr: Box<dyn Write + 'a>,
+}
+
+impl<'a> Context<'a> {
+    pub fn new(stdin: impl Read + 'a, stdout: impl Write + 'a, stderr: impl Write + 'a) -> Self {
+        Self {
+            stdin: Box::new(stdin),
+            stdout: Box::new(stdout),
+            stderr: Box::new(stderr),
+        }
+    }
+}
+
+impl Context<'static> {
+    pub fn std() -> Self {
+        Self::new(std::io::stdin(), std::io::stdout(), std::io::stderr())
+    }
+}
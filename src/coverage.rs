@@ -0,0 +1,167 @@
+//! Coverage for `interp --coverage` and `test --coverage`: which ops ran and which way each `if`
+//! went, written as an lcov tracefile that points back at the lines of the `.wa` sources.
+//!
+//! Every `if` is a branch with two sides, numbered 0 for its block running and 1 for it being
+//! skipped.
+
+use std::collections::BTreeMap;
+
+use crate::{ops::Op, tokenise::Span};
+
+/// What one run of a sequence of ops covered.
+#[derive(Debug, Clone)]
+pub struct Coverage {
+    ops: Vec<Span<Op>>,
+    hits: Vec<usize>,
+    /// How often each `if` ran its block and skipped it.
+    sides: Vec<[usize; 2]>,
+}
+
+impl Coverage {
+    pub fn new(ops: &[Span<Op>]) -> Self {
+        Self {
+            ops: ops.to_vec(),
+            hits: vec![0; ops.len()],
+            sides: vec![[0; 2]; ops.len()],
+        }
+    }
+
+    /// Counts one execution of the op at `ip`.
+    pub fn record(&mut self, ip: usize) {
+        self.hits[ip] += 1;
+    }
+
+    /// Counts the `if` at `ip` running its block, or skipping it.
+    pub fn branch(&mut self, ip: usize, taken: bool) {
+        self.sides[ip][!taken as usize] += 1;
+    }
+}
+
+/// The coverage of one source file, merged over every run that touched it.
+#[derive(Debug, Clone, Default)]
+struct Record {
+    /// executions of each row that holds an op other than `end`
+    lines: BTreeMap<usize, usize>,
+    /// the sides of each `if`, by row and column
+    branches: BTreeMap<(usize, usize), [usize; 2]>,
+}
+
+/// Coverage of any number of runs over any number of files, written out by its [`Display`]
+/// implementation as an lcov tracefile.
+///
+/// [`Display`]: std::fmt::Display
+#[derive(Debug, Clone, Default)]
+pub struct Lcov {
+    files: BTreeMap<String, Record>,
+}
+
+impl Lcov {
+    /// Adds a run of ops from `source`, the path the tracefile will name.
+    pub fn add(&mut self, source: &str, coverage: &Coverage) {
+        let record = self.files.entry(source.to_string()).or_default();
+        // a line ran as often as its busiest op did
+        let mut lines = BTreeMap::new();
+        for (i, op) in coverage.ops.iter().enumerate() {
+            // a skipped block jumps past its `end`, which only closes it and is no line of code
+            if let Op::End = op.token {
+                continue;
+            }
+            let n = lines.entry(op.idx.row).or_insert(0);
+            *n = coverage.hits[i].max(*n);
+            if let Op::If(_) = op.token {
+                let sides = record.branches.entry((op.idx.row, op.idx.col)).or_default();
+                sides[0] += coverage.sides[i][0];
+                sides[1] += coverage.sides[i][1];
+            }
+        }
+        for (row, n) in lines {
+            *record.lines.entry(row).or_insert(0) += n;
+        }
+    }
+}
+
+impl std::fmt::Display for Lcov {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "TN:")?;
+        for (source, record) in &self.files {
+            writeln!(f, "SF:{source}")?;
+            // each `if` is its own block, numbered in source order
+            for (block, (&(row, _), sides)) in record.branches.iter().enumerate() {
+                let line = row + 1;
+                // sides of a branch that never ran are `-` rather than 0
+                let ran = sides[0] + sides[1] > 0;
+                for (branch, &n) in sides.iter().enumerate() {
+                    match ran {
+                        true => writeln!(f, "BRDA:{line},{block},{branch},{n}")?,
+                        false => writeln!(f, "BRDA:{line},{block},{branch},-")?,
+                    }
+                }
+            }
+            let sides = record.branches.values().flatten();
+            writeln!(f, "BRF:{}", record.branches.len() * 2)?;
+            writeln!(f, "BRH:{}", sides.filter(|&&n| n > 0).count())?;
+            for (row, n) in &record.lines {
+                writeln!(f, "DA:{},{n}", row + 1)?;
+            }
+            let lines = record.lines.values();
+            writeln!(f, "LF:{}", record.lines.len())?;
+            writeln!(f, "LH:{}", lines.filter(|&&n| n > 0).count())?;
+            writeln!(f, "end_of_record")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{context::Context, interp::Interpreter, parse::parse_str};
+
+    const SRC: &str = "\
+1 if
+  2 .
+end
+0 if
+  1 if
+    3 .
+  end
+end
+";
+
+    fn run() -> Coverage {
+        let ctx = Context::new(std::io::empty(), std::io::sink(), std::io::sink());
+        let mut interp = Interpreter::new("t.wa", parse_str(SRC).unwrap(), ctx);
+        interp.start_coverage();
+        interp.run().unwrap();
+        interp.coverage().unwrap().clone()
+    }
+
+    #[test]
+    fn writes_merged_runs_as_lcov() {
+        let mut lcov = Lcov::default();
+        lcov.add("t.wa", &run());
+        lcov.add("t.wa", &run());
+        // the nested `if` never ran, and lines holding only an `end` are no lines of code
+        let expected = "\
+TN:
+SF:t.wa
+BRDA:1,0,0,2
+BRDA:1,0,1,0
+BRDA:4,1,0,0
+BRDA:4,1,1,2
+BRDA:5,2,0,-
+BRDA:5,2,1,-
+BRF:6
+BRH:2
+DA:1,2
+DA:2,2
+DA:4,2
+DA:5,0
+DA:6,0
+LF:5
+LH:3
+end_of_record
+";
+        assert_eq!(lcov.to_string(), expected);
+    }
+}
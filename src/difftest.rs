@@ -0,0 +1,458 @@
+//! Differential testing: random well-formed programs are run through the interpreter and every
+//! other backend available on this machine, and any disagreement is shrunk to a small program
+//! that still shows it.
+
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    c, check::check, compile_program, context::Context, interp_program, opt, parse::parse_ops,
+    tokenise::Tokeniser, vm::Bytecode,
+};
+
+/// The name programs are stamped with in error messages, whichever backend runs them.
+const FILE_NAME: &str = "difftest.wa";
+
+/// A small xorshift generator, so a seed always reproduces the same program.
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // a zero state would only ever produce zeroes
+        Self(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn chance(&mut self, percent: usize) -> bool {
+        self.below(100) < percent
+    }
+}
+
+/// A random program of about `size` ops that never faults statically: every op has the inputs
+/// it needs and every `if` block leaves the stack as deep as it found it. Division by zero,
+/// conditions other than 0 or 1 and leftover data can still happen at runtime.
+pub fn generate(rng: &mut Rng, size: usize) -> String {
+    let mut out = vec![];
+    let depth = block(rng, &mut out, 0, size, 0);
+    // the rest of the time whatever is left over is an error
+    if rng.chance(90) {
+        out.extend((0..depth).map(|_| ".".to_string()));
+    }
+    let mut src = String::new();
+    for (i, tok) in out.iter().enumerate() {
+        src.push_str(tok);
+        src.push(match (i + 1) % 12 {
+            0 => '\n',
+            _ => ' ',
+        });
+    }
+    src
+}
+
+fn literal(rng: &mut Rng) -> String {
+    // `isize::MIN` has no literal, wrapping arithmetic reaches it instead
+    const EDGES: [isize; 8] = [0, 1, 2, -1, 7, -9, isize::MAX, isize::MIN + 1];
+    match rng.chance(30) {
+        true => EDGES[rng.below(EDGES.len())].to_string(),
+        false => (rng.below(200) as isize - 50).to_string(),
+    }
+}
+
+/// Appends ops to `out` starting at stack depth `depth`, returning the depth they end at.
+fn block(
+    rng: &mut Rng,
+    out: &mut Vec<String>,
+    mut depth: usize,
+    size: usize,
+    nest: usize,
+) -> usize {
+    const BINARY: [&str; 11] = ["+", "-", "*", "/", "%", "/%", "=", "<", ">", "<=", ">="];
+    const COMPARE: [&str; 5] = ["=", "<", ">", "<=", ">="];
+    let mut left = size;
+    while left > 0 {
+        left -= 1;
+        match rng.below(100) {
+            _ if depth < 2 => {
+                out.push(literal(rng));
+                depth += 1;
+            }
+            0..=29 => {
+                out.push(literal(rng));
+                depth += 1;
+            }
+            30..=37 => {
+                out.push("dup".into());
+                depth += 1;
+            }
+            38..=45 => out.push("swap".into()),
+            46..=51 => {
+                out.push("drop".into());
+                depth -= 1;
+            }
+            52..=59 => {
+                out.push(".".into());
+                depth -= 1;
+            }
+            60..=87 => {
+                let op = BINARY[rng.below(BINARY.len())];
+                // the divisor is second from the top, so a zero is only likely if put there
+                if op.starts_with('/') || op == "%" {
+                    out.push(match rng.chance(5) {
+                        true => "0".into(),
+                        false => (rng.below(20) + 1).to_string(),
+                    });
+                    out.push("swap".into());
+                    depth += 1;
+                }
+                out.push(op.into());
+                depth -= (op != "/%") as usize;
+            }
+            _ if nest >= 4 || left < 2 => {}
+            _ => {
+                match rng.below(20) {
+                    0..=11 => {
+                        out.push(COMPARE[rng.below(COMPARE.len())].into());
+                        depth -= 1;
+                    }
+                    12..=18 => {
+                        out.extend([["0", "1"][rng.below(2)].into(), "bool".into()]);
+                        depth += 1;
+                    }
+                    // anything else only gets past the check by luck
+                    _ => {
+                        out.push("dup".into());
+                        depth += 1;
+                    }
+                }
+                depth -= 1;
+                out.push("if".into());
+                let inner = rng.below(left.min(16)) + 1;
+                left -= inner;
+                let mut end = block(rng, out, depth, inner, nest + 1);
+                while end > depth {
+                    out.push("drop".into());
+                    end -= 1;
+                }
+                while end < depth {
+                    out.push(literal(rng));
+                    end += 1;
+                }
+                out.push("end".into());
+            }
+        }
+    }
+    depth
+}
+
+/// Something that can run a program and report what it did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Interp,
+    /// the interpreter after the peephole optimiser
+    Optimised,
+    Vm,
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    Jit,
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    Elf,
+    /// the C backend built with the system `cc`
+    C,
+}
+
+impl std::fmt::Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Backend::Interp => "interp",
+            Backend::Optimised => "interp -O",
+            Backend::Vm => "vm",
+            #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+            Backend::Jit => "jit",
+            #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+            Backend::Elf => "elf",
+            Backend::C => "c",
+        };
+        write!(f, "{s}")
+    }
+}
+
+impl Backend {
+    /// Every backend that can run here, the interpreter first.
+    pub fn available() -> Vec<Backend> {
+        let mut backends = vec![Backend::Interp, Backend::Optimised, Backend::Vm];
+        #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+        backends.extend([Backend::Jit, Backend::Elf]);
+        let cc = Command::new("cc").arg("--version").output();
+        if cc.is_ok_and(|o| o.status.success()) {
+            backends.push(Backend::C);
+        }
+        backends
+    }
+}
+
+/// What a run wrote and how it ended, as `wa` itself would show it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outcome {
+    pub stdout: String,
+    pub stderr: String,
+    pub code: i32,
+}
+
+impl std::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "exit code {}", self.code)?;
+        writeln!(f, "stdout: {:?}", self.stdout)?;
+        write!(f, "stderr: {:?}", self.stderr)
+    }
+}
+
+/// Runs programs, keeping whatever files the external backends need in `dir`.
+pub struct Harness {
+    pub backends: Vec<Backend>,
+    dir: PathBuf,
+}
+
+/// A backend that did something other than the interpreter.
+#[derive(Debug, Clone)]
+pub struct Mismatch {
+    pub backend: Backend,
+    pub expected: Outcome,
+    pub got: Outcome,
+}
+
+impl Harness {
+    pub fn new(backends: Vec<Backend>) -> anyhow::Result<Self> {
+        // harnesses in one process, such as concurrent tests, each need their own files
+        static HARNESSES: AtomicUsize = AtomicUsize::new(0);
+        let n = HARNESSES.fetch_add(1, Ordering::Relaxed);
+        let dir = std::env::temp_dir().join(format!("wa-difftest-{}-{n}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self { backends, dir })
+    }
+
+    pub fn run(&self, backend: Backend, src: &str) -> anyhow::Result<Outcome> {
+        self.run_as(backend, FILE_NAME, src)
+    }
+
+    /// Runs `src` as if it had been read from `file_name`, which errors are stamped with.
+    pub fn run_as(&self, backend: Backend, file_name: &str, src: &str) -> anyhow::Result<Outcome> {
+        let prog = match parse_ops(Tokeniser::new(src.as_bytes()).collect(), file_name) {
+            Ok(prog) => prog,
+            Err(e) => return Ok(failed(vec![], e)),
+        };
+        let mut stdout = vec![];
+        let res = match backend {
+            Backend::Interp => interp_program(file_name, prog, context(&mut stdout)),
+            Backend::Optimised => {
+                interp_program(file_name, opt::optimise(prog).0, context(&mut stdout))
+            }
+            Backend::Vm => Bytecode::new(file_name, &prog).run(&mut context(&mut stdout)),
+            #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+            Backend::Jit => crate::jit::Jit::new(file_name, &prog)?.run(&mut context(&mut stdout)),
+            #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+            Backend::Elf => {
+                let exe = self.dir.join("elf");
+                compile_program(file_name, &prog, &exe)?;
+                return execute(&exe);
+            }
+            Backend::C => {
+                let (source, exe) = (self.dir.join("prog.c"), self.dir.join("c"));
+                std::fs::write(&source, c::emit(file_name, &prog))?;
+                let cc = Command::new("cc")
+                    .args(["-std=c99", "-O1", "-o"])
+                    .args([&exe, &source])
+                    .output()?;
+                if !cc.status.success() {
+                    anyhow::bail!("cc failed:\n{}", String::from_utf8_lossy(&cc.stderr));
+                }
+                return execute(&exe);
+            }
+        };
+        Ok(match res {
+            Ok(()) => Outcome {
+                stdout: String::from_utf8_lossy(&stdout).into_owned(),
+                stderr: String::new(),
+                code: 0,
+            },
+            Err(e) => failed(stdout, e),
+        })
+    }
+
+    /// Runs `src` on every backend, returning the ones that disagree with the interpreter.
+    pub fn compare(&self, src: &str) -> anyhow::Result<Vec<Mismatch>> {
+        let expected = self.run(Backend::Interp, src)?;
+        let mut mismatches = vec![];
+        for &backend in &self.backends {
+            if backend == Backend::Interp {
+                continue;
+            }
+            let got = self.run(backend, src)?;
+            if got != expected {
+                mismatches.push(Mismatch {
+                    backend,
+                    expected: expected.clone(),
+                    got,
+                });
+            }
+        }
+        Ok(mismatches)
+    }
+
+    /// Shrinks `src` to a program on which `backend` still disagrees with the interpreter. Runs
+    /// of tokens are deleted, `if` blocks are dropped or run unconditionally and literals are
+    /// simplified for as long as that keeps failing. Like the generated programs, every
+    /// candidate has to be free of static faults.
+    pub fn shrink(&self, backend: Backend, src: &str) -> anyhow::Result<String> {
+        let fails = |toks: &[&str]| -> anyhow::Result<bool> {
+            let src = toks.join(" ");
+            let well_formed = parse_ops(Tokeniser::new(src.as_bytes()).collect(), FILE_NAME)
+                .is_ok_and(|prog| check(&prog.ops).faults.iter().all(Option::is_none));
+            Ok(well_formed && self.run(Backend::Interp, &src)? != self.run(backend, &src)?)
+        };
+        let mut toks = src.split_whitespace().collect::<Vec<_>>();
+
+        // halving first gets rid of most of a large program cheaply
+        let mut chunk = toks.len().div_ceil(2).max(1);
+        while chunk > 8 {
+            let mut i = 0;
+            while i < toks.len() {
+                let end = (i + chunk).min(toks.len());
+                let candidate = [&toks[..i], &toks[end..]].concat();
+                match fails(&candidate)? {
+                    true => toks = candidate,
+                    false => i += chunk,
+                }
+            }
+            chunk /= 2;
+        }
+
+        loop {
+            let mut changed = false;
+            let mut i = 0;
+            while i < toks.len() {
+                let Some(end) = matching_end(&toks, i) else {
+                    i += 1;
+                    continue;
+                };
+                // the condition is dropped either way, so the stack stays as deep
+                let skipped = [&toks[..i], &["drop"], &toks[end + 1..]].concat();
+                let inlined = [&toks[..i], &["drop"], &toks[i + 1..end], &toks[end + 1..]].concat();
+                match (fails(&skipped)?, fails(&inlined)?) {
+                    (true, _) => (toks, changed) = (skipped, true),
+                    (_, true) => (toks, changed) = (inlined, true),
+                    _ => i += 1,
+                }
+            }
+
+            for width in (1..=chunk).rev() {
+                let mut i = 0;
+                while i + width <= toks.len() {
+                    let candidate = [&toks[..i], &toks[i + width..]].concat();
+                    match fails(&candidate)? {
+                        true => (toks, changed) = (candidate, true),
+                        false => i += 1,
+                    }
+                }
+            }
+
+            for i in 0..toks.len() {
+                if toks[i].parse::<isize>().is_err() {
+                    continue;
+                }
+                for simpler in ["0", "1"].into_iter().take_while(|&s| s != toks[i]) {
+                    let mut candidate = toks.clone();
+                    candidate[i] = simpler;
+                    if fails(&candidate)? {
+                        (toks, changed) = (candidate, true);
+                        break;
+                    }
+                }
+            }
+            if !changed {
+                return Ok(toks.join(" "));
+            }
+        }
+    }
+}
+
+/// The index of the `end` closing the `if` at `at`, if there is one there.
+fn matching_end(toks: &[&str], at: usize) -> Option<usize> {
+    if toks[at] != "if" {
+        return None;
+    }
+    let mut depth = 0;
+    for (i, &tok) in toks.iter().enumerate().skip(at + 1) {
+        match tok {
+            "if" => depth += 1,
+            "end" if depth == 0 => return Some(i),
+            "end" => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn context(stdout: &mut Vec<u8>) -> Context<'_> {
+    Context::new(std::io::empty(), stdout, std::io::sink())
+}
+
+fn failed(stdout: Vec<u8>, e: anyhow::Error) -> Outcome {
+    Outcome {
+        stdout: String::from_utf8_lossy(&stdout).into_owned(),
+        stderr: format!("Error: {e}\n"),
+        code: 1,
+    }
+}
+
+fn execute(exe: &Path) -> anyhow::Result<Outcome> {
+    let out = Command::new(exe).output()?;
+    Ok(Outcome {
+        stdout: String::from_utf8_lossy(&out.stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&out.stderr).into_owned(),
+        // killed by a signal
+        code: out.status.code().unwrap_or(-1),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generation_is_reproducible() {
+        let src = generate(&mut Rng::new(7), 60);
+        assert_eq!(src, generate(&mut Rng::new(7), 60));
+    }
+
+    #[test]
+    fn backends_agree_on_generated_programs() {
+        let harness = Harness::new(Backend::available()).unwrap();
+        for seed in 0..16 {
+            let src = generate(&mut Rng::new(seed), 40);
+            let mismatches = harness.compare(&src).unwrap();
+            assert!(
+                mismatches.is_empty(),
+                "seed {seed}:\n{src}\n{mismatches:#?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,221 @@
+//! `compile --emit=dot`: the control-flow graph of a program in Graphviz DOT.
+//!
+//! Each node is a basic block, listing its ops and the static stack depth it is entered and left
+//! with. Blocks end at an `if`, which branches to its block when the condition is true and past
+//! its `end` when it is false, and at an `end`, after which both paths meet again. Test blocks
+//! are drawn as clusters of their own.
+
+use std::{fmt::Write, ops::Range};
+
+use crate::{
+    check::{check, Checked},
+    ops::Op,
+    parse::Program,
+    tokenise::Span,
+};
+
+pub fn emit(file_name: impl AsRef<str>, program: &Program) -> String {
+    let mut out = String::new();
+    writeln!(out, "digraph {} {{", quote(file_name.as_ref())).unwrap();
+    writeln!(out, "    node [shape=box, fontname=\"monospace\"];").unwrap();
+    graph(&mut out, "", &program.ops, "    ");
+    for (k, test) in program.tests.iter().enumerate() {
+        writeln!(out, "    subgraph cluster_t{k} {{").unwrap();
+        let label = format!("test \"{}\"", test.name);
+        writeln!(out, "        label={};", quote(&label)).unwrap();
+        graph(&mut out, &format!("t{k}_"), &test.program.ops, "        ");
+        writeln!(out, "    }}").unwrap();
+    }
+    out.push_str("}\n");
+    out
+}
+
+/// The basic blocks of `ops`, in order.
+fn blocks(ops: &[Span<Op>]) -> Vec<Range<usize>> {
+    let mut leaders = vec![0];
+    for (i, op) in ops.iter().enumerate() {
+        match op.token {
+            Op::If(target) => leaders.extend([i + 1, target.0]),
+            Op::End => leaders.push(i + 1),
+            _ => {}
+        }
+    }
+    leaders.retain(|&i| i < ops.len());
+    leaders.sort();
+    leaders.dedup();
+    let ends = leaders.iter().skip(1).copied().chain([ops.len()]);
+    leaders.iter().zip(ends).map(|(&s, e)| s..e).collect()
+}
+
+/// Writes the nodes and edges of `ops`, named with `prefix` so that several graphs can share a
+/// file.
+fn graph(out: &mut String, prefix: &str, ops: &[Span<Op>], indent: &str) {
+    let checked = check(ops);
+    let blocks = blocks(ops);
+    // the node starting at op `i`, or the exit once past the last one
+    let node = |i: usize| match blocks.iter().position(|b| b.start == i) {
+        Some(b) => format!("{prefix}b{b}"),
+        None => format!("{prefix}exit"),
+    };
+
+    writeln!(out, "{indent}{prefix}entry [shape=oval, label=\"entry\"];").unwrap();
+    let exit = match checked.exit_depth {
+        Some(d) => format!("exit, depth {d}"),
+        None => "exit, never reached".to_string(),
+    };
+    writeln!(
+        out,
+        "{indent}{prefix}exit [shape=oval, label={}];",
+        quote(&exit)
+    )
+    .unwrap();
+    writeln!(out, "{indent}{prefix}entry -> {};", node(0)).unwrap();
+
+    for (b, block) in blocks.iter().enumerate() {
+        let mut label = format!("b{b}: {}\\l", depths(&checked, ops, block.clone()));
+        for i in block.clone() {
+            let Span { idx, token } = ops[i];
+            let line = match checked.faults[i] {
+                Some(fault) => format!("{idx}  {token}  fails: {fault}"),
+                None => format!("{idx}  {token}"),
+            };
+            label.push_str(&escape(&line));
+            label.push_str("\\l");
+        }
+        writeln!(out, "{indent}{prefix}b{b} [label=\"{label}\"];").unwrap();
+
+        let last = block.end - 1;
+        match ops[last].token {
+            Op::If(target) => {
+                let (taken, skipped) = (node(last + 1), node(target.0));
+                writeln!(out, "{indent}{prefix}b{b} -> {taken} [label=\"true\"];").unwrap();
+                writeln!(out, "{indent}{prefix}b{b} -> {skipped} [label=\"false\"];").unwrap();
+            }
+            _ => writeln!(out, "{indent}{prefix}b{b} -> {};", node(block.end)).unwrap(),
+        }
+    }
+}
+
+/// The depth a block is entered and left with, as far as [`check`] knows.
+fn depths(checked: &Checked, ops: &[Span<Op>], block: Range<usize>) -> String {
+    let last = block.end - 1;
+    let Some(entry) = checked.depths[block.start] else {
+        return "never reached".to_string();
+    };
+    let exit = checked.depths[last]
+        .filter(|_| checked.faults[last].is_none())
+        .map(|d| {
+            let (n_in, n_out) = ops[last].token.arity();
+            d - n_in + n_out
+        });
+    match exit {
+        Some(exit) => format!("depth {entry} -> {exit}"),
+        None => format!("depth {entry}, then fails"),
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", escape(s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse_str;
+
+    fn dot(src: &str) -> String {
+        emit("t.wa", &parse_str(src).unwrap())
+    }
+
+    /// The lines of `dot` that start with `prefix`, trimmed.
+    fn lines<'a>(dot: &'a str, prefix: &str) -> Vec<&'a str> {
+        dot.lines()
+            .map(str::trim)
+            .filter(|l| l.starts_with(prefix))
+            .collect()
+    }
+
+    #[test]
+    fn splits_nested_ifs_into_blocks() {
+        let program = parse_str("1 if 2 if 3 . end end 4 .").unwrap();
+        assert_eq!(blocks(&program.ops), [0..2, 2..4, 4..7, 7..8, 8..10]);
+    }
+
+    #[test]
+    fn draws_both_sides_of_each_if() {
+        let nested = dot("1 if 2 if 3 . end end 4 .");
+        let edges = nested
+            .lines()
+            .map(str::trim)
+            .filter(|l| l.split(' ').nth(1) == Some("->"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            edges,
+            [
+                "entry -> b0;",
+                "b0 -> b1 [label=\"true\"];",
+                "b0 -> b4 [label=\"false\"];",
+                "b1 -> b2 [label=\"true\"];",
+                "b1 -> b3 [label=\"false\"];",
+                "b2 -> b3;",
+                "b3 -> b4;",
+                "b4 -> exit;",
+            ]
+        );
+        // skipping a block that closes the program goes straight to the exit
+        let closing = dot("5 0 if 6 drop end");
+        assert!(
+            closing.contains("b0 -> exit [label=\"false\"];"),
+            "{closing}"
+        );
+        assert!(closing.contains("b1 -> exit;"), "{closing}");
+        assert!(closing.contains("label=\"exit, depth 1\""), "{closing}");
+    }
+
+    #[test]
+    fn labels_blocks_with_their_depths() {
+        let dot = dot("drop 1 if 2 . end");
+        assert_eq!(
+            lines(&dot, "b0 [").concat(),
+            "b0 [label=\"b0: depth 0, then fails\\l1:1  DROP  fails: Stack Underflow, expected at \
+             least 1 element(s), got 0\\l1:6  PUSH 1\\l1:8  IF => 6\\l\"];"
+        );
+        assert!(dot.contains("b1 [label=\"b1: never reached\\l"), "{dot}");
+        assert!(dot.contains("label=\"exit, never reached\""), "{dot}");
+    }
+
+    #[test]
+    fn links_the_entry_of_an_empty_program_to_its_exit() {
+        assert_eq!(
+            dot(""),
+            "\
+digraph \"t.wa\" {
+    node [shape=box, fontname=\"monospace\"];
+    entry [shape=oval, label=\"entry\"];
+    exit [shape=oval, label=\"exit, depth 0\"];
+    entry -> exit;
+}
+"
+        );
+    }
+
+    #[test]
+    fn draws_test_blocks_as_clusters() {
+        let dot = dot("1 .\ntest \"adds\" 1 1 + 2 = assert end");
+        let cluster = "    subgraph cluster_t0 {
+        label=\"test \\\"adds\\\"\";
+        t0_entry [shape=oval, label=\"entry\"];
+        t0_exit [shape=oval, label=\"exit, depth 0\"];
+        t0_entry -> t0_b0;
+        t0_b0 [label=\"b0: depth 0 -> 0\\l2:13  PUSH 1\\l2:15  PUSH 1\\l2:17  ADD\\l2:19  PUSH 2\\l2:21  EQ\\l2:23  ASSERT\\l\"];
+        t0_b0 -> t0_exit;
+    }
+}
+";
+        assert!(dot.ends_with(cluster), "{dot}");
+    }
+}
@@ -0,0 +1,339 @@
+//! Stack effects inferred from the arity of each op, for `check --effects`.
+//!
+//! An effect is written `( in -- out )` with the top of the stack on the right, like the hover
+//! text of the language server. Values are named by their [`Type`] where it is known: `int` for
+//! what arithmetic takes and makes, `bool` for what comparisons make and `if` and `assert` take,
+//! and whatever a cast makes.
+//! Any other value taken from below is named by a letter, which it keeps if it is left on the
+//! stack, as in `( a b -- b a )` for `swap`, or `( a:int -- a int )` for `dup 1 +`.
+
+use std::ops::Range;
+
+use crate::{
+    ops::{Op, Op1_0, Op1_2, Op2_1, Op2_2},
+    parse::Program,
+    tokenise::{Span, TokenIdx},
+    types::Type,
+};
+
+/// A value on the stack while inferring an effect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Item {
+    /// the n-th value taken from below the stack the ops started with, counting from the top
+    Input(usize),
+    /// a value the ops made, of a type unless it depends on the path taken
+    Made(Option<Type>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Effect {
+    /// What each value taken from below is used as, `None` where nothing requires a type, the
+    /// top of the starting stack first.
+    pub inputs: Vec<Option<Type>>,
+    /// What is left in their place, bottom first.
+    pub outputs: Vec<Item>,
+}
+
+/// The effect of an `if` block, a test block or a whole program, and where it starts.
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub at: TokenIdx,
+    pub kind: BlockKind,
+    pub effect: Effect,
+}
+
+#[derive(Debug, Clone)]
+pub enum BlockKind {
+    Program,
+    If,
+    Test(String),
+}
+
+impl std::fmt::Display for BlockKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlockKind::Program => write!(f, "program"),
+            BlockKind::If => write!(f, "if"),
+            BlockKind::Test(name) => write!(f, "test \"{name}\""),
+        }
+    }
+}
+
+/// The effect of `program`, each of its `if` blocks and each test block with its own `if` blocks,
+/// in source order.
+pub fn effects(program: &Program) -> Vec<Block> {
+    let mut blocks = vec![Block {
+        at: program
+            .ops
+            .first()
+            .map_or_else(TokenIdx::default, |op| op.idx),
+        kind: BlockKind::Program,
+        effect: Effect::default(),
+    }];
+    blocks[0].effect = infer(&program.ops, &mut blocks);
+    for test in &program.tests {
+        let at = blocks.len();
+        let effect = infer(&test.program.ops, &mut blocks);
+        let kind = BlockKind::Test(test.name.clone());
+        blocks.insert(
+            at,
+            Block {
+                at: test.at,
+                kind,
+                effect,
+            },
+        );
+    }
+    blocks
+}
+
+/// The effect of `ops`, adding every `if` block among them to `blocks`.
+pub fn infer(ops: &[Span<Op>], blocks: &mut Vec<Block>) -> Effect {
+    run(ops, 0..ops.len(), blocks)
+}
+
+/// Infers the effect of the ops in `range`, which hold whole `if` blocks, adding those to
+/// `blocks`.
+fn run(ops: &[Span<Op>], range: Range<usize>, blocks: &mut Vec<Block>) -> Effect {
+    let mut state = State::default();
+    let mut i = range.start;
+    while i < range.end {
+        let Span { idx: at, token: op } = ops[i];
+        i += 1;
+        match op {
+            Op::Push(_) => state.stack.push(Item::Made(Some(Type::Int))),
+            Op::Intr1_0(Op1_0::Display | Op1_0::Drop) => {
+                state.pop(None);
+            }
+            Op::Intr1_2(Op1_2::Duplicate) => {
+                let a = state.pop(None);
+                state.stack.extend([a, a]);
+            }
+            Op::Intr2_2(Op2_2::Swap) => {
+                let (b, a) = (state.pop(None), state.pop(None));
+                state.stack.extend([b, a]);
+            }
+            Op::Intr2_2(Op2_2::DivMod) => {
+                state.pop(Some(Type::Int));
+                state.pop(Some(Type::Int));
+                state.stack.extend([Item::Made(Some(Type::Int)); 2]);
+            }
+            Op::Intr2_1(op_id) => {
+                let (takes, makes) = match op_id {
+                    Op2_1::Add | Op2_1::Sub | Op2_1::Mul | Op2_1::Div | Op2_1::Mod => {
+                        (Some(Type::Int), Type::Int)
+                    }
+                    Op2_1::Equ
+                    | Op2_1::Less
+                    | Op2_1::Greater
+                    | Op2_1::LessEqu
+                    | Op2_1::GreaterEqu => (None, Type::Bool),
+                };
+                state.pop(takes);
+                state.pop(takes);
+                state.stack.push(Item::Made(Some(makes)));
+            }
+            Op::Assert(_) => {
+                state.pop(Some(Type::Bool));
+            }
+            Op::Cast(to) => {
+                state.pop(None);
+                state.stack.push(Item::Made(Some(to)));
+            }
+            Op::AssertStack(_) | Op::End => {}
+            Op::If(target) => {
+                state.pop(Some(Type::Bool));
+                // the block runs up to its `end`, just before the target
+                let end = (target.0 - 1).min(range.end);
+                let at_block = blocks.len();
+                let body = run(ops, i..end, blocks);
+                blocks.insert(
+                    at_block,
+                    Block {
+                        at,
+                        kind: BlockKind::If,
+                        effect: body.clone(),
+                    },
+                );
+                state.apply_if(&body);
+                i = end;
+            }
+        }
+    }
+    Effect {
+        inputs: state.inputs,
+        outputs: state.stack,
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    inputs: Vec<Option<Type>>,
+    stack: Vec<Item>,
+}
+
+impl State {
+    /// Takes the top value, from below the starting stack if the ops have run out of their own,
+    /// noting that it is used as `want`.
+    fn pop(&mut self, want: Option<Type>) -> Item {
+        let item = self.stack.pop().unwrap_or_else(|| {
+            self.inputs.push(None);
+            Item::Input(self.inputs.len() - 1)
+        });
+        if let (Item::Input(n), Some(_)) = (item, want) {
+            // the first use decides
+            self.inputs[n] = self.inputs[n].or(want);
+        }
+        item
+    }
+
+    fn type_of(&self, item: Item) -> Option<Type> {
+        match item {
+            Item::Input(n) => self.inputs[n],
+            Item::Made(t) => t,
+        }
+    }
+
+    /// Runs an `if` block with effect `body`, or skips it, leaving what both paths agree on.
+    fn apply_if(&mut self, body: &Effect) {
+        let (before, known) = (self.stack.clone(), self.inputs.len());
+        let taken = body
+            .inputs
+            .iter()
+            .map(|&want| self.pop(want))
+            .collect::<Vec<_>>();
+        // what the block took from further down is there when it is skipped too
+        let skipped = (known..self.inputs.len())
+            .rev()
+            .map(Item::Input)
+            .chain(before)
+            .collect::<Vec<_>>();
+        for &item in &body.outputs {
+            self.stack.push(match item {
+                Item::Input(n) => taken[n],
+                made => made,
+            });
+        }
+        // a block that changes the depth fails at its `end`, so only skipping it gets past
+        if self.stack.len() != skipped.len() {
+            self.stack = skipped;
+            return;
+        }
+        for (i, &was) in skipped.iter().enumerate() {
+            let now = self.stack[i];
+            if now != was {
+                let (a, b) = (self.type_of(now), self.type_of(was));
+                self.stack[i] = Item::Made(a.filter(|_| a == b));
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Effect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // inputs left on the stack, and any without a type, go by letters, deepest first
+        let mut names = vec![None; self.inputs.len()];
+        let mut letters = 0;
+        for n in (0..self.inputs.len()).rev() {
+            let kept = self.outputs.contains(&Item::Input(n));
+            if kept || self.inputs[n].is_none() {
+                names[n] = Some(letter(letters));
+                letters += 1;
+            }
+        }
+
+        write!(f, "(")?;
+        for n in (0..self.inputs.len()).rev() {
+            match (&names[n], self.inputs[n]) {
+                (Some(name), Some(t)) => write!(f, " {name}:{t}")?,
+                (Some(name), None) => write!(f, " {name}")?,
+                (None, Some(t)) => write!(f, " {t}")?,
+                (None, None) => unreachable!("untyped inputs are named"),
+            }
+        }
+        write!(f, " --")?;
+        for &item in &self.outputs {
+            match item {
+                Item::Input(n) => match &names[n] {
+                    Some(name) => write!(f, " {name}")?,
+                    None => unreachable!("kept inputs are named"),
+                },
+                Item::Made(Some(t)) => write!(f, " {t}")?,
+                Item::Made(None) => write!(f, " any")?,
+            }
+        }
+        write!(f, " )")
+    }
+}
+
+/// `a` to `z`, then `a1` onwards.
+fn letter(n: usize) -> String {
+    let ch = (b'a' + (n % 26) as u8) as char;
+    match n / 26 {
+        0 => ch.to_string(),
+        round => format!("{ch}{round}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse_str;
+
+    /// Each block of `src` as `<kind> at <row:col>: <effect>`, in the order `effects` gives.
+    fn blocks(src: &str) -> Vec<String> {
+        effects(&parse_str(src).unwrap())
+            .iter()
+            .map(|b| format!("{} at {}: {}", b.kind, b.at, b.effect))
+            .collect()
+    }
+
+    fn effect(src: &str) -> String {
+        infer(&parse_str(src).unwrap().ops, &mut vec![]).to_string()
+    }
+
+    #[test]
+    fn names_values_kept_from_below() {
+        assert_eq!(effect("swap"), "( a b -- b a )");
+        assert_eq!(effect("dup 1 +"), "( a:int -- a int )");
+        assert_eq!(effect("drop 1 2 <"), "( a -- bool )");
+    }
+
+    #[test]
+    fn merges_the_paths_around_an_if() {
+        assert_eq!(
+            blocks("0 swap if drop 5 end"),
+            ["program at 1:1: ( bool -- int )", "if at 1:8: ( a -- int )"]
+        );
+        // an int when skipped and a bool when taken
+        assert_eq!(
+            blocks("0 swap if drop 1 2 < end"),
+            [
+                "program at 1:1: ( bool -- any )",
+                "if at 1:8: ( a -- bool )"
+            ]
+        );
+    }
+
+    #[test]
+    fn only_skips_a_block_that_changes_the_depth() {
+        assert_eq!(
+            blocks("if 1 end"),
+            ["program at 1:1: ( bool -- )", "if at 1:1: ( -- int )"]
+        );
+    }
+
+    #[test]
+    fn lists_test_blocks_after_the_program_with_their_ifs() {
+        let src = "1 2 < if 4 drop end\ntest \"t\" 1 if 2 drop end 1 assert end\n5 .";
+        assert_eq!(
+            blocks(src),
+            [
+                "program at 1:1: ( -- )",
+                "if at 1:7: ( -- )",
+                "test \"t\" at 2:1: ( -- )",
+                "if at 2:12: ( -- )",
+            ]
+        );
+    }
+}
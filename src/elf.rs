@@ -0,0 +1,415 @@
+mod dwarf;
+
+use std::fmt::Write;
+
+use crate::{
+    check::Fault,
+    native::{lower, Lowering, Runtime, Trap},
+    parse::Program,
+    tokenise::TokenIdx,
+    x86::{Alu, Asm, Cond, Inst, Label, Mem, Reg},
+};
+
+const BASE: u64 = 0x400000;
+const PAGE: u64 = 0x1000;
+const OUT_BUF: usize = 4096;
+
+const SYS_WRITE: i64 = 1;
+const SYS_EXIT: i64 = 60;
+
+/// Routines and buffers carried by a standalone executable, which talks to the kernel directly.
+///
+/// Displayed values are formatted in place and buffered in `.bss`, flushed on exit and before
+/// an error is reported so the two streams interleave as they do under the interpreter.
+struct ElfRuntime {
+    file_name: String,
+    spans: Vec<TokenIdx>,
+    print: Label,
+    flush: Label,
+    out_buf: Label,
+    out_len: Label,
+    data: Vec<(Label, Vec<u8>)>,
+}
+
+impl ElfRuntime {
+    fn new(asm: &mut Asm, file_name: &str, spans: Vec<TokenIdx>) -> Self {
+        Self {
+            file_name: file_name.to_string(),
+            spans,
+            print: asm.label(),
+            flush: asm.label(),
+            out_buf: asm.label(),
+            out_len: asm.label(),
+            data: vec![],
+        }
+    }
+
+    fn stamp(&self, op: usize) -> String {
+        self.spans
+            .get(op)
+            .copied()
+            .unwrap_or_default()
+            .as_stamp(&self.file_name)
+    }
+
+    /// Writes `msg` to stderr straight from `.data`.
+    fn write_err(&mut self, asm: &mut Asm, msg: String) {
+        let l = asm.label();
+        asm.emit(Inst::MovImm(Reg::Rax, SYS_WRITE));
+        asm.emit(Inst::MovImm(Reg::Rdi, 2));
+        asm.emit(Inst::Lea(Reg::Rsi, Mem::Rip(l)));
+        asm.emit(Inst::MovImm(Reg::Rdx, msg.len() as i64));
+        asm.emit(Inst::Syscall);
+        self.data.push((l, msg.into_bytes()));
+    }
+
+    fn exit(asm: &mut Asm, code: i64) {
+        asm.emit(Inst::MovImm(Reg::Rax, SYS_EXIT));
+        asm.emit(Inst::MovImm(Reg::Rdi, code));
+        asm.emit(Inst::Syscall);
+    }
+
+    /// `print`: writes the decimal value of `rdi` and a newline to the fd in `rsi`, buffering
+    /// stdout. Clobbers the caller-saved registers.
+    fn emit_print(&self, asm: &mut Asm) {
+        let [positive, digits, sign, written, copy, copy_byte, direct] =
+            [(); 7].map(|_| asm.label());
+        asm.emit(Inst::Label(self.print));
+        // digits are written backwards from the end of a scratch area below the return address
+        asm.emit(Inst::AluImm(Alu::Sub, Reg::Rsp, 32));
+        asm.emit(Inst::Lea(Reg::R8, Mem::Base(Reg::Rsp, 32)));
+        asm.emit(Inst::AluImm(Alu::Sub, Reg::R8, 1));
+        asm.emit(Inst::MovImm(Reg::Rcx, b'\n' as i64));
+        asm.emit(Inst::StoreByte(Mem::Base(Reg::R8, 0), Reg::Rcx));
+        asm.emit(Inst::Mov(Reg::Rax, Reg::Rdi));
+        asm.emit(Inst::Mov(Reg::R10, Reg::Rdi));
+        asm.emit(Inst::Test(Reg::Rax, Reg::Rax));
+        asm.emit(Inst::Jcc(Cond::Ge, positive));
+        // isize::MIN negates to itself, which is still the right magnitude unsigned
+        asm.emit(Inst::Neg(Reg::Rax));
+        asm.emit(Inst::Label(positive));
+        asm.emit(Inst::MovImm(Reg::R9, 10));
+        asm.emit(Inst::Label(digits));
+        asm.emit(Inst::Zero(Reg::Rdx));
+        asm.emit(Inst::Div(Reg::R9));
+        asm.emit(Inst::AluImm(Alu::Add, Reg::Rdx, b'0' as i32));
+        asm.emit(Inst::AluImm(Alu::Sub, Reg::R8, 1));
+        asm.emit(Inst::StoreByte(Mem::Base(Reg::R8, 0), Reg::Rdx));
+        asm.emit(Inst::Test(Reg::Rax, Reg::Rax));
+        asm.emit(Inst::Jcc(Cond::Ne, digits));
+        asm.emit(Inst::Test(Reg::R10, Reg::R10));
+        asm.emit(Inst::Jcc(Cond::Ge, sign));
+        asm.emit(Inst::AluImm(Alu::Sub, Reg::R8, 1));
+        asm.emit(Inst::MovImm(Reg::Rcx, b'-' as i64));
+        asm.emit(Inst::StoreByte(Mem::Base(Reg::R8, 0), Reg::Rcx));
+        asm.emit(Inst::Label(sign));
+        asm.emit(Inst::Lea(Reg::Rdx, Mem::Base(Reg::Rsp, 32)));
+        asm.emit(Inst::Alu(Alu::Sub, Reg::Rdx, Reg::R8));
+        asm.emit(Inst::AluImm(Alu::Cmp, Reg::Rsi, 1));
+        asm.emit(Inst::Jcc(Cond::Ne, direct));
+
+        asm.emit(Inst::Load(Reg::Rax, Mem::Rip(self.out_len)));
+        asm.emit(Inst::Alu(Alu::Add, Reg::Rax, Reg::Rdx));
+        asm.emit(Inst::AluImm(Alu::Cmp, Reg::Rax, OUT_BUF as i32));
+        asm.emit(Inst::Jcc(Cond::Le, copy));
+        asm.emit(Inst::Push(Reg::R8));
+        asm.emit(Inst::Push(Reg::Rdx));
+        asm.emit(Inst::Call(self.flush));
+        asm.emit(Inst::Pop(Reg::Rdx));
+        asm.emit(Inst::Pop(Reg::R8));
+        asm.emit(Inst::Label(copy));
+        asm.emit(Inst::Load(Reg::Rax, Mem::Rip(self.out_len)));
+        asm.emit(Inst::Lea(Reg::Rdi, Mem::Rip(self.out_buf)));
+        asm.emit(Inst::Alu(Alu::Add, Reg::Rdi, Reg::Rax));
+        asm.emit(Inst::Alu(Alu::Add, Reg::Rax, Reg::Rdx));
+        asm.emit(Inst::Store(Mem::Rip(self.out_len), Reg::Rax));
+        asm.emit(Inst::Label(copy_byte));
+        asm.emit(Inst::LoadByte(Reg::Rcx, Mem::Base(Reg::R8, 0)));
+        asm.emit(Inst::StoreByte(Mem::Base(Reg::Rdi, 0), Reg::Rcx));
+        asm.emit(Inst::AluImm(Alu::Add, Reg::R8, 1));
+        asm.emit(Inst::AluImm(Alu::Add, Reg::Rdi, 1));
+        asm.emit(Inst::AluImm(Alu::Sub, Reg::Rdx, 1));
+        asm.emit(Inst::Jcc(Cond::Ne, copy_byte));
+        asm.emit(Inst::Jmp(written));
+
+        asm.emit(Inst::Label(direct));
+        asm.emit(Inst::Mov(Reg::Rdi, Reg::Rsi));
+        asm.emit(Inst::Mov(Reg::Rsi, Reg::R8));
+        asm.emit(Inst::MovImm(Reg::Rax, SYS_WRITE));
+        asm.emit(Inst::Syscall);
+        asm.emit(Inst::Label(written));
+        asm.emit(Inst::AluImm(Alu::Add, Reg::Rsp, 32));
+        asm.emit(Inst::Ret);
+    }
+
+    /// `flush`: writes out whatever `print` has buffered for stdout.
+    fn emit_flush(&self, asm: &mut Asm) {
+        let (next, done) = (asm.label(), asm.label());
+        asm.emit(Inst::Label(self.flush));
+        asm.emit(Inst::Load(Reg::Rdx, Mem::Rip(self.out_len)));
+        asm.emit(Inst::Lea(Reg::Rsi, Mem::Rip(self.out_buf)));
+        asm.emit(Inst::Label(next));
+        asm.emit(Inst::Test(Reg::Rdx, Reg::Rdx));
+        asm.emit(Inst::Jcc(Cond::E, done));
+        asm.emit(Inst::MovImm(Reg::Rax, SYS_WRITE));
+        asm.emit(Inst::MovImm(Reg::Rdi, 1));
+        asm.emit(Inst::Syscall);
+        // a failed write drops the rest, there is nowhere left to report it
+        asm.emit(Inst::Test(Reg::Rax, Reg::Rax));
+        asm.emit(Inst::Jcc(Cond::Le, done));
+        asm.emit(Inst::Alu(Alu::Add, Reg::Rsi, Reg::Rax));
+        asm.emit(Inst::Alu(Alu::Sub, Reg::Rdx, Reg::Rax));
+        asm.emit(Inst::Jmp(next));
+        asm.emit(Inst::Label(done));
+        asm.emit(Inst::Zero(Reg::Rax));
+        asm.emit(Inst::Store(Mem::Rip(self.out_len), Reg::Rax));
+        asm.emit(Inst::Ret);
+    }
+}
+
+impl Runtime for ElfRuntime {
+    fn prologue(&mut self, asm: &mut Asm) {
+        asm.emit(Inst::Mov(Reg::Rbp, Reg::Rsp));
+    }
+
+    fn epilogue(&mut self, asm: &mut Asm) {
+        asm.emit(Inst::Call(self.flush));
+        Self::exit(asm, 0);
+    }
+
+    fn display(&mut self, asm: &mut Asm, _op: usize) {
+        asm.emit(Inst::Mov(Reg::Rdi, Reg::Rax));
+        asm.emit(Inst::MovImm(Reg::Rsi, 1));
+        asm.emit(Inst::Call(self.print));
+    }
+
+    fn trap(&mut self, asm: &mut Asm, op: usize, trap: Trap) {
+        let at = self.stamp(op);
+        if let Trap::NotBool = trap {
+            // nothing returns from here, so `rbx` is free to hold the value across the flush
+            asm.emit(Inst::Mov(Reg::Rbx, Reg::Rax));
+        }
+        asm.emit(Inst::Call(self.flush));
+        match trap {
+            Trap::Fault(fault) => self.write_err(asm, format!("Error: {at}: {fault}\n")),
+            Trap::DivZero => self.write_err(asm, format!("Error: {at}: division by zero\n")),
+            Trap::NotBool => {
+                self.write_err(asm, format!("Error: {at}: expected bool, got "));
+                asm.emit(Inst::Mov(Reg::Rdi, Reg::Rbx));
+                asm.emit(Inst::MovImm(Reg::Rsi, 2));
+                asm.emit(Inst::Call(self.print));
+            }
+            Trap::Leftover(remaining) => self.write_err(
+                asm,
+                format!("Error: {at}: {}\n", Fault::Leftover { remaining }),
+            ),
+        }
+        Self::exit(asm, 1);
+    }
+}
+
+/// A `Program` lowered to a standalone x86-64 Linux executable, before layout.
+pub struct Executable {
+    asm: Asm,
+    data: Vec<(Label, Vec<u8>)>,
+    bss: Vec<(Label, usize)>,
+    file_name: String,
+    /// where the code for each op starts, for the line table
+    lines: Vec<(Label, TokenIdx)>,
+    print: Label,
+    flush: Label,
+}
+
+impl Executable {
+    pub fn new(file_name: impl AsRef<str>, Program { ops, .. }: &Program) -> Self {
+        let mut asm = Asm::new();
+        let spans = ops.iter().map(|s| s.idx).collect();
+        let mut rt = ElfRuntime::new(&mut asm, file_name.as_ref(), spans);
+        let marks = lower(&mut asm, ops, &mut rt, Lowering::default());
+        rt.emit_print(&mut asm);
+        rt.emit_flush(&mut asm);
+        Self {
+            asm,
+            data: rt.data,
+            bss: vec![(rt.out_len, 8), (rt.out_buf, OUT_BUF)],
+            file_name: file_name.as_ref().to_string(),
+            lines: marks.into_iter().map(|(l, op)| (l, ops[op].idx)).collect(),
+            print: rt.print,
+            flush: rt.flush,
+        }
+    }
+
+    /// The equivalent fasm source, assembled and linked with
+    /// `fasm out.asm && ld out.o -o out`.
+    pub fn to_asm(&self) -> String {
+        let mut s = String::new();
+        s.push_str("format elf64\n\n");
+        s.push_str("section \".text\" executable\n\n");
+        s.push_str("public _start\n\n");
+        s.push_str("_start:\n");
+        for inst in &self.asm.insts {
+            let _ = writeln!(s, "{inst}");
+        }
+        s.push_str("\nsection \".data\" writeable\n");
+        for (l, bytes) in &self.data {
+            let bytes = bytes.iter().map(|b| b.to_string()).collect::<Vec<_>>();
+            let _ = writeln!(s, "{l}: db {}", bytes.join(","));
+        }
+        s.push_str("\nsection \".bss\" writeable\n");
+        for (l, len) in &self.bss {
+            let _ = writeln!(s, "{l}: rb {len}");
+        }
+        s
+    }
+
+    /// A static ELF64 image with `.text` and `.data` each in their own page aligned segment,
+    /// `.bss` zero filled after `.data`, and section headers for inspection tools. DWARF line
+    /// info maps the code back to the source, relative to the current directory.
+    pub fn to_elf(&self) -> Vec<u8> {
+        let mut assembled = self.asm.assemble();
+        let text_off = PAGE;
+        let text_addr = BASE + text_off;
+        let text_end = text_off + assembled.code.len() as u64;
+        let data_off = text_end.next_multiple_of(PAGE);
+        let data_addr = BASE + data_off;
+
+        let mut data = vec![];
+        for (l, bytes) in &self.data {
+            assembled.labels[l.0] = Some((data_addr - text_addr) as usize + data.len());
+            data.extend_from_slice(bytes);
+        }
+        let bss_addr = (data_addr + data.len() as u64).next_multiple_of(8);
+        let mut bss_len = 0;
+        for (l, len) in &self.bss {
+            assembled.labels[l.0] = Some((bss_addr - text_addr) as usize + bss_len);
+            bss_len += len;
+        }
+        for (at, l) in assembled.relocs {
+            let to = assembled.labels[l.0].expect("every label placed") as i64;
+            let field = &mut assembled.code[at..at + 4];
+            let rel = i32::from_le_bytes(field.try_into().unwrap()) as i64 + to;
+            field.copy_from_slice(&(rel as i32).to_le_bytes());
+        }
+
+        let addr = |l: Label| text_addr + assembled.labels[l.0].expect("code label placed") as u64;
+        let comp_dir = std::env::current_dir().unwrap_or_default();
+        #[rustfmt::skip]
+        let subprograms = [
+            dwarf::Subprogram { name: "_start", low: text_addr, high: addr(self.print) },
+            dwarf::Subprogram { name: "print", low: addr(self.print), high: addr(self.flush) },
+            dwarf::Subprogram { name: "flush", low: addr(self.flush), high: BASE + text_end },
+        ];
+        let rows = self.lines.iter().map(|&(l, span)| (addr(l), span));
+        let debug = dwarf::sections(
+            &self.file_name,
+            &comp_dir.to_string_lossy(),
+            &subprograms,
+            &rows.collect::<Vec<_>>(),
+        );
+
+        let shstrtab =
+            b"\0.text\0.data\0.bss\0.shstrtab\0.debug_abbrev\0.debug_info\0.debug_line\0";
+        let data_end = data_off + data.len() as u64;
+        let abbrev_off = data_end + shstrtab.len() as u64;
+        let info_off = abbrev_off + debug.abbrev.len() as u64;
+        let line_off = info_off + debug.info.len() as u64;
+        let shoff = (line_off + debug.line.len() as u64).next_multiple_of(8);
+
+        let mut out = vec![];
+        // ELF header
+        out.extend_from_slice(b"\x7fELF");
+        out.extend_from_slice(&[2, 1, 1, 0]); // 64 bit, little endian, version 1, SysV
+        out.extend_from_slice(&[0; 8]);
+        out.extend_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+        out.extend_from_slice(&0x3eu16.to_le_bytes()); // EM_X86_64
+        out.extend_from_slice(&1u32.to_le_bytes());
+        out.extend_from_slice(&text_addr.to_le_bytes()); // entry
+        out.extend_from_slice(&64u64.to_le_bytes()); // phoff
+        out.extend_from_slice(&shoff.to_le_bytes());
+        out.extend_from_slice(&0u32.to_le_bytes()); // flags
+        out.extend_from_slice(&64u16.to_le_bytes()); // ehsize
+        out.extend_from_slice(&56u16.to_le_bytes()); // phentsize
+        out.extend_from_slice(&2u16.to_le_bytes()); // phnum
+        out.extend_from_slice(&64u16.to_le_bytes()); // shentsize
+        out.extend_from_slice(&8u16.to_le_bytes()); // shnum
+        out.extend_from_slice(&4u16.to_le_bytes()); // shstrndx
+
+        // the first segment maps the headers along with `.text`
+        program_header(&mut out, 0b101, 0, BASE, text_end, text_end);
+        let bss_end = bss_addr + bss_len as u64;
+        program_header(
+            &mut out,
+            0b110,
+            data_off,
+            data_addr,
+            data.len() as u64,
+            bss_end - data_addr,
+        );
+
+        out.resize(text_off as usize, 0);
+        out.extend_from_slice(&assembled.code);
+        out.resize(data_off as usize, 0);
+        out.extend_from_slice(&data);
+        out.extend_from_slice(shstrtab);
+        out.extend_from_slice(&debug.abbrev);
+        out.extend_from_slice(&debug.info);
+        out.extend_from_slice(&debug.line);
+        out.resize(shoff as usize, 0);
+
+        const SHT_PROGBITS: u32 = 1;
+        const SHT_STRTAB: u32 = 3;
+        const SHT_NOBITS: u32 = 8;
+        const ALLOC: u64 = 0x2;
+        const WRITE: u64 = 0x1;
+        const EXEC: u64 = 0x4;
+        out.extend_from_slice(&[0; 64]);
+        #[rustfmt::skip]
+        let sections = [
+            (1, SHT_PROGBITS, ALLOC | EXEC, text_addr, text_off, assembled.code.len() as u64, 16),
+            (7, SHT_PROGBITS, ALLOC | WRITE, data_addr, data_off, data.len() as u64, 1),
+            (13, SHT_NOBITS, ALLOC | WRITE, bss_addr, data_end, bss_len as u64, 8),
+            (18, SHT_STRTAB, 0, 0, data_end, shstrtab.len() as u64, 1),
+            (28, SHT_PROGBITS, 0, 0, abbrev_off, debug.abbrev.len() as u64, 1),
+            (42, SHT_PROGBITS, 0, 0, info_off, debug.info.len() as u64, 1),
+            (54, SHT_PROGBITS, 0, 0, line_off, debug.line.len() as u64, 1),
+        ];
+        for (name, kind, flags, addr, offset, size, align) in sections {
+            section_header(&mut out, name, kind, flags, addr, offset, size, align);
+        }
+        out
+    }
+}
+
+fn program_header(out: &mut Vec<u8>, flags: u32, offset: u64, addr: u64, filesz: u64, memsz: u64) {
+    out.extend_from_slice(&1u32.to_le_bytes()); // PT_LOAD
+    out.extend_from_slice(&flags.to_le_bytes());
+    out.extend_from_slice(&offset.to_le_bytes());
+    out.extend_from_slice(&addr.to_le_bytes());
+    out.extend_from_slice(&addr.to_le_bytes());
+    out.extend_from_slice(&filesz.to_le_bytes());
+    out.extend_from_slice(&memsz.to_le_bytes());
+    out.extend_from_slice(&PAGE.to_le_bytes());
+}
+
+#[allow(clippy::too_many_arguments)]
+fn section_header(
+    out: &mut Vec<u8>,
+    name: u32,
+    kind: u32,
+    flags: u64,
+    addr: u64,
+    offset: u64,
+    size: u64,
+    align: u64,
+) {
+    out.extend_from_slice(&name.to_le_bytes());
+    out.extend_from_slice(&kind.to_le_bytes());
+    out.extend_from_slice(&flags.to_le_bytes());
+    out.extend_from_slice(&addr.to_le_bytes());
+    out.extend_from_slice(&offset.to_le_bytes());
+    out.extend_from_slice(&size.to_le_bytes());
+    out.extend_from_slice(&0u32.to_le_bytes()); // link
+    out.extend_from_slice(&0u32.to_le_bytes()); // info
+    out.extend_from_slice(&align.to_le_bytes());
+    out.extend_from_slice(&0u64.to_le_bytes()); // entsize
+}
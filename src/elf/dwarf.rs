@@ -0,0 +1,333 @@
+//! Just enough DWARF 4 for a debugger to map machine code back to `.wa` source: one compile
+//! unit naming the source file, a subprogram for each routine so stepping knows where
+//! functions start and end, and a line table with a row per op.
+
+use crate::tokenise::TokenIdx;
+
+const DW_TAG_COMPILE_UNIT: u64 = 0x11;
+const DW_TAG_SUBPROGRAM: u64 = 0x2e;
+
+const DW_AT_NAME: u64 = 0x03;
+const DW_AT_STMT_LIST: u64 = 0x10;
+const DW_AT_LOW_PC: u64 = 0x11;
+const DW_AT_HIGH_PC: u64 = 0x12;
+const DW_AT_LANGUAGE: u64 = 0x13;
+const DW_AT_COMP_DIR: u64 = 0x1b;
+const DW_AT_PRODUCER: u64 = 0x25;
+
+const DW_FORM_ADDR: u64 = 0x01;
+const DW_FORM_DATA2: u64 = 0x05;
+const DW_FORM_DATA8: u64 = 0x07;
+const DW_FORM_STRING: u64 = 0x08;
+const DW_FORM_SEC_OFFSET: u64 = 0x17;
+
+/// There is no language code for wa, debuggers treat this one as plain assembly.
+const DW_LANG_MIPS_ASSEMBLER: u16 = 0x8001;
+
+const DW_LNS_COPY: u8 = 0x01;
+const DW_LNS_ADVANCE_PC: u8 = 0x02;
+const DW_LNS_ADVANCE_LINE: u8 = 0x03;
+const DW_LNS_SET_COLUMN: u8 = 0x05;
+const DW_LNE_END_SEQUENCE: u8 = 0x01;
+const DW_LNE_SET_ADDRESS: u8 = 0x02;
+
+/// Operand counts of the standard opcodes, 1 through 12.
+const STANDARD_OPCODE_LENGTHS: [u8; 12] = [0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1];
+
+/// The contents of the `.debug_abbrev`, `.debug_info` and `.debug_line` sections.
+pub struct Sections {
+    pub abbrev: Vec<u8>,
+    pub info: Vec<u8>,
+    pub line: Vec<u8>,
+}
+
+/// Code, tag, whether entries have children, and `(attribute, form)` pairs.
+type Abbrev = (u64, u64, bool, &'static [(u64, u64)]);
+
+/// A named range of code, `[low, high)`.
+pub struct Subprogram<'a> {
+    pub name: &'a str,
+    pub low: u64,
+    pub high: u64,
+}
+
+/// Debug info for code from `file_name`, relative to `comp_dir`. `rows` holds the address each
+/// source position starts at, in increasing order, and the line table runs to the end of the
+/// first subprogram; the rest have no source of their own.
+pub fn sections(
+    file_name: &str,
+    comp_dir: &str,
+    subprograms: &[Subprogram],
+    rows: &[(u64, TokenIdx)],
+) -> Sections {
+    let (low, high) = subprograms.first().map_or((0, 0), |s| (s.low, s.high));
+    let cu_low = subprograms.iter().map(|s| s.low).min().unwrap_or(low);
+    let cu_high = subprograms.iter().map(|s| s.high).max().unwrap_or(high);
+
+    let mut abbrev = vec![];
+    #[rustfmt::skip]
+    let abbrevs: [Abbrev; 2] = [
+        (1, DW_TAG_COMPILE_UNIT, true, &[
+            (DW_AT_PRODUCER, DW_FORM_STRING),
+            (DW_AT_LANGUAGE, DW_FORM_DATA2),
+            (DW_AT_NAME, DW_FORM_STRING),
+            (DW_AT_COMP_DIR, DW_FORM_STRING),
+            (DW_AT_STMT_LIST, DW_FORM_SEC_OFFSET),
+            (DW_AT_LOW_PC, DW_FORM_ADDR),
+            (DW_AT_HIGH_PC, DW_FORM_DATA8),
+        ]),
+        (2, DW_TAG_SUBPROGRAM, false, &[
+            (DW_AT_NAME, DW_FORM_STRING),
+            (DW_AT_LOW_PC, DW_FORM_ADDR),
+            (DW_AT_HIGH_PC, DW_FORM_DATA8),
+        ]),
+    ];
+    for (code, tag, children, attrs) in abbrevs {
+        uleb(&mut abbrev, code);
+        uleb(&mut abbrev, tag);
+        abbrev.push(children as u8);
+        for &(attr, form) in attrs {
+            uleb(&mut abbrev, attr);
+            uleb(&mut abbrev, form);
+        }
+        abbrev.extend_from_slice(&[0, 0]);
+    }
+    abbrev.push(0);
+
+    let mut dies = vec![];
+    uleb(&mut dies, 1);
+    string(&mut dies, concat!("wa ", env!("CARGO_PKG_VERSION")));
+    dies.extend_from_slice(&DW_LANG_MIPS_ASSEMBLER.to_le_bytes());
+    string(&mut dies, file_name);
+    string(&mut dies, comp_dir);
+    dies.extend_from_slice(&0u32.to_le_bytes()); // the only line program
+    dies.extend_from_slice(&cu_low.to_le_bytes());
+    dies.extend_from_slice(&(cu_high - cu_low).to_le_bytes());
+    for Subprogram { name, low, high } in subprograms {
+        uleb(&mut dies, 2);
+        string(&mut dies, name);
+        dies.extend_from_slice(&low.to_le_bytes());
+        dies.extend_from_slice(&(high - low).to_le_bytes());
+    }
+    dies.push(0);
+
+    let mut info = vec![];
+    info.extend_from_slice(&(7 + dies.len() as u32).to_le_bytes());
+    info.extend_from_slice(&4u16.to_le_bytes());
+    info.extend_from_slice(&0u32.to_le_bytes()); // abbrev offset
+    info.push(8); // address size
+    info.extend_from_slice(&dies);
+
+    let mut header = vec![
+        1,                                       // minimum instruction length
+        1,                                       // maximum operations per instruction
+        1,                                       // default is_stmt
+        -5i8 as u8,                              // line base
+        14,                                      // line range
+        STANDARD_OPCODE_LENGTHS.len() as u8 + 1, // opcode base
+    ];
+    header.extend_from_slice(&STANDARD_OPCODE_LENGTHS);
+    header.push(0); // no include directories beyond the compile directory
+    string(&mut header, file_name);
+    header.extend_from_slice(&[0, 0, 0]); // directory, mtime and length
+    header.push(0);
+
+    let mut program = vec![0, 9, DW_LNE_SET_ADDRESS];
+    program.extend_from_slice(&low.to_le_bytes());
+    let (mut addr, mut line, mut col) = (low, 1, 0);
+    for (i, &(at, span)) in rows.iter().enumerate() {
+        // ops that emit no code share an address with the op after them, which owns it
+        if rows.get(i + 1).is_some_and(|&(next, _)| next == at) {
+            continue;
+        }
+        if at != addr {
+            program.push(DW_LNS_ADVANCE_PC);
+            uleb(&mut program, at - addr);
+            addr = at;
+        }
+        let row = span.row as i64 + 1;
+        if row != line {
+            program.push(DW_LNS_ADVANCE_LINE);
+            sleb(&mut program, row - line);
+            line = row;
+        }
+        if span.col as u64 + 1 != col {
+            col = span.col as u64 + 1;
+            program.push(DW_LNS_SET_COLUMN);
+            uleb(&mut program, col);
+        }
+        program.push(DW_LNS_COPY);
+    }
+    if high != addr {
+        program.push(DW_LNS_ADVANCE_PC);
+        uleb(&mut program, high - addr);
+    }
+    program.extend_from_slice(&[0, 1, DW_LNE_END_SEQUENCE]);
+
+    let mut line = vec![];
+    line.extend_from_slice(&((6 + header.len() + program.len()) as u32).to_le_bytes());
+    line.extend_from_slice(&4u16.to_le_bytes());
+    line.extend_from_slice(&(header.len() as u32).to_le_bytes());
+    line.extend_from_slice(&header);
+    line.extend_from_slice(&program);
+
+    Sections { abbrev, info, line }
+}
+
+fn uleb(out: &mut Vec<u8>, mut n: u64) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        match n {
+            0 => return out.push(byte),
+            _ => out.push(byte | 0x80),
+        }
+    }
+}
+
+fn sleb(out: &mut Vec<u8>, mut n: i64) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        let done = (n == 0 && byte & 0x40 == 0) || (n == -1 && byte & 0x40 != 0);
+        match done {
+            true => return out.push(byte),
+            false => out.push(byte | 0x80),
+        }
+    }
+}
+
+fn string(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(s.as_bytes());
+    out.push(0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoded(f: impl FnOnce(&mut Vec<u8>)) -> Vec<u8> {
+        let mut out = vec![];
+        f(&mut out);
+        out
+    }
+
+    #[test]
+    fn encodes_leb128() {
+        for (n, bytes) in [
+            (0, &[0x00][..]),
+            (2, &[0x02]),
+            (127, &[0x7f]),
+            (128, &[0x80, 0x01]),
+            (624485, &[0xe5, 0x8e, 0x26]),
+        ] {
+            assert_eq!(encoded(|out| uleb(out, n)), bytes, "uleb {n}");
+        }
+        for (n, bytes) in [
+            (0, &[0x00][..]),
+            (2, &[0x02]),
+            (-2, &[0x7e]),
+            (63, &[0x3f]),
+            (64, &[0xc0, 0x00]),
+            (-64, &[0x40]),
+            (-65, &[0xbf, 0x7f]),
+            (-123456, &[0xc0, 0xbb, 0x78]),
+        ] {
+            assert_eq!(encoded(|out| sleb(out, n)), bytes, "sleb {n}");
+        }
+    }
+
+    /// Reads LEB128 from the front of `bytes`, sign extending if `signed`.
+    fn read_leb(bytes: &mut &[u8], signed: bool) -> i64 {
+        let (mut n, mut shift) = (0i64, 0);
+        loop {
+            let byte = bytes[0];
+            *bytes = &bytes[1..];
+            n |= ((byte & 0x7f) as i64) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if signed && shift < 64 && byte & 0x40 != 0 {
+                    n |= -1 << shift;
+                }
+                return n;
+            }
+        }
+    }
+
+    /// Runs the line number program in `line`, returning its rows as `(address, line, column)`
+    /// and the address its sequence ends at.
+    fn decode_lines(line: &[u8]) -> (Vec<(u64, i64, i64)>, u64) {
+        let unit_length = u32::from_le_bytes(line[0..4].try_into().unwrap()) as usize;
+        assert_eq!(unit_length, line.len() - 4, "unit length");
+        assert_eq!(u16::from_le_bytes(line[4..6].try_into().unwrap()), 4);
+        let header_length = u32::from_le_bytes(line[6..10].try_into().unwrap()) as usize;
+        let header = &line[10..10 + header_length];
+        assert_eq!(
+            header[5],
+            STANDARD_OPCODE_LENGTHS.len() as u8 + 1,
+            "opcode base"
+        );
+        assert!(header.ends_with(b"t.wa\0\0\0\0\0"), "file table at the end");
+
+        let mut program = &line[10 + header_length..];
+        let (mut rows, mut addr, mut row, mut col) = (vec![], 0, 1, 1);
+        loop {
+            let op = program[0];
+            program = &program[1..];
+            match op {
+                0 => {
+                    let len = read_leb(&mut program, false) as usize;
+                    let (ext, rest) = program.split_at(len);
+                    program = rest;
+                    match ext[0] {
+                        DW_LNE_SET_ADDRESS => {
+                            addr = u64::from_le_bytes(ext[1..].try_into().unwrap())
+                        }
+                        DW_LNE_END_SEQUENCE => {
+                            assert!(program.is_empty(), "nothing after the sequence ends");
+                            return (rows, addr);
+                        }
+                        ext => panic!("unexpected extended opcode {ext}"),
+                    }
+                }
+                DW_LNS_COPY => rows.push((addr, row, col)),
+                DW_LNS_ADVANCE_PC => addr += read_leb(&mut program, false) as u64,
+                DW_LNS_ADVANCE_LINE => row += read_leb(&mut program, true),
+                DW_LNS_SET_COLUMN => col = read_leb(&mut program, false),
+                op => panic!("unexpected opcode {op}"),
+            }
+        }
+    }
+
+    #[test]
+    fn line_table_has_a_row_per_op_with_code() {
+        let at = |row, col| TokenIdx { row, col };
+        let subprograms = [
+            Subprogram {
+                name: "_start",
+                low: 0x1000,
+                high: 0x1030,
+            },
+            Subprogram {
+                name: "print",
+                low: 0x1030,
+                high: 0x1050,
+            },
+        ];
+        let rows = [
+            (0x1000, at(0, 0)),
+            // emits no code, so the op after it owns the address
+            (0x1004, at(1, 2)),
+            (0x1004, at(1, 5)),
+            (0x1010, at(0, 3)),
+            (0x1010, at(2, 0)),
+        ];
+        let Sections { line, .. } = sections("t.wa", "/src", &subprograms, &rows);
+        let (rows, end) = decode_lines(&line);
+        assert_eq!(rows, [(0x1000, 1, 1), (0x1004, 2, 6), (0x1010, 3, 1)]);
+        assert_eq!(
+            end, 0x1030,
+            "the sequence ends at the end of the first subprogram"
+        );
+    }
+}
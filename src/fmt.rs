@@ -0,0 +1,178 @@
+//! `wa fmt`: the canonical layout of a program.
+//!
+//! Line breaks are the author's, everything else is normalised: tokens on a line are separated
+//! by one space, blocks are indented by four spaces per level of `if` or `test`, with `end` and
+//! `else` lines outdented to the line that opened them, and runs of blank lines become one.
+//! Trailing comments on neighbouring lines, such as stack effects or `// expect:`s, are aligned
+//! one space after the longest line of code among them, and a comment-only line that lined up
+//! with the comment above it stays lined up with it. A program that does not parse is left as it
+//! is, since where its blocks begin and end is not known.
+
+use crate::{
+    parse::parse_ops,
+    tokenise::{Piece, Tokeniser},
+};
+
+const INDENT: &str = "    ";
+
+#[derive(Debug, Default)]
+struct Line<'a> {
+    tokens: Vec<&'a str>,
+    comment: Option<&'a str>,
+    /// Where the comment started in the original source.
+    comment_col: usize,
+    blank_before: bool,
+}
+
+impl Line<'_> {
+    fn is_empty(&self) -> bool {
+        self.tokens.is_empty() && self.comment.is_none()
+    }
+}
+
+/// Formats `src`, or fails with why it does not parse. Formatting the result again leaves it
+/// unchanged.
+pub fn format(file_name: &str, src: &[u8]) -> anyhow::Result<String> {
+    parse_ops(Tokeniser::new(src).collect(), file_name)?;
+    let lines = lines(src);
+
+    // the code of each line, indented
+    let mut depth = 0usize;
+    let mut code = Vec::with_capacity(lines.len());
+    for line in &lines {
+        let mut indent = depth;
+        let mut leading = true;
+        for &tok in &line.tokens {
+            match tok {
+                "if" | "test" => depth += 1,
+                "end" => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+            if leading {
+                match tok {
+                    "end" => indent = depth,
+                    "else" => indent = depth.saturating_sub(1),
+                    _ => leading = false,
+                }
+            }
+        }
+        code.push(match line.tokens.is_empty() {
+            true => INDENT.repeat(indent),
+            false => INDENT.repeat(indent) + &line.tokens.join(" "),
+        });
+    }
+
+    let mut out = String::new();
+    let mut i = 0;
+    while i < lines.len() {
+        // a run of trailing comments shares a column
+        let mut end = i + 1;
+        if !lines[i].tokens.is_empty() && lines[i].comment.is_some() {
+            while let Some(next) = lines.get(end) {
+                let prev = &lines[end - 1];
+                let joins = next.comment.is_some()
+                    && !next.blank_before
+                    && (!next.tokens.is_empty() || next.comment_col == prev.comment_col);
+                if !joins {
+                    break;
+                }
+                end += 1;
+            }
+        }
+        let col = (i..end)
+            .filter(|&j| !lines[j].tokens.is_empty())
+            .map(|j| code[j].chars().count() + 1)
+            .max();
+
+        for j in i..end {
+            let line = &lines[j];
+            if line.blank_before {
+                out.push('\n');
+            }
+            let mut text = code[j].clone();
+            if let Some(comment) = line.comment {
+                // only runs of comment-only lines have no column, and keep their indentation
+                if let Some(col) = col {
+                    let pad = col - text.chars().count();
+                    text.push_str(&" ".repeat(pad));
+                }
+                text.push_str(comment.trim_end());
+            }
+            out.push_str(text.trim_end());
+            out.push('\n');
+        }
+        i = end;
+    }
+    Ok(out)
+}
+
+/// The lines of `src` that hold anything, each knowing whether blank lines came before it.
+fn lines(src: &[u8]) -> Vec<Line<'_>> {
+    let mut lines = vec![];
+    let mut line = Line::default();
+    let mut newlines = 0;
+    for span in Tokeniser::new(src).lossless() {
+        match span.token {
+            Piece::Whitespace(ws) => {
+                let n = ws.bytes().filter(|&b| b == b'\n').count();
+                if n > 0 && !line.is_empty() {
+                    lines.push(std::mem::take(&mut line));
+                    newlines = 0;
+                }
+                newlines += n;
+                continue;
+            }
+            Piece::Token(tok) => line.tokens.push(tok),
+            Piece::Comment(comment) => {
+                line.comment = Some(comment);
+                line.comment_col = span.idx.col;
+            }
+        }
+        if line.tokens.len() + line.comment.is_some() as usize == 1 {
+            line.blank_before = newlines > 1 && !lines.is_empty();
+        }
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format_str(src: &str) -> String {
+        format("t.wa", src.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn normalises_spacing_indentation_and_blank_lines() {
+        let src = "1   1 = if\n1 .  // one\n  22 . // two\nend\n\n\n\n3 .\n";
+        let want = "1 1 = if\n    1 .  // one\n    22 . // two\nend\n\n3 .\n";
+        assert_eq!(format_str(src), want);
+    }
+
+    #[test]
+    fn is_idempotent() {
+        let examples = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("examples");
+        let generated =
+            (0..8).map(|seed| crate::difftest::generate(&mut crate::difftest::Rng::new(seed), 60));
+        let srcs = crate::golden::programs(&examples)
+            .unwrap()
+            .into_iter()
+            .map(|p| std::fs::read_to_string(p).unwrap())
+            .chain(generated);
+        for src in srcs {
+            let Ok(once) = format("t.wa", src.as_bytes()) else {
+                continue;
+            };
+            assert_eq!(format_str(&once), once, "formatting twice changed\n{src}");
+        }
+    }
+
+    #[test]
+    fn leaves_programs_that_do_not_parse_alone() {
+        assert!(format("t.wa", b"1 if\n2 .\n").is_err());
+    }
+}
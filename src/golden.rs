@@ -0,0 +1,276 @@
+//! Golden tests: every `.wa` program under a directory is run on the interpreter and checked
+//! against what it is expected to print.
+//!
+//! Expectations are written in the program itself as comments, one per line of output:
+//!
+//! ```text
+//! 1 2 + .   // expect: 3
+//! .         // expect-error: prog.wa:2:1: Stack Underflow, expected at least 1 element(s), got 0
+//! ```
+//!
+//! A program without any is checked against the same lines, minus the `//`, in a `.expect`
+//! file next to it, which `--update` records from what the program actually does. Errors are
+//! stamped with the path of the program relative to the directory being tested.
+//!
+//! Each `test "name" ... end` block in a program is then run on its own, starting from an empty
+//! stack, and passes if none of its `assert`s fail. A program made only of test blocks needs no
+//! expectations.
+//!
+//! With coverage requested, each program is run once more on its own interpreter to record it,
+//! which sees the same run as the harness does since programs get no input.
+
+use std::path::{Path, PathBuf};
+
+use crate::{
+    context::Context,
+    coverage::Lcov,
+    difftest::{Backend, Harness, Outcome},
+    interp::Interpreter,
+    parse::{parse_ops, Program, Test},
+    tokenise::Tokeniser,
+};
+
+/// What a program should print: its lines of output and the diagnostic it fails with, if any.
+/// A failing program exits with 1 and everything else with 0.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Expectation {
+    pub stdout: Vec<String>,
+    pub error: Option<String>,
+}
+
+impl Expectation {
+    /// Collects `// expect: ` and `// expect-error: ` comments, or returns `None` if `src`
+    /// has none.
+    pub fn from_annotations(file_name: &str, src: &str) -> anyhow::Result<Option<Self>> {
+        let comments = src.lines().enumerate().filter_map(|(row, line)| {
+            let (_, comment) = line.split_once("//")?;
+            Some((row, comment.trim_start()))
+        });
+        let mut exp = Self::default();
+        let mut found = false;
+        for (row, comment) in comments {
+            found |= exp.add(file_name, row, comment)?;
+        }
+        Ok(found.then_some(exp))
+    }
+
+    /// Reads the contents of a `.expect` file.
+    pub fn parse(file_name: &str, text: &str) -> anyhow::Result<Self> {
+        let mut exp = Self::default();
+        for (row, line) in text.lines().enumerate() {
+            if !line.trim().is_empty() && !exp.add(file_name, row, line)? {
+                anyhow::bail!(
+                    "{file_name}:{}: expected `expect:` or `expect-error:`",
+                    row + 1
+                );
+            }
+        }
+        Ok(exp)
+    }
+
+    /// Adds `line` if it is an expectation, returning whether it was.
+    fn add(&mut self, file_name: &str, row: usize, line: &str) -> anyhow::Result<bool> {
+        if let Some(out) = line.strip_prefix("expect:") {
+            self.stdout.push(out.trim().to_string());
+        } else if let Some(error) = line.strip_prefix("expect-error:") {
+            if self.error.is_some() {
+                anyhow::bail!("{file_name}:{}: a program can only fail once", row + 1);
+            }
+            self.error = Some(error.trim().to_string());
+        } else {
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    pub fn from_outcome(outcome: &Outcome) -> Self {
+        let error = outcome.stderr.trim_end_matches('\n');
+        Self {
+            stdout: outcome.stdout.lines().map(str::to_string).collect(),
+            error: (!error.is_empty())
+                .then(|| error.strip_prefix("Error: ").unwrap_or(error).to_string()),
+        }
+    }
+
+    pub fn to_outcome(&self) -> Outcome {
+        Outcome {
+            stdout: self.stdout.iter().map(|l| format!("{l}\n")).collect(),
+            stderr: self
+                .error
+                .as_ref()
+                .map_or(String::new(), |e| format!("Error: {e}\n")),
+            code: self.error.is_some() as i32,
+        }
+    }
+
+    /// The contents of a `.expect` file.
+    pub fn to_text(&self) -> String {
+        let mut s = String::new();
+        for line in &self.stdout {
+            s.push_str(&format!("expect: {line}\n"));
+        }
+        if let Some(error) = &self.error {
+            s.push_str(&format!("expect-error: {error}\n"));
+        }
+        s
+    }
+}
+
+/// How a single program fared.
+#[derive(Debug, Clone)]
+pub enum Status {
+    Passed,
+    Failed {
+        expected: Outcome,
+        got: Outcome,
+    },
+    /// neither annotations nor a `.expect` file
+    Missing,
+    /// the `.expect` file was written by `--update`
+    Recorded,
+    /// a test block failed with this diagnostic
+    Errored(String),
+}
+
+/// Every `.wa` file under `dir`, sorted.
+pub fn programs(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut found = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            found.extend(programs(&path)?);
+        } else if path.extension().is_some_and(|e| e == "wa") {
+            found.push(path);
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+/// Runs a test block on a fresh stack, adding what it covered to `lcov` under the given source
+/// path. Whatever it prints is discarded, and it may leave data on the stack.
+pub fn run_test(file_name: &str, test: Test, lcov: Option<(&mut Lcov, &str)>) -> Status {
+    match run_quietly(file_name, test.program, lcov) {
+        Ok(()) => Status::Passed,
+        Err(e) => Status::Errored(e.to_string()),
+    }
+}
+
+/// Runs `program` with nothing to read and its output discarded, up to its last op or the first
+/// failure, adding what it covered to `lcov` under the given source path.
+fn run_quietly(
+    file_name: &str,
+    program: Program,
+    lcov: Option<(&mut Lcov, &str)>,
+) -> anyhow::Result<()> {
+    let ctx = Context::new(std::io::empty(), std::io::sink(), std::io::sink());
+    let mut interp = Interpreter::new(file_name, program, ctx);
+    if lcov.is_some() {
+        interp.start_coverage();
+    }
+    let res = loop {
+        match interp.step() {
+            Ok(true) => {}
+            Ok(false) => break Ok(()),
+            Err(e) => break Err(e),
+        }
+    };
+    if let (Some((lcov, source)), Some(coverage)) = (lcov, interp.coverage()) {
+        lcov.add(source, coverage);
+    }
+    res
+}
+
+/// Runs every program under `dir`, or `dir` itself if it is a file, followed by its test blocks,
+/// which are named `<path> test "<name>"`. Programs without annotations have `.expect` files
+/// recorded for them when `update` is set, and what every program and test block covered is
+/// added to `lcov` if given.
+pub fn run_dir(
+    dir: &Path,
+    update: bool,
+    mut lcov: Option<&mut Lcov>,
+) -> anyhow::Result<Vec<(String, Status)>> {
+    let harness = Harness::new(vec![Backend::Interp])?;
+    let (root, paths) = match dir.is_dir() {
+        true => (dir, programs(dir)?),
+        false => (dir.parent().unwrap_or(dir), vec![dir.to_path_buf()]),
+    };
+    let mut results = vec![];
+    for path in paths {
+        let name = path.strip_prefix(root).unwrap_or(&path).to_string_lossy();
+        let src = String::from_utf8_lossy(&std::fs::read(&path)?).into_owned();
+        let label = path.display().to_string();
+        // a program that fails to parse has no tests to run, its output says why
+        let (only_tests, tests) = match parse_ops(Tokeniser::new(src.as_bytes()).collect(), &name) {
+            Ok(mut p) => {
+                let tests = std::mem::take(&mut p.tests);
+                let only_tests = p.ops.is_empty() && !tests.is_empty();
+                if let Some(lcov) = lcov.as_deref_mut() {
+                    // how it fails is the harness's to check
+                    let _ = run_quietly(&name, p, Some((lcov, &label)));
+                }
+                (only_tests, tests)
+            }
+            Err(_) => (false, vec![]),
+        };
+
+        let golden = path.with_extension("expect");
+        let expected = match Expectation::from_annotations(&name, &src)? {
+            Some(exp) => Some(exp),
+            None if only_tests => None,
+            None if update => {
+                let got = harness.run_as(Backend::Interp, &name, &src)?;
+                std::fs::write(&golden, Expectation::from_outcome(&got).to_text())?;
+                results.push((label.clone(), Status::Recorded));
+                None
+            }
+            None => match std::fs::read_to_string(&golden) {
+                Ok(text) => Some(Expectation::parse(&golden.to_string_lossy(), &text)?),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    results.push((label.clone(), Status::Missing));
+                    None
+                }
+                Err(e) => return Err(e.into()),
+            },
+        };
+
+        if let Some(expected) = expected {
+            let got = harness.run_as(Backend::Interp, &name, &src)?;
+            let expected = expected.to_outcome();
+            let status = match got == expected {
+                true => Status::Passed,
+                false => Status::Failed { expected, got },
+            };
+            results.push((label.clone(), status));
+        }
+        for test in tests {
+            let test_label = format!("{label} test \"{}\"", test.name);
+            let status = run_test(&name, test, lcov.as_deref_mut().map(|l| (l, &*label)));
+            results.push((test_label, status));
+        }
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn annotations_round_trip_through_expect_files() {
+        let src = "1 . // expect: 1\n. // expect-error: t.wa:2:1: oops\n";
+        let exp = Expectation::from_annotations("t.wa", src).unwrap().unwrap();
+        assert_eq!(exp.stdout, ["1"]);
+        assert_eq!(exp, Expectation::parse("t.expect", &exp.to_text()).unwrap());
+    }
+
+    #[test]
+    fn examples_pass() {
+        let examples = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples");
+        let results = run_dir(&examples, false, None).unwrap();
+        assert!(!results.is_empty());
+        for (path, status) in results {
+            assert!(matches!(status, Status::Passed), "{path}: {status:?}");
+        }
+    }
+}
@@ -0,0 +1,377 @@
+use std::io::Write;
+
+use crate::{
+    check::Fault,
+    context::Context,
+    coverage::Coverage,
+    limits::Limits,
+    ops::{Op, Op1_2, Op2_2},
+    parse::Program,
+    profile::Profile,
+    snapshot::{fingerprint, Snapshot},
+    stack::Stack,
+    tokenise::{Span, TokenIdx},
+};
+
+pub struct Interpreter<'a> {
+    file_name: String,
+    ops: Vec<Span<Op>>,
+    messages: Vec<String>,
+    stacks: Vec<Vec<isize>>,
+    ctx: Context<'a>,
+    stack: Stack<isize>,
+    jmp_check: Vec<usize>,
+    ip: usize,
+    prev_tok_id: Option<TokenIdx>,
+    steps: usize,
+    profile: Option<Profile>,
+    coverage: Option<Coverage>,
+    /// The op that pushed each value on the stack, bottom first, while tracking provenance.
+    /// Values already on a resumed stack have none.
+    origins: Option<Vec<Option<usize>>>,
+    pub trace: bool,
+    pub limits: Limits,
+}
+
+impl<'a> Interpreter<'a> {
+    pub fn new(
+        file_name: impl AsRef<str>,
+        Program {
+            ops,
+            messages,
+            stacks,
+            ..
+        }: Program,
+        ctx: Context<'a>,
+    ) -> Self {
+        Self {
+            file_name: file_name.as_ref().to_string(),
+            ops,
+            messages,
+            stacks,
+            ctx,
+            stack: Stack::new(),
+            jmp_check: vec![],
+            ip: 0,
+            prev_tok_id: None,
+            steps: 0,
+            profile: None,
+            coverage: None,
+            origins: None,
+            trace: false,
+            limits: Limits::default(),
+        }
+    }
+
+    /// Rebuilds a paused run from a [`Snapshot`] taken against the same `program`.
+    pub fn resume(
+        file_name: impl AsRef<str>,
+        program: Program,
+        ctx: Context<'a>,
+        snapshot: Snapshot,
+    ) -> anyhow::Result<Self> {
+        let mut interp = Self::new(file_name, program, ctx);
+        if snapshot.fingerprint != fingerprint(&interp.ops) {
+            anyhow::bail!(
+                "snapshot was taken from a different program than {}",
+                interp.file_name
+            );
+        }
+        if snapshot.ip > interp.ops.len() {
+            anyhow::bail!(
+                "snapshot instruction pointer {} is out of range",
+                snapshot.ip
+            );
+        }
+        interp.ip = snapshot.ip;
+        interp.steps = snapshot.steps;
+        interp.prev_tok_id = snapshot.prev_tok_id;
+        interp.stack = snapshot.stack.into();
+        interp.jmp_check = snapshot.jmp_check;
+        Ok(interp)
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            fingerprint: fingerprint(&self.ops),
+            ip: self.ip,
+            steps: self.steps,
+            prev_tok_id: self.prev_tok_id,
+            stack: self.stack.as_slice().to_vec(),
+            jmp_check: self.jmp_check.clone(),
+        }
+    }
+
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    /// Counts every op executed from now on.
+    pub fn start_profile(&mut self) {
+        self.profile = Some(Profile::new(&self.ops));
+    }
+
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// Records which ops run and which way each `if` goes from now on.
+    pub fn start_coverage(&mut self) {
+        self.coverage = Some(Coverage::new(&self.ops));
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    /// Remembers which op pushed each value from now on, so that underflow and leftover data
+    /// errors can show the stack and where each value on it came from.
+    pub fn track_provenance(&mut self) {
+        self.origins = Some(vec![None; self.stack.len()]);
+    }
+
+    /// The stack, top first, with the op each value came from, as lines to add to an error.
+    /// Empty unless provenance is tracked.
+    fn provenance(&self) -> String {
+        let Some(origins) = &self.origins else {
+            return String::new();
+        };
+        let mut lines = match self.stack.is_empty() {
+            true => return String::new(),
+            false => String::from("\n  the stack, top first:"),
+        };
+        for (n, origin) in self.stack.as_slice().iter().zip(origins).rev() {
+            lines.push_str(&match origin {
+                Some(ip) => {
+                    let Span { idx, token } = self.ops[*ip];
+                    format!(
+                        "\n    {n} from {token} at {}",
+                        idx.as_stamp(&self.file_name)
+                    )
+                }
+                None => format!("\n    {n} from before the snapshot"),
+            });
+        }
+        lines
+    }
+
+    pub fn run(&mut self) -> anyhow::Result<()> {
+        while self.step()? {}
+
+        if !self.stack.is_empty() {
+            anyhow::bail!(
+                "{}: {}{}",
+                self.prev_tok_id
+                    .unwrap_or_default()
+                    .as_stamp(&self.file_name),
+                Fault::Leftover {
+                    remaining: self.stack.len()
+                },
+                self.provenance()
+            )
+        }
+
+        self.ctx.stdout.flush()?;
+        Ok(())
+    }
+
+    /// Executes the op at the instruction pointer, returning `false` once the program has finished.
+    pub fn step(&mut self) -> anyhow::Result<bool> {
+        let Some(&Span {
+            idx: tok_id,
+            token: op,
+        }) = self.ops.get(self.ip)
+        else {
+            return Ok(false);
+        };
+        self.limits
+            .check_step(self.steps, tok_id, &self.file_name)?;
+        self.steps += 1;
+        if let Some(profile) = &mut self.profile {
+            profile.record(self.ip);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record(self.ip);
+        }
+        // the same error `Stack::pop` would give, showing what there was instead
+        let (pops, pushes) = op.arity();
+        if self.origins.is_some() && self.stack.len() < pops {
+            anyhow::bail!(
+                "{}: Stack Underflow, expected at least {pops} element(s), got {}{}",
+                tok_id.as_stamp(&self.file_name),
+                self.stack.len(),
+                self.provenance()
+            );
+        }
+        let fmt_span = Span {
+            idx: tok_id,
+            token: self.file_name.as_str(),
+        };
+        let (stack, ctx) = (&mut self.stack, &mut self.ctx);
+        let mut next_ip = self.ip + 1;
+        match op {
+            Op::Push(n) => stack.push([n]),
+            Op::Intr1_0(op_id) => stack.run(op_id.into_op(), ctx, fmt_span)?,
+            Op::Intr1_2(op_id) => stack.run(op_id.into_op(), ctx, fmt_span)?,
+            Op::Intr2_1(op_id) => stack.run(op_id.into_op(), ctx, fmt_span)?,
+            Op::Intr2_2(op_id) => stack.run(op_id.into_op(), ctx, fmt_span)?,
+            // types are only checked before running
+            Op::Cast(_) => {
+                let value = stack.pop::<1>(fmt_span)?;
+                stack.push(value);
+            }
+            Op::If(end_idx) => {
+                let [i] = stack.pop::<1>(fmt_span)?;
+                if let (Some(coverage), 0 | 1) = (&mut self.coverage, i) {
+                    coverage.branch(self.ip, i == 1);
+                }
+                match i {
+                    1 => self.jmp_check.push(stack.len()),
+                    0 => next_ip = end_idx.0,
+                    i => anyhow::bail!(
+                        "{at}: expected bool, got {i}",
+                        at = tok_id.as_stamp(&self.file_name)
+                    ),
+                }
+            }
+            Op::End => {
+                let len = self.jmp_check.pop().ok_or(anyhow::anyhow!(
+                    "{at}: Unbalanced END expr",
+                    at = tok_id.as_stamp(&self.file_name)
+                ))?;
+                // TODO: This can be checked at compile time
+                if len != stack.len() {
+                    anyhow::bail!(
+                        "{}: conditional execution must not alter stack length. expected: {len}, got: {}", tok_id.as_stamp(&self.file_name), stack.len())
+                }
+            }
+            Op::Assert(msg_idx) => {
+                let [i] = stack.pop::<1>(fmt_span)?;
+                let at = tok_id.as_stamp(&self.file_name);
+                match (i, msg_idx) {
+                    (1, _) => {}
+                    (0, None) => anyhow::bail!("{at}: assertion failed"),
+                    (0, Some(m)) => anyhow::bail!("{at}: assertion failed: {}", self.messages[m]),
+                    (i, _) => anyhow::bail!("{at}: expected bool, got {i}"),
+                }
+            }
+            Op::AssertStack(stack_idx) => {
+                let expected = &self.stacks[stack_idx];
+                if stack.as_slice() != expected.as_slice() {
+                    anyhow::bail!(
+                        "{at}: assertion failed: expected stack {}, got {}",
+                        show(expected),
+                        show(stack.as_slice()),
+                        at = tok_id.as_stamp(&self.file_name)
+                    )
+                }
+            }
+        };
+        if let Some(origins) = &mut self.origins {
+            // `dup` copies a value, `swap` moves two and a cast keeps one, none makes new ones
+            let len = origins.len();
+            match op {
+                Op::Intr1_2(Op1_2::Duplicate) => origins.push(origins[len - 1]),
+                Op::Intr2_2(Op2_2::Swap) => origins.swap(len - 1, len - 2),
+                Op::Cast(_) => {}
+                _ => {
+                    origins.truncate(len - pops);
+                    origins.extend(std::iter::repeat_n(Some(self.ip), pushes));
+                }
+            }
+        }
+        self.limits
+            .check_stack(self.stack.len(), tok_id, &self.file_name)?;
+        if self.trace {
+            writeln!(
+                self.ctx.stderr,
+                "{at}: {op}",
+                at = tok_id.as_stamp(&self.file_name)
+            )?;
+            writeln!(self.ctx.stderr, "{} ", self.stack)?;
+        }
+        self.prev_tok_id.replace(tok_id);
+        self.ip = next_ip;
+        Ok(true)
+    }
+}
+
+/// `[a b ...]`, bottom first, the way `assert-stack` spells it.
+fn show(stack: &[isize]) -> String {
+    let items = stack.iter().map(isize::to_string).collect::<Vec<_>>();
+    format!("[{}]", items.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse_str;
+
+    fn interpreter(src: &str) -> Interpreter<'static> {
+        let ctx = Context::new(std::io::empty(), std::io::sink(), std::io::sink());
+        Interpreter::new("t.wa", parse_str(src).unwrap(), ctx)
+    }
+
+    /// The error running `src` with provenance ends with.
+    fn error(src: &str) -> String {
+        let mut interp = interpreter(src);
+        interp.track_provenance();
+        interp.run().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn follows_values_through_dup_and_swap() {
+        assert_eq!(
+            error("1 2 swap dup"),
+            "\
+t.wa:1:10: Unhandled data on the stack. 3 element(s) remaining after last operation
+  the stack, top first:
+    1 from PUSH 1 at t.wa:1:1
+    1 from PUSH 1 at t.wa:1:1
+    2 from PUSH 2 at t.wa:1:3"
+        );
+    }
+
+    #[test]
+    fn blames_results_on_the_op_that_made_them() {
+        assert_eq!(
+            error("3 4 + -"),
+            "\
+t.wa:1:7: Stack Underflow, expected at least 2 element(s), got 1
+  the stack, top first:
+    7 from ADD at t.wa:1:5"
+        );
+    }
+
+    #[test]
+    fn keeps_origins_through_casts() {
+        assert_eq!(
+            error("1 2 int"),
+            "\
+t.wa:1:5: Unhandled data on the stack. 2 element(s) remaining after last operation
+  the stack, top first:
+    2 from PUSH 2 at t.wa:1:3
+    1 from PUSH 1 at t.wa:1:1"
+        );
+    }
+
+    #[test]
+    fn marks_values_from_before_a_snapshot() {
+        let mut interp = interpreter("1 2 3 +");
+        interp.step().unwrap();
+        interp.step().unwrap();
+        let snapshot = interp.snapshot();
+        let ctx = Context::new(std::io::empty(), std::io::sink(), std::io::sink());
+        let mut interp =
+            Interpreter::resume("t.wa", parse_str("1 2 3 +").unwrap(), ctx, snapshot).unwrap();
+        interp.track_provenance();
+        assert_eq!(
+            interp.run().unwrap_err().to_string(),
+            "\
+t.wa:1:7: Unhandled data on the stack. 2 element(s) remaining after last operation
+  the stack, top first:
+    5 from ADD at t.wa:1:7
+    1 from before the snapshot"
+        );
+    }
+}
@@ -0,0 +1,512 @@
+use std::fmt::Write;
+
+use crate::{
+    check::{check, Fault},
+    ops::{Op, Op1_0, Op1_2, Op2_1, Op2_2},
+    parse::Program,
+    tokenise::{Span, TokenIdx},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Value(pub u32);
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "v{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockId(pub u32);
+
+impl std::fmt::Display for BlockId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "b{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    /// traps on a zero divisor
+    Div,
+    /// traps on a zero divisor
+    Rem,
+    Eq,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+}
+
+impl std::fmt::Display for BinOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            BinOp::Add => "add",
+            BinOp::Sub => "sub",
+            BinOp::Mul => "mul",
+            BinOp::Div => "div",
+            BinOp::Rem => "rem",
+            BinOp::Eq => "eq",
+            BinOp::Lt => "lt",
+            BinOp::Gt => "gt",
+            BinOp::Le => "le",
+            BinOp::Ge => "ge",
+        };
+        write!(f, "{s}")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum InstKind {
+    Const(isize),
+    /// `lhs op rhs`, where `lhs` was the top of the stack
+    Binary(BinOp, Value, Value),
+    Display(Value),
+}
+
+#[derive(Debug, Clone)]
+pub struct Inst {
+    pub result: Option<Value>,
+    pub kind: InstKind,
+    pub span: TokenIdx,
+}
+
+/// A control transfer, passing `args` to the params of `to`.
+#[derive(Debug, Clone)]
+pub struct Edge {
+    pub to: BlockId,
+    pub args: Vec<Value>,
+}
+
+impl std::fmt::Display for Edge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to)?;
+        if !self.args.is_empty() {
+            write!(f, "({})", list(&self.args))?;
+        }
+        Ok(())
+    }
+}
+
+fn list(vals: &[Value]) -> String {
+    vals.iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[derive(Debug, Clone)]
+pub enum Terminator {
+    Jump(Edge),
+    /// Goes to `then` when `cond` is 1 and to `els` when it is 0, trapping on anything else.
+    Branch {
+        cond: Value,
+        then: Edge,
+        els: Edge,
+    },
+    /// A stack fault the checker proved certain once the block is reached.
+    Trap(Fault),
+    /// Ends the program, which is an error if any values are left.
+    Return(Vec<Value>),
+}
+
+impl Terminator {
+    fn edges_mut(&mut self) -> Vec<&mut Edge> {
+        match self {
+            Terminator::Jump(edge) => vec![edge],
+            Terminator::Branch { then, els, .. } => vec![then, els],
+            Terminator::Trap(_) | Terminator::Return(_) => vec![],
+        }
+    }
+
+    fn for_each_value(&mut self, mut f: impl FnMut(&mut Value)) {
+        match self {
+            Terminator::Jump(Edge { args, .. }) | Terminator::Return(args) => {
+                args.iter_mut().for_each(f)
+            }
+            Terminator::Branch { cond, then, els } => {
+                f(cond);
+                then.args.iter_mut().chain(els.args.iter_mut()).for_each(f);
+            }
+            Terminator::Trap(_) => {}
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub params: Vec<Value>,
+    pub insts: Vec<Inst>,
+    pub term: Terminator,
+    pub term_span: TokenIdx,
+}
+
+/// A `Program` in SSA form.
+///
+/// Stack slots become named values: `dup` and `swap` only rename, and every op that computes
+/// something defines a fresh value. Blocks follow the `if`/`end` structure, which is the only
+/// control flow the language has. Where paths merge after a block, the merge takes block
+/// params for the slots that differ between its predecessors.
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub blocks: Vec<Block>,
+    next_value: u32,
+}
+
+impl Function {
+    fn value(&mut self) -> Value {
+        self.next_value += 1;
+        Value(self.next_value - 1)
+    }
+
+    fn block(&mut self, params: usize) -> BlockId {
+        let params = (0..params).map(|_| self.value()).collect();
+        self.blocks.push(Block {
+            params,
+            insts: vec![],
+            term: Terminator::Return(vec![]),
+            term_span: TokenIdx::default(),
+        });
+        BlockId(self.blocks.len() as u32 - 1)
+    }
+
+    fn inst(&mut self, b: BlockId, kind: InstKind, span: TokenIdx) -> Value {
+        let v = self.value();
+        self.blocks[b.0 as usize].insts.push(Inst {
+            result: Some(v),
+            kind,
+            span,
+        });
+        v
+    }
+
+    fn terminate(&mut self, b: BlockId, term: Terminator, span: TokenIdx) {
+        let block = &mut self.blocks[b.0 as usize];
+        block.term = term;
+        block.term_span = span;
+    }
+
+    pub fn new(Program { ops, .. }: &Program) -> Self {
+        let checked = check(ops);
+        let mut func = Function {
+            blocks: vec![],
+            next_value: 0,
+        };
+        // blocks that start at an op, where a skipped `if` block rejoins
+        let mut joins = vec![None; ops.len()];
+        let mut cur = Some((func.block(0), vec![]));
+        let mut last = TokenIdx::default();
+
+        for (i, Span { idx, token: op }) in ops.iter().enumerate() {
+            if let Some(join) = joins[i] {
+                if let Some((b, stack)) = cur.take() {
+                    let edge = Edge {
+                        to: join,
+                        args: stack,
+                    };
+                    func.terminate(b, Terminator::Jump(edge), last);
+                }
+                let params = func.blocks[join.0 as usize].params.clone();
+                cur = Some((join, params));
+            }
+            let Some((b, mut stack)) = cur.take() else {
+                continue;
+            };
+            let span = *idx;
+            last = span;
+            if let Some(fault) = checked.faults[i] {
+                func.terminate(b, Terminator::Trap(fault), span);
+                continue;
+            }
+
+            match *op {
+                Op::Push(n) => stack.push(func.inst(b, InstKind::Const(n), span)),
+                Op::Intr1_0(Op1_0::Display) => {
+                    let t = stack.pop().expect("checked");
+                    func.blocks[b.0 as usize].insts.push(Inst {
+                        result: None,
+                        kind: InstKind::Display(t),
+                        span,
+                    });
+                }
+                Op::Intr1_0(Op1_0::Drop) => {
+                    stack.pop();
+                }
+                Op::Intr1_2(Op1_2::Duplicate) => stack.push(*stack.last().expect("checked")),
+                Op::Intr2_2(Op2_2::Swap) => {
+                    let n = stack.len();
+                    stack.swap(n - 1, n - 2);
+                }
+                Op::Intr2_1(op_id) => {
+                    let (t, t1) = (stack.pop().expect("checked"), stack.pop().expect("checked"));
+                    let op = match op_id {
+                        Op2_1::Add => BinOp::Add,
+                        Op2_1::Sub => BinOp::Sub,
+                        Op2_1::Mul => BinOp::Mul,
+                        Op2_1::Div => BinOp::Div,
+                        Op2_1::Mod => BinOp::Rem,
+                        Op2_1::Equ => BinOp::Eq,
+                        Op2_1::Less => BinOp::Lt,
+                        Op2_1::Greater => BinOp::Gt,
+                        Op2_1::LessEqu => BinOp::Le,
+                        Op2_1::GreaterEqu => BinOp::Ge,
+                    };
+                    stack.push(func.inst(b, InstKind::Binary(op, t, t1), span));
+                }
+                Op::Intr2_2(Op2_2::DivMod) => {
+                    let (t, t1) = (stack.pop().expect("checked"), stack.pop().expect("checked"));
+                    stack.push(func.inst(b, InstKind::Binary(BinOp::Rem, t, t1), span));
+                    stack.push(func.inst(b, InstKind::Binary(BinOp::Div, t, t1), span));
+                }
+                Op::If(target) => {
+                    let cond = stack.pop().expect("checked");
+                    let then = func.block(0);
+                    // a skipped block that closes the program returns from the `if`, which is
+                    // blamed for any data left over
+                    let els = match joins.get(target.0) {
+                        Some(_) => {
+                            let join = func.block(stack.len());
+                            joins[target.0] = Some(join);
+                            join
+                        }
+                        None => {
+                            let ret = func.block(stack.len());
+                            let params = func.blocks[ret.0 as usize].params.clone();
+                            func.terminate(ret, Terminator::Return(params), span);
+                            ret
+                        }
+                    };
+                    let term = Terminator::Branch {
+                        cond,
+                        then: Edge {
+                            to: then,
+                            args: vec![],
+                        },
+                        els: Edge {
+                            to: els,
+                            args: stack.clone(),
+                        },
+                    };
+                    func.terminate(b, term, span);
+                    cur = Some((then, stack));
+                    continue;
+                }
+                Op::End | Op::Cast(_) => {}
+                Op::Assert(_) | Op::AssertStack(_) => {
+                    unreachable!("assertions only parse inside test blocks")
+                }
+            }
+            cur = Some((b, stack));
+        }
+
+        if let Some((b, stack)) = cur {
+            func.terminate(b, Terminator::Return(stack), last);
+        }
+        func.remove_trivial_params();
+        func
+    }
+
+    /// Drops block params that receive the same value from every predecessor, other than the
+    /// param itself, replacing them with that value.
+    fn remove_trivial_params(&mut self) {
+        let mut subst: Vec<Option<Value>> = vec![None; self.next_value as usize];
+        let resolve = |subst: &[Option<Value>], mut v: Value| {
+            while let Some(to) = subst[v.0 as usize] {
+                v = to;
+            }
+            v
+        };
+
+        loop {
+            let mut changed = false;
+            for b in 0..self.blocks.len() {
+                let mut k = 0;
+                while k < self.blocks[b].params.len() {
+                    let param = self.blocks[b].params[k];
+                    let mut incoming = None;
+                    let mut trivial = true;
+                    for pred in &mut self.blocks {
+                        for edge in pred.term.edges_mut() {
+                            if edge.to.0 as usize != b {
+                                continue;
+                            }
+                            let arg = resolve(&subst, edge.args[k]);
+                            if arg == param || incoming == Some(arg) {
+                                continue;
+                            }
+                            trivial &= incoming.is_none();
+                            incoming = Some(arg);
+                        }
+                    }
+                    match (trivial, incoming) {
+                        (true, Some(v)) => {
+                            subst[param.0 as usize] = Some(v);
+                            self.blocks[b].params.remove(k);
+                            for pred in &mut self.blocks {
+                                for edge in pred.term.edges_mut() {
+                                    if edge.to.0 as usize == b {
+                                        edge.args.remove(k);
+                                    }
+                                }
+                            }
+                            changed = true;
+                        }
+                        _ => k += 1,
+                    }
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        for block in &mut self.blocks {
+            for inst in &mut block.insts {
+                match &mut inst.kind {
+                    InstKind::Const(_) => {}
+                    InstKind::Binary(_, a, b) => {
+                        *a = resolve(&subst, *a);
+                        *b = resolve(&subst, *b);
+                    }
+                    InstKind::Display(a) => *a = resolve(&subst, *a),
+                }
+            }
+            block.term.for_each_value(|v| *v = resolve(&subst, *v));
+        }
+    }
+
+    /// Text form, one instruction per line with the position in `file_name` it came from.
+    pub fn to_text(&self, file_name: impl AsRef<str>) -> String {
+        let mut s = format!("; {}\n", file_name.as_ref());
+        for (i, block) in self.blocks.iter().enumerate() {
+            match block.params.is_empty() {
+                true => {
+                    let _ = writeln!(s, "b{i}:");
+                }
+                false => {
+                    let _ = writeln!(s, "b{i}({}):", list(&block.params));
+                }
+            }
+            for inst in &block.insts {
+                let line = match (&inst.kind, inst.result) {
+                    (InstKind::Const(n), Some(v)) => format!("{v} = const {n}"),
+                    (InstKind::Binary(op, a, b), Some(v)) => format!("{v} = {op} {a}, {b}"),
+                    (InstKind::Display(a), _) => format!("display {a}"),
+                    (_, None) => unreachable!("value instruction without a result"),
+                };
+                let _ = writeln!(s, "    {line:<32} ; {}", inst.span);
+            }
+            let line = match &block.term {
+                Terminator::Jump(edge) => format!("jump {edge}"),
+                Terminator::Branch { cond, then, els } => format!("br {cond}, {then}, {els}"),
+                Terminator::Trap(fault) => format!("trap \"{fault}\""),
+                Terminator::Return(vals) if vals.is_empty() => "ret".to_string(),
+                Terminator::Return(vals) => format!("ret {}", list(vals)),
+            };
+            let _ = writeln!(s, "    {line:<32} ; {}", block.term_span);
+        }
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse_str;
+
+    fn ir(src: &str) -> String {
+        Function::new(&parse_str(src).unwrap()).to_text("t.wa")
+    }
+
+    #[test]
+    fn merges_a_changed_slot_through_a_block_param() {
+        let expected = r#"; t.wa
+b0:
+    v0 = const 3                     ; 1:1
+    v1 = const 1                     ; 1:3
+    br v1, b1, b2(v0)                ; 1:5
+b1:
+    v3 = const 1                     ; 1:8
+    v4 = add v3, v0                  ; 1:10
+    jump b2(v4)                      ; 1:12
+b2(v2):
+    display v2                       ; 1:16
+    ret                              ; 1:16
+"#;
+        assert_eq!(ir("3 1 if 1 + end ."), expected);
+    }
+
+    #[test]
+    fn keeps_both_params_when_a_block_swaps() {
+        let expected = r#"; t.wa
+b0:
+    v0 = const 1                     ; 1:1
+    v1 = const 2                     ; 1:3
+    v2 = const 3                     ; 1:5
+    br v2, b1, b2(v0, v1)            ; 1:7
+b1:
+    jump b2(v1, v0)                  ; 1:15
+b2(v3, v4):
+    display v4                       ; 1:19
+    display v3                       ; 1:21
+    ret                              ; 1:21
+"#;
+        assert_eq!(ir("1 2 3 if swap end . ."), expected);
+    }
+
+    #[test]
+    fn removes_params_every_edge_agrees_on() {
+        let expected = r#"; t.wa
+b0:
+    v0 = const 1                     ; 1:1
+    v1 = const 2                     ; 1:3
+    v2 = const 3                     ; 1:5
+    br v2, b1, b2                    ; 1:7
+b1:
+    v5 = const 4                     ; 1:10
+    jump b2                          ; 1:17
+b2:
+    v6 = add v1, v0                  ; 1:21
+    display v6                       ; 1:23
+    ret                              ; 1:23
+"#;
+        assert_eq!(ir("1 2 3 if 4 drop end + ."), expected);
+    }
+
+    #[test]
+    fn returns_from_nested_ifs_that_close_the_program() {
+        let expected = r#"; t.wa
+b0:
+    v0 = const 1                     ; 1:1
+    v1 = const 2                     ; 1:3
+    br v1, b1, b2                    ; 1:5
+b1:
+    v3 = const 3                     ; 1:8
+    v4 = const 4                     ; 1:10
+    v5 = lt v4, v3                   ; 1:12
+    br v5, b3, b4                    ; 1:14
+b2:
+    ret v0                           ; 1:5
+b3:
+    v7 = const 5                     ; 1:17
+    display v7                       ; 1:19
+    jump b4                          ; 1:21
+b4:
+    ret v0                           ; 1:25
+"#;
+        assert_eq!(ir("1 2 if 3 4 < if 5 . end end"), expected);
+    }
+
+    #[test]
+    fn traps_where_a_fault_is_certain() {
+        let expected = r#"; t.wa
+b0:
+    v0 = const 1                     ; 1:1
+    trap "Stack Underflow, expected at least 2 element(s), got 1" ; 1:3
+"#;
+        assert_eq!(ir("1 +"), expected);
+    }
+}
@@ -0,0 +1,344 @@
+use std::{ffi::c_void, io::Write};
+
+use crate::{
+    check::{check, Fault},
+    context::Context,
+    native::{lower, Lowering, Runtime, Trap},
+    parse::Program,
+    tokenise::TokenIdx,
+    x86::{Alu, Asm, Cond, Inst, Label, Mem, Reg},
+};
+
+const PROT_READ: i32 = 0x1;
+const PROT_WRITE: i32 = 0x2;
+const PROT_EXEC: i32 = 0x4;
+const MAP_PRIVATE: i32 = 0x02;
+const MAP_ANONYMOUS: i32 = 0x20;
+
+extern "C" {
+    fn mmap(addr: *mut c_void, len: usize, prot: i32, flags: i32, fd: i32, off: i64)
+        -> *mut c_void;
+    fn mprotect(addr: *mut c_void, len: usize, prot: i32) -> i32;
+    fn munmap(addr: *mut c_void, len: usize) -> i32;
+}
+
+/// Machine code mapped read + execute.
+struct ExecBuffer {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+impl ExecBuffer {
+    fn new(code: &[u8]) -> anyhow::Result<Self> {
+        let len = code.len().max(1);
+        // SAFETY: a fresh anonymous mapping, written while still writable and then sealed
+        unsafe {
+            let ptr = mmap(
+                std::ptr::null_mut(),
+                len,
+                PROT_READ | PROT_WRITE,
+                MAP_PRIVATE | MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            if ptr as isize == -1 {
+                anyhow::bail!("mmap failed: {}", std::io::Error::last_os_error());
+            }
+            std::ptr::copy_nonoverlapping(code.as_ptr(), ptr as *mut u8, code.len());
+            if mprotect(ptr, len, PROT_READ | PROT_EXEC) != 0 {
+                let e = std::io::Error::last_os_error();
+                munmap(ptr, len);
+                anyhow::bail!("mprotect failed: {e}");
+            }
+            Ok(Self { ptr, len })
+        }
+    }
+}
+
+impl Drop for ExecBuffer {
+    fn drop(&mut self) {
+        unsafe { munmap(self.ptr, self.len) };
+    }
+}
+
+/// State shared between jitted code and the Rust helpers it calls, pointed to by `rbx`.
+#[repr(C)]
+struct Frame<'c, 'a> {
+    op: u64,
+    value: i64,
+    ctx: &'c mut Context<'a>,
+    io_error: Option<std::io::Error>,
+}
+
+const OK: i64 = 0;
+const FAULT: i64 = 1;
+const DIV_ZERO: i64 = 2;
+const NOT_BOOL: i64 = 3;
+const LEFTOVER: i64 = 4;
+const IO: i64 = 5;
+
+extern "C" fn display(frame: &mut Frame, value: i64) -> u64 {
+    match writeln!(frame.ctx.stdout, "{value}") {
+        Ok(()) => 0,
+        Err(e) => {
+            frame.io_error = Some(e);
+            1
+        }
+    }
+}
+
+#[derive(Default)]
+struct JitRuntime {
+    exit: Option<Label>,
+}
+
+impl JitRuntime {
+    fn exit(&self) -> Label {
+        self.exit.expect("prologue emitted first")
+    }
+
+    fn fail(&mut self, asm: &mut Asm, op: usize, kind: i64) {
+        asm.emit(Inst::MovImm(Reg::Rcx, op as i64));
+        asm.emit(Inst::Store(
+            Mem::Base(Reg::Rbx, std::mem::offset_of!(Frame, op) as i32),
+            Reg::Rcx,
+        ));
+        asm.emit(Inst::MovImm(Reg::Rax, kind));
+        asm.emit(Inst::Jmp(self.exit()));
+    }
+}
+
+const SAVED: [Reg; 6] = [Reg::Rbx, Reg::Rbp, Reg::R12, Reg::R13, Reg::R14, Reg::R15];
+
+impl Runtime for JitRuntime {
+    fn prologue(&mut self, asm: &mut Asm) {
+        let (exit, body) = (asm.label(), asm.label());
+        self.exit = Some(exit);
+        for r in SAVED {
+            asm.emit(Inst::Push(r));
+        }
+        asm.emit(Inst::Mov(Reg::Rbx, Reg::Rdi));
+        asm.emit(Inst::Mov(Reg::Rbp, Reg::Rsp));
+        asm.emit(Inst::Jmp(body));
+        asm.emit(Inst::Label(exit));
+        asm.emit(Inst::Mov(Reg::Rsp, Reg::Rbp));
+        for r in SAVED.into_iter().rev() {
+            asm.emit(Inst::Pop(r));
+        }
+        asm.emit(Inst::Ret);
+        asm.emit(Inst::Label(body));
+    }
+
+    fn epilogue(&mut self, asm: &mut Asm) {
+        asm.emit(Inst::MovImm(Reg::Rax, OK));
+        asm.emit(Inst::Jmp(self.exit()));
+    }
+
+    fn display(&mut self, asm: &mut Asm, op: usize) {
+        let ok = asm.label();
+        asm.emit(Inst::Mov(Reg::Rsi, Reg::Rax));
+        asm.emit(Inst::Mov(Reg::Rdi, Reg::Rbx));
+        // the data stack leaves rsp at any 8 byte boundary, the SysV ABI wants 16
+        asm.emit(Inst::Mov(Reg::R12, Reg::Rsp));
+        asm.emit(Inst::AluImm(Alu::And, Reg::Rsp, -16));
+        asm.emit(Inst::MovImm(Reg::Rax, display as *const () as i64));
+        asm.emit(Inst::CallReg(Reg::Rax));
+        asm.emit(Inst::Mov(Reg::Rsp, Reg::R12));
+        asm.emit(Inst::Test(Reg::Rax, Reg::Rax));
+        asm.emit(Inst::Jcc(Cond::E, ok));
+        self.fail(asm, op, IO);
+        asm.emit(Inst::Label(ok));
+    }
+
+    fn trap(&mut self, asm: &mut Asm, op: usize, trap: Trap) {
+        let kind = match trap {
+            Trap::Fault(_) => FAULT,
+            Trap::DivZero => DIV_ZERO,
+            Trap::NotBool => {
+                asm.emit(Inst::Store(
+                    Mem::Base(Reg::Rbx, std::mem::offset_of!(Frame, value) as i32),
+                    Reg::Rax,
+                ));
+                NOT_BOOL
+            }
+            Trap::Leftover(n) => {
+                asm.emit(Inst::MovImm(Reg::Rax, n as i64));
+                asm.emit(Inst::Store(
+                    Mem::Base(Reg::Rbx, std::mem::offset_of!(Frame, value) as i32),
+                    Reg::Rax,
+                ));
+                LEFTOVER
+            }
+        };
+        self.fail(asm, op, kind);
+    }
+}
+
+/// Deepest data stack, in slots, that jitted code may build. The stack lives on the calling
+/// thread's native stack, so this keeps well inside the smallest default thread stack (2 MiB).
+pub const MAX_STACK_DEPTH: usize = 64 * 1024;
+
+/// A `Program` compiled to x86-64 in an executable mapping of this process.
+pub struct Jit {
+    file_name: String,
+    code: ExecBuffer,
+    code_len: usize,
+    spans: Vec<TokenIdx>,
+    faults: Vec<Option<Fault>>,
+}
+
+impl Jit {
+    pub fn new(file_name: impl AsRef<str>, program: &Program) -> anyhow::Result<Self> {
+        Self::with_lowering(file_name, program, Lowering::default())
+    }
+
+    pub fn with_lowering(
+        file_name: impl AsRef<str>,
+        Program { ops, .. }: &Program,
+        lowering: Lowering,
+    ) -> anyhow::Result<Self> {
+        let checked = check(ops);
+        if checked.max_depth > MAX_STACK_DEPTH {
+            let i = (0..ops.len())
+                .find(|&i| checked.depth_at(i + 1).is_some_and(|d| d > MAX_STACK_DEPTH))
+                .unwrap_or_default();
+            anyhow::bail!(
+                "{at}: stack depth of {depth} exceeds the jit limit of {MAX_STACK_DEPTH}",
+                at = ops[i].idx.as_stamp(file_name),
+                depth = checked.max_depth,
+            );
+        }
+
+        let mut asm = Asm::new();
+        lower(&mut asm, ops, &mut JitRuntime::default(), lowering);
+        let assembled = asm.assemble();
+        debug_assert!(assembled.relocs.is_empty());
+        Ok(Self {
+            file_name: file_name.as_ref().to_string(),
+            code: ExecBuffer::new(&assembled.code)?,
+            code_len: assembled.code.len(),
+            spans: ops.iter().map(|s| s.idx).collect(),
+            faults: checked.faults,
+        })
+    }
+
+    /// Size of the generated machine code in bytes.
+    pub fn code_len(&self) -> usize {
+        self.code_len
+    }
+
+    pub fn run(&self, ctx: &mut Context) -> anyhow::Result<()> {
+        let mut frame = Frame {
+            op: 0,
+            value: 0,
+            ctx,
+            io_error: None,
+        };
+        // SAFETY: the buffer holds a function lowered against `JitRuntime`, which takes a
+        // `Frame` in `rdi`, restores every callee-saved register and returns a status in `rax`.
+        let status = unsafe {
+            let f: extern "C" fn(*mut Frame) -> i64 = std::mem::transmute(self.code.ptr);
+            f(&mut frame)
+        };
+
+        let at = self
+            .spans
+            .get(frame.op as usize)
+            .copied()
+            .unwrap_or_default()
+            .as_stamp(&self.file_name);
+        match status {
+            OK => {}
+            FAULT => match self.faults[frame.op as usize] {
+                Some(fault) => anyhow::bail!("{at}: {fault}"),
+                None => unreachable!("trapped on an op without a fault"),
+            },
+            DIV_ZERO => anyhow::bail!("{at}: division by zero"),
+            NOT_BOOL => anyhow::bail!("{at}: expected bool, got {}", frame.value),
+            LEFTOVER => anyhow::bail!(
+                "{at}: {}",
+                Fault::Leftover {
+                    remaining: frame.value as usize
+                }
+            ),
+            IO => match frame.io_error.take() {
+                Some(e) => anyhow::bail!("{at}: {e}"),
+                None => unreachable!("io trap without an error"),
+            },
+            s => unreachable!("unknown jit status {s}"),
+        }
+        frame.ctx.stdout.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse_str;
+
+    fn jit(src: &str) -> anyhow::Result<Jit> {
+        Jit::new("t.wa", &parse_str(src)?)
+    }
+
+    #[test]
+    fn runs_within_the_stack_limit() {
+        let mut stdout = vec![];
+        jit("1 2 + .")
+            .unwrap()
+            .run(&mut Context::new(
+                std::io::empty(),
+                &mut stdout,
+                std::io::sink(),
+            ))
+            .unwrap();
+        assert_eq!(stdout, b"3\n");
+    }
+
+    /// What running `src` printed and the error it stopped with, if any.
+    fn outcome(src: &str, lowering: Lowering) -> (String, Option<String>) {
+        let mut stdout = vec![];
+        let res = Jit::with_lowering("t.wa", &parse_str(src).unwrap(), lowering)
+            .unwrap()
+            .run(&mut Context::new(
+                std::io::empty(),
+                &mut stdout,
+                std::io::sink(),
+            ));
+        (
+            String::from_utf8(stdout).unwrap(),
+            res.err().map(|e| e.to_string()),
+        )
+    }
+
+    #[test]
+    fn lowerings_agree() {
+        for src in [
+            "1 2 + 3 * 4 - .",
+            // deeper than the registers that cache the top of the stack
+            "1 2 3 4 5 6 7 8 9 + + + + + + + + .",
+            "9 4 /% . . 9 4 % . 9 4 / . 7 -1 / .",
+            "1 2 swap . . 3 dup * . 4 5 drop .",
+            "1 2 < if 3 4 + . end 2 1 < if 5 . end 6 .",
+            "1 2 3 4 5 6 7 1 if 8 9 + drop end + + + + + + .",
+            "1 2 .",
+            "0 1 /",
+            "2 if end",
+            "1 +",
+        ] {
+            let naive = outcome(src, Lowering::Naive);
+            assert_eq!(outcome(src, Lowering::StackCached), naive, "{src}");
+        }
+        assert_eq!(
+            outcome("1 2 3 4 5 6 7 8 9 + + + + + + + + .", Lowering::Naive).0,
+            "45\n"
+        );
+    }
+
+    #[test]
+    fn rejects_programs_deeper_than_the_stack_limit() {
+        let src = "1 ".repeat(MAX_STACK_DEPTH + 1);
+        let e = jit(&src).err().expect("too deep to jit");
+        assert!(e.to_string().contains("exceeds the jit limit"), "{e}");
+    }
+}
@@ -0,0 +1,151 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use crate::tokenise::TokenIdx;
+
+/// Per-run resource limits, checked by the interpreter before and after every op.
+///
+/// There are no limits on memory size or call depth, as programs have no memory and make no
+/// calls.
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    pub max_steps: Option<usize>,
+    pub max_stack_depth: Option<usize>,
+    pub cancel: Option<Arc<AtomicBool>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Steps(usize),
+    StackDepth(usize),
+    Cancelled,
+}
+
+/// The error returned when a run hits one of its [`Limits`], so embedders can tell it apart from
+/// a fault in the program itself.
+#[derive(Debug)]
+pub struct LimitExceeded {
+    pub at: String,
+    pub limit: Limit,
+}
+
+impl std::fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.limit {
+            Limit::Steps(n) => write!(f, "{}: step budget of {n} op(s) exhausted", self.at),
+            Limit::StackDepth(n) => write!(f, "{}: stack depth limit of {n} exceeded", self.at),
+            Limit::Cancelled => write!(f, "{}: execution cancelled", self.at),
+        }
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+impl Limits {
+    /// Called before the op at `tok_id` runs, with the number of ops executed so far.
+    pub fn check_step(
+        &self,
+        steps: usize,
+        tok_id: TokenIdx,
+        file_name: impl AsRef<str>,
+    ) -> Result<(), LimitExceeded> {
+        let limit = match (&self.cancel, self.max_steps) {
+            (Some(c), _) if c.load(Ordering::Relaxed) => Limit::Cancelled,
+            (_, Some(n)) if steps >= n => Limit::Steps(n),
+            _ => return Ok(()),
+        };
+        Err(LimitExceeded {
+            at: tok_id.as_stamp(file_name),
+            limit,
+        })
+    }
+
+    /// Called after the op at `tok_id` has run, with the resulting stack depth.
+    pub fn check_stack(
+        &self,
+        depth: usize,
+        tok_id: TokenIdx,
+        file_name: impl AsRef<str>,
+    ) -> Result<(), LimitExceeded> {
+        match self.max_stack_depth {
+            Some(n) if depth > n => Err(LimitExceeded {
+                at: tok_id.as_stamp(file_name),
+                limit: Limit::StackDepth(n),
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{context::Context, interp::Interpreter, parse::parse_str};
+
+    fn run(src: &str, limits: Limits) -> anyhow::Result<()> {
+        let ctx = Context::new(std::io::empty(), std::io::sink(), std::io::sink());
+        let mut interp = Interpreter::new("t.wa", parse_str(src).unwrap(), ctx);
+        interp.limits = limits;
+        interp.run()
+    }
+
+    /// The limit `src` exceeds and where.
+    fn exceeded(src: &str, limits: Limits) -> (String, Limit) {
+        let e = run(src, limits).unwrap_err();
+        let e = e
+            .downcast_ref::<LimitExceeded>()
+            .unwrap_or_else(|| panic!("not a LimitExceeded: {e}"));
+        (e.at.clone(), e.limit)
+    }
+
+    #[test]
+    fn stops_after_the_step_budget() {
+        let limits = Limits {
+            max_steps: Some(3),
+            ..Limits::default()
+        };
+        assert_eq!(
+            exceeded("1 2 + .", limits.clone()),
+            ("t.wa:1:7".to_string(), Limit::Steps(3))
+        );
+        assert_eq!(
+            run("1 2 + .", limits).unwrap_err().to_string(),
+            "t.wa:1:7: step budget of 3 op(s) exhausted"
+        );
+        let limits = Limits {
+            max_steps: Some(4),
+            ..Limits::default()
+        };
+        run("1 2 + .", limits).unwrap();
+    }
+
+    #[test]
+    fn stops_past_the_stack_depth() {
+        let limits = Limits {
+            max_stack_depth: Some(2),
+            ..Limits::default()
+        };
+        assert_eq!(
+            exceeded("1 2 drop 3 4 drop drop drop", limits.clone()),
+            ("t.wa:1:12".to_string(), Limit::StackDepth(2))
+        );
+        run("1 2 drop 3 drop drop", limits).unwrap();
+    }
+
+    #[test]
+    fn stops_when_cancelled() {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let limits = Limits {
+            cancel: Some(cancel.clone()),
+            ..Limits::default()
+        };
+        run("1 .", limits.clone()).unwrap();
+        cancel.store(true, Ordering::Relaxed);
+        assert_eq!(
+            exceeded("1 .", limits),
+            ("t.wa:1:1".to_string(), Limit::Cancelled)
+        );
+    }
+}
@@ -0,0 +1,427 @@
+//! Lints: code that runs, but most likely not the way its author meant.
+//!
+//! Each [`Lint`] can be allowed, reported as a warning, or denied so that it stops the program
+//! from running, with `-A=<lint>`, `-W=<lint>` and `-D=<lint>`, where `all` names every lint.
+//! `wa lint` warns about every lint by default, while runs allow `constant-if`, since a constant
+//! condition is the usual way to switch a block on or off by hand.
+
+use std::io::Write;
+
+use crate::{
+    check::check,
+    ops::{Op, Op1_0, Op1_2, Op2_1, Op2_2},
+    parse::Program,
+    tokenise::{Span, TokenIdx},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lint {
+    /// `dup drop` and `swap swap`
+    RedundantPair,
+    /// an `if` whose condition is the same on every run
+    ConstantIf,
+    /// arithmetic on constants that wraps around
+    Overflow,
+    /// ops after one that always fails
+    Unreachable,
+}
+
+impl Lint {
+    pub const ALL: [Lint; 4] = [
+        Lint::RedundantPair,
+        Lint::ConstantIf,
+        Lint::Overflow,
+        Lint::Unreachable,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Lint::RedundantPair => "redundant-pair",
+            Lint::ConstantIf => "constant-if",
+            Lint::Overflow => "overflow",
+            Lint::Unreachable => "unreachable",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Allow,
+    Warn,
+    Deny,
+}
+
+/// The level of every lint.
+#[derive(Debug, Clone)]
+pub struct Levels([Level; Lint::ALL.len()]);
+
+impl Default for Levels {
+    fn default() -> Self {
+        Self([Level::Warn; Lint::ALL.len()])
+    }
+}
+
+impl Levels {
+    /// The defaults for running a program rather than linting it.
+    pub fn for_runs() -> Self {
+        let mut levels = Self::default();
+        levels.0[Lint::ConstantIf as usize] = Level::Allow;
+        levels
+    }
+
+    pub fn get(&self, lint: Lint) -> Level {
+        self.0[lint as usize]
+    }
+
+    /// Applies `flag` if it is `-A=`, `-W=` or `-D=`, returning whether it was.
+    pub fn apply(&mut self, flag: &str) -> anyhow::Result<bool> {
+        let (level, name) = match flag.split_once('=') {
+            Some(("-A", name)) => (Level::Allow, name),
+            Some(("-W", name)) => (Level::Warn, name),
+            Some(("-D", name)) => (Level::Deny, name),
+            _ => return Ok(false),
+        };
+        match Lint::ALL.into_iter().find(|l| l.name() == name) {
+            Some(lint) => self.0[lint as usize] = level,
+            None if name == "all" => self.0 = [level; Lint::ALL.len()],
+            None => {
+                let names = Lint::ALL.map(Lint::name).join(", ");
+                anyhow::bail!("unknown lint {name}, expected all or one of {names}")
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Warning {
+    pub lint: Lint,
+    pub at: TokenIdx,
+    pub message: String,
+}
+
+/// Everything every lint finds in `program` and its test blocks, in source order.
+pub fn lint(program: &Program) -> Vec<Warning> {
+    let mut warnings = vec![];
+    let tests = program.tests.iter().map(|t| &t.program.ops);
+    for ops in std::iter::once(&program.ops).chain(tests) {
+        redundant_pairs(ops, &mut warnings);
+        values(ops, &mut warnings);
+        unreachable(ops, &mut warnings);
+    }
+    warnings.sort_by_key(|w| (w.at.row, w.at.col));
+    warnings
+}
+
+/// Writes the warnings in `program` that `levels` does not allow to `out`, failing if any of
+/// them are denied.
+pub fn report(
+    file_name: &str,
+    program: &Program,
+    levels: &Levels,
+    out: &mut impl Write,
+) -> anyhow::Result<()> {
+    let mut denied = 0;
+    for Warning { lint, at, message } in lint(program) {
+        let kind = match levels.get(lint) {
+            Level::Allow => continue,
+            Level::Warn => "warning",
+            Level::Deny => {
+                denied += 1;
+                "error"
+            }
+        };
+        let at = at.as_stamp(file_name);
+        writeln!(out, "{kind}: {at}: {message} ({})", lint.name())?;
+    }
+    if denied > 0 {
+        anyhow::bail!("{file_name}: {denied} denied lint(s)");
+    }
+    Ok(())
+}
+
+fn redundant_pairs(ops: &[Span<Op>], warnings: &mut Vec<Warning>) {
+    for pair in ops.windows(2) {
+        let message = match (pair[0].token, pair[1].token) {
+            (Op::Intr1_2(Op1_2::Duplicate), Op::Intr1_0(Op1_0::Drop)) => "`dup drop` does nothing",
+            (Op::Intr2_2(Op2_2::Swap), Op::Intr2_2(Op2_2::Swap)) => "`swap swap` does nothing",
+            _ => continue,
+        };
+        warnings.push(Warning {
+            lint: Lint::RedundantPair,
+            at: pair[0].idx,
+            message: message.to_string(),
+        });
+    }
+}
+
+/// Follows constants, the values that are the same on every run, through the stack, for
+/// `constant-if` and `overflow`. Both sides of every `if` are assumed to run, and nothing is
+/// known about elements missing from a stack that underflowed.
+fn values(ops: &[Span<Op>], warnings: &mut Vec<Warning>) {
+    let mut stack: Vec<Option<isize>> = vec![];
+    // the stack each open `if` started its block with
+    let mut blocks = vec![];
+    for &Span { idx: at, token: op } in ops {
+        let pop = |stack: &mut Vec<Option<isize>>| stack.pop().flatten();
+        let mut warn = |lint, message| warnings.push(Warning { lint, at, message });
+        match op {
+            Op::Push(n) => stack.push(Some(n)),
+            Op::Intr1_0(_) | Op::Assert(_) => {
+                pop(&mut stack);
+            }
+            Op::Intr1_2(Op1_2::Duplicate) => {
+                let t = pop(&mut stack);
+                stack.extend([t, t]);
+            }
+            Op::Intr2_2(Op2_2::Swap) => {
+                let (t, t1) = (pop(&mut stack), pop(&mut stack));
+                stack.extend([t, t1]);
+            }
+            Op::Intr2_1(op_id) => {
+                let (t, t1) = (pop(&mut stack), pop(&mut stack));
+                let constant = t.zip(t1).and_then(|(t, t1)| {
+                    let (res, overflowed) = arithmetic(op_id, t, t1)?;
+                    if overflowed {
+                        let msg = format!("{op} of {t1} and {t} overflows, wrapping to {res}");
+                        warn(Lint::Overflow, msg);
+                    }
+                    Some(res)
+                });
+                stack.push(constant);
+            }
+            Op::Intr2_2(Op2_2::DivMod) => {
+                let (t, t1) = (pop(&mut stack), pop(&mut stack));
+                let (mut quot, mut rem) = (None, None);
+                if let (Some(t), Some(t1 @ (..=-1 | 1..))) = (t, t1) {
+                    if t.checked_div(t1).is_none() {
+                        let res = t.wrapping_div(t1);
+                        let msg = format!("{op} of {t1} and {t} overflows, wrapping to {res}");
+                        warn(Lint::Overflow, msg);
+                    }
+                    quot = Some(t.wrapping_div(t1));
+                    rem = Some(t.wrapping_rem(t1));
+                }
+                stack.extend([rem, quot]);
+            }
+            Op::If(_) => {
+                let cond = pop(&mut stack);
+                let message = match cond {
+                    Some(1) => "`if` condition is always true, so its block always runs".into(),
+                    Some(0) => "`if` condition is always false, so its block never runs".into(),
+                    Some(n) => format!("`if` condition is always {n}, which is not a bool"),
+                    None => String::new(),
+                };
+                if !message.is_empty() {
+                    warn(Lint::ConstantIf, message);
+                }
+                blocks.push(stack.clone());
+            }
+            Op::End => {
+                let Some(skipped) = blocks.pop() else {
+                    continue;
+                };
+                // a block that changes the depth fails at its `end`
+                stack = match skipped.len() == stack.len() {
+                    true => skipped
+                        .iter()
+                        .zip(&stack)
+                        .map(|(&a, &b)| a.filter(|_| a == b))
+                        .collect(),
+                    false => skipped,
+                };
+            }
+            Op::Cast(_) | Op::AssertStack(_) => {}
+        }
+    }
+}
+
+/// The wrapped result of `op`, as `into_op` computes it, and whether it overflowed, or `None` if
+/// it divides by zero.
+fn arithmetic(op: Op2_1, t: isize, t1: isize) -> Option<(isize, bool)> {
+    let res = match op {
+        Op2_1::Add => t.overflowing_add(t1),
+        Op2_1::Sub => t.overflowing_sub(t1),
+        Op2_1::Mul => t.overflowing_mul(t1),
+        Op2_1::Div if t1 == 0 => return None,
+        Op2_1::Div => t.overflowing_div(t1),
+        Op2_1::Mod if t1 == 0 => return None,
+        Op2_1::Mod => t.overflowing_rem(t1),
+        Op2_1::Equ => ((t == t1) as isize, false),
+        Op2_1::Less => ((t < t1) as isize, false),
+        Op2_1::Greater => ((t > t1) as isize, false),
+        Op2_1::LessEqu => ((t <= t1) as isize, false),
+        Op2_1::GreaterEqu => ((t >= t1) as isize, false),
+    };
+    Some(res)
+}
+
+fn unreachable(ops: &[Span<Op>], warnings: &mut Vec<Warning>) {
+    let depths = check(ops).depths;
+    for i in 1..ops.len() {
+        if depths[i].is_none() && depths[i - 1].is_some() {
+            warnings.push(Warning {
+                lint: Lint::Unreachable,
+                at: ops[i].idx,
+                message: "never reached, an earlier op always fails".to_string(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse_str;
+
+    /// What each lint finds in `src`, as `(lint, "row:col", message)`.
+    fn found(src: &str) -> Vec<(Lint, String, String)> {
+        lint(&parse_str(src).unwrap())
+            .into_iter()
+            .map(|w| (w.lint, w.at.to_string(), w.message))
+            .collect()
+    }
+
+    fn levels(flags: &[&str]) -> Levels {
+        let mut levels = Levels::default();
+        for flag in flags {
+            assert!(levels.apply(flag).unwrap(), "{flag} is a lint flag");
+        }
+        levels
+    }
+
+    fn one(lint: Lint, at: &str, message: &str) -> Vec<(Lint, String, String)> {
+        vec![(lint, at.to_string(), message.to_string())]
+    }
+
+    #[test]
+    fn finds_redundant_pairs() {
+        let message = "`dup drop` does nothing";
+        assert_eq!(
+            found("1 dup drop ."),
+            one(Lint::RedundantPair, "1:3", message)
+        );
+        let message = "`swap swap` does nothing";
+        assert_eq!(
+            found("1 2 swap swap . ."),
+            one(Lint::RedundantPair, "1:5", message)
+        );
+    }
+
+    #[test]
+    fn finds_constant_ifs() {
+        let message = "`if` condition is always true, so its block always runs";
+        assert_eq!(found("1 if 2 . end"), one(Lint::ConstantIf, "1:3", message));
+        assert_eq!(
+            found("0 if end 2 if end"),
+            [
+                (
+                    Lint::ConstantIf,
+                    "1:3".to_string(),
+                    "`if` condition is always false, so its block never runs".to_string()
+                ),
+                (
+                    Lint::ConstantIf,
+                    "1:12".to_string(),
+                    "`if` condition is always 2, which is not a bool".to_string()
+                ),
+            ]
+        );
+        // nothing is known about a division by zero, which fails when it runs
+        assert!(found("0 1 / if end").is_empty());
+    }
+
+    #[test]
+    fn follows_constants_through_casts() {
+        let message = "`if` condition is always true, so its block always runs";
+        assert_eq!(
+            found("1 bool if end"),
+            one(Lint::ConstantIf, "1:8", message)
+        );
+    }
+
+    #[test]
+    fn finds_wrapping_arithmetic() {
+        let message =
+            "ADD of 9223372036854775807 and 1 overflows, wrapping to -9223372036854775808";
+        assert_eq!(
+            found("9223372036854775807 1 + ."),
+            one(Lint::Overflow, "1:23", message)
+        );
+    }
+
+    #[test]
+    fn finds_code_after_a_certain_underflow() {
+        let message = "never reached, an earlier op always fails";
+        assert_eq!(found("drop 1 ."), one(Lint::Unreachable, "1:6", message));
+    }
+
+    #[test]
+    fn applies_level_flags_in_order() {
+        let l = levels(&["-A=overflow", "-D=unreachable", "-W=redundant-pair"]);
+        assert_eq!(l.get(Lint::Overflow), Level::Allow);
+        assert_eq!(l.get(Lint::Unreachable), Level::Deny);
+        assert_eq!(l.get(Lint::RedundantPair), Level::Warn);
+
+        let l = levels(&["-D=all", "-A=constant-if"]);
+        assert_eq!(l.get(Lint::ConstantIf), Level::Allow);
+        assert_eq!(l.get(Lint::Overflow), Level::Deny);
+        let l = levels(&["-A=constant-if", "-D=all"]);
+        assert_eq!(l.get(Lint::ConstantIf), Level::Deny);
+
+        let mut flags = Levels::default();
+        assert!(!flags.apply("-O").unwrap());
+        assert!(!flags.apply("--max-steps=3").unwrap());
+        let e = flags.apply("-D=shadowing").unwrap_err().to_string();
+        assert!(e.contains("unknown lint shadowing"), "{e}");
+    }
+
+    #[test]
+    fn runs_allow_constant_ifs() {
+        let levels = Levels::for_runs();
+        for lint in Lint::ALL {
+            let expected = match lint {
+                Lint::ConstantIf => Level::Allow,
+                _ => Level::Warn,
+            };
+            assert_eq!(levels.get(lint), expected, "{}", lint.name());
+        }
+    }
+
+    #[test]
+    fn reports_warnings_and_fails_only_on_denied_ones() {
+        let program = parse_str("1 dup drop .\n0 if end").unwrap();
+        let report = |flags: &[&str]| {
+            let mut out = vec![];
+            let res = report("t.wa", &program, &levels(flags), &mut out);
+            (
+                String::from_utf8(out).unwrap(),
+                res.map_err(|e| e.to_string()),
+            )
+        };
+        assert_eq!(
+            report(&[]),
+            (
+                "warning: t.wa:1:3: `dup drop` does nothing (redundant-pair)\n\
+                 warning: t.wa:2:3: `if` condition is always false, so its block never runs \
+                 (constant-if)\n"
+                    .to_string(),
+                Ok(())
+            )
+        );
+        assert_eq!(
+            report(&["-A=constant-if", "-D=overflow"]),
+            (
+                "warning: t.wa:1:3: `dup drop` does nothing (redundant-pair)\n".to_string(),
+                Ok(())
+            )
+        );
+        assert_eq!(
+            report(&["-A=all", "-D=redundant-pair"]),
+            (
+                "error: t.wa:1:3: `dup drop` does nothing (redundant-pair)\n".to_string(),
+                Err("t.wa: 1 denied lint(s)".to_string())
+            )
+        );
+    }
+}
@@ -0,0 +1,696 @@
+//! `wa lsp`: a language server speaking the Language Server Protocol over stdin and stdout.
+//!
+//! Documents are synced whole and re-analysed from scratch on every change, since parsing and
+//! checking a file is cheap. Diagnostics come from the parser and the static checker, hovering a
+//! word shows its stack effect and the stack depth it runs at, the document symbols are its test
+//! blocks, and semantic tokens classify literals, intrinsics, control words and strings.
+
+mod json;
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, Write},
+};
+
+use json::{object, Json};
+
+use crate::{
+    check::{check, Fault},
+    effect,
+    ops::Op,
+    parse::{self, parse_ops, Program},
+    tokenise::{Span, TokenIdx, Tokeniser},
+    types,
+};
+
+/// Indices into this are the token types of semantic tokens.
+const TOKEN_TYPES: [&str; 4] = ["number", "function", "keyword", "string"];
+const NUMBER: usize = 0;
+const FUNCTION: usize = 1;
+const KEYWORD: usize = 2;
+const STRING: usize = 3;
+
+const PARSE_ERROR: i32 = -32700;
+const METHOD_NOT_FOUND: i32 = -32601;
+const INTERNAL_ERROR: i32 = -32603;
+
+/// Serves requests from `input` until the client sends `exit` or closes it.
+pub fn run(mut input: impl BufRead, output: impl Write) -> anyhow::Result<()> {
+    let mut server = Server {
+        output,
+        docs: HashMap::new(),
+    };
+    while let Some(msg) = read_message(&mut input)? {
+        // a message that cannot be read has no id to answer to, but the next one may be fine
+        let msg = match msg {
+            Ok(msg) => msg,
+            Err(e) => {
+                server.send(object([
+                    ("jsonrpc", "2.0".into()),
+                    ("id", Json::Null),
+                    ("error", error(PARSE_ERROR, e.to_string())),
+                ]))?;
+                continue;
+            }
+        };
+        let method = msg.get("method").and_then(Json::as_str).unwrap_or_default();
+        if method == "exit" {
+            break;
+        }
+        let params = msg.get("params").unwrap_or(&Json::Null);
+        let reply = server.handle(method, params);
+        // notifications have no id and get no reply, even when they fail
+        let Some(id) = msg.get("id") else {
+            continue;
+        };
+        let reply = match reply {
+            Ok(Some(result)) => ("result", result),
+            Ok(None) => (
+                "error",
+                error(METHOD_NOT_FOUND, format!("unknown method {method}")),
+            ),
+            Err(e) => ("error", error(INTERNAL_ERROR, e.to_string())),
+        };
+        server.send(object([
+            ("jsonrpc", "2.0".into()),
+            ("id", id.clone()),
+            reply,
+        ]))?;
+    }
+    Ok(())
+}
+
+fn error(code: i32, message: String) -> Json {
+    object([
+        ("code", Json::Number(code.into())),
+        ("message", message.into()),
+    ])
+}
+
+/// The next message, or why it is not one. Only a failure to read `input` is an error, and
+/// `None` means it has ended.
+fn read_message(input: &mut impl BufRead) -> anyhow::Result<Option<anyhow::Result<Json>>> {
+    let mut len = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                len = Some(value.trim().parse::<usize>());
+            }
+        }
+    }
+    let len = match len {
+        Some(Ok(len)) => len,
+        Some(Err(e)) => return Ok(Some(Err(anyhow::anyhow!("bad Content-Length: {e}")))),
+        None => {
+            return Ok(Some(Err(anyhow::anyhow!(
+                "message without a Content-Length header"
+            ))))
+        }
+    };
+    let mut body = vec![0; len];
+    input.read_exact(&mut body)?;
+    Ok(Some(
+        std::str::from_utf8(&body)
+            .map_err(anyhow::Error::from)
+            .and_then(Json::parse),
+    ))
+}
+
+struct Server<W> {
+    output: W,
+    /// The text of every open document, by URI.
+    docs: HashMap<String, String>,
+}
+
+impl<W: Write> Server<W> {
+    fn send(&mut self, msg: Json) -> anyhow::Result<()> {
+        let body = msg.to_string();
+        write!(self.output, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+        self.output.flush()?;
+        Ok(())
+    }
+
+    /// The result of a request, or `None` if the method is not one the server knows.
+    fn handle(&mut self, method: &str, params: &Json) -> anyhow::Result<Option<Json>> {
+        let uri = params
+            .get("textDocument")
+            .and_then(|d| d.get("uri"))
+            .and_then(Json::as_str)
+            .unwrap_or_default()
+            .to_string();
+        let result = match method {
+            "initialize" => object([
+                ("capabilities", capabilities()),
+                (
+                    "serverInfo",
+                    object([
+                        ("name", "wa".into()),
+                        ("version", env!("CARGO_PKG_VERSION").into()),
+                    ]),
+                ),
+            ]),
+            "initialized" | "shutdown" | "$/cancelRequest" | "$/setTrace" => Json::Null,
+            "textDocument/didOpen" => {
+                let text = params
+                    .get("textDocument")
+                    .and_then(|d| d.get("text"))
+                    .and_then(Json::as_str)
+                    .unwrap_or_default();
+                self.docs.insert(uri.clone(), text.to_string());
+                self.publish(&uri)?;
+                Json::Null
+            }
+            "textDocument/didChange" => {
+                // synced whole, so the last change holds the entire document
+                let change = params
+                    .get("contentChanges")
+                    .and_then(Json::as_array)
+                    .and_then(|changes| changes.last())
+                    .and_then(|c| c.get("text"))
+                    .and_then(Json::as_str);
+                if let Some(text) = change {
+                    self.docs.insert(uri.clone(), text.to_string());
+                }
+                self.publish(&uri)?;
+                Json::Null
+            }
+            "textDocument/didClose" => {
+                self.docs.remove(&uri);
+                self.publish(&uri)?;
+                Json::Null
+            }
+            "textDocument/hover" => {
+                let position = params.get("position").unwrap_or(&Json::Null);
+                let row = position.get("line").and_then(Json::as_usize).unwrap_or(0);
+                let character = position.get("character").and_then(Json::as_usize);
+                let doc = self.document(&uri)?;
+                let at = TokenIdx {
+                    row,
+                    col: doc.byte_col(row, character.unwrap_or(0)),
+                };
+                doc.hover(at).unwrap_or(Json::Null)
+            }
+            "textDocument/documentSymbol" => self.document(&uri)?.symbols().into(),
+            "textDocument/semanticTokens/full" => {
+                object([("data", self.document(&uri)?.semantic_tokens().into())])
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(result))
+    }
+
+    fn document<'a>(&'a self, uri: &'a str) -> anyhow::Result<Document<'a>> {
+        let text = self
+            .docs
+            .get(uri)
+            .ok_or_else(|| anyhow::anyhow!("{uri} is not open"))?;
+        Ok(Document::new(uri, text))
+    }
+
+    fn publish(&mut self, uri: &str) -> anyhow::Result<()> {
+        let diagnostics = match self.docs.get(uri) {
+            Some(text) => Document::new(uri, text).diagnostics(),
+            None => vec![],
+        };
+        self.send(object([
+            ("jsonrpc", "2.0".into()),
+            ("method", "textDocument/publishDiagnostics".into()),
+            (
+                "params",
+                object([("uri", uri.into()), ("diagnostics", diagnostics.into())]),
+            ),
+        ]))
+    }
+}
+
+fn capabilities() -> Json {
+    let legend = object([
+        (
+            "tokenTypes",
+            TOKEN_TYPES
+                .iter()
+                .map(|&t| t.into())
+                .collect::<Vec<_>>()
+                .into(),
+        ),
+        ("tokenModifiers", Json::Array(vec![])),
+    ]);
+    object([
+        ("textDocumentSync", 1.into()), // full
+        ("hoverProvider", true.into()),
+        ("documentSymbolProvider", true.into()),
+        (
+            "semanticTokensProvider",
+            object([("legend", legend), ("full", true.into())]),
+        ),
+    ])
+}
+
+/// A document and what the parser made of it.
+struct Document<'a> {
+    file_name: &'a str,
+    lines: Vec<&'a str>,
+    tokens: Vec<Span<&'a str>>,
+    program: anyhow::Result<Program>,
+}
+
+impl<'a> Document<'a> {
+    fn new(file_name: &'a str, text: &'a str) -> Self {
+        let tokens = Tokeniser::new(text.as_bytes()).collect::<Vec<_>>();
+        let program = parse_ops(tokens.clone(), file_name);
+        Self {
+            file_name,
+            lines: text.lines().collect(),
+            tokens,
+            program,
+        }
+    }
+
+    /// Columns count bytes, but clients count UTF-16 code units.
+    fn utf16_col(&self, at: TokenIdx) -> usize {
+        let line = self.lines.get(at.row).copied().unwrap_or_default();
+        let before = line.get(..at.col).unwrap_or(line);
+        before.encode_utf16().count() + at.col.saturating_sub(line.len())
+    }
+
+    /// The byte column `character` UTF-16 code units into `row`.
+    fn byte_col(&self, row: usize, character: usize) -> usize {
+        let line = self.lines.get(row).copied().unwrap_or_default();
+        let mut units = 0;
+        for (i, ch) in line.char_indices() {
+            if units >= character {
+                return i;
+            }
+            units += ch.len_utf16();
+        }
+        line.len() + character.saturating_sub(units)
+    }
+
+    fn position(&self, at: TokenIdx) -> Json {
+        object([
+            ("line", at.row.into()),
+            ("character", self.utf16_col(at).into()),
+        ])
+    }
+
+    /// From `at` to `len` bytes after it.
+    fn range(&self, at: TokenIdx, len: usize) -> Json {
+        let end = TokenIdx {
+            row: at.row,
+            col: at.col + len,
+        };
+        object([("start", self.position(at)), ("end", self.position(end))])
+    }
+
+    /// The range of the token starting at `at`.
+    fn token_range(&self, at: TokenIdx) -> Json {
+        let len = self
+            .tokens
+            .iter()
+            .find(|t| t.idx.row == at.row && t.idx.col == at.col)
+            .map_or(1, |t| t.token.len());
+        self.range(at, len)
+    }
+
+    fn diagnostics(&self) -> Vec<Json> {
+        let diagnostic = |at, message: String| {
+            object([
+                ("range", self.token_range(at)),
+                ("severity", 1.into()), // error
+                ("source", "wa".into()),
+                ("message", message.into()),
+            ])
+        };
+        let program = match &self.program {
+            Ok(program) => program,
+            Err(e) => {
+                let msg = e.to_string();
+                let (at, msg) = locate(&msg, self.file_name);
+                return vec![diagnostic(at, msg.to_string())];
+            }
+        };
+
+        let mut out = types::check(program)
+            .into_iter()
+            .map(|e| diagnostic(e.at, e.message))
+            .collect::<Vec<_>>();
+        let tests = program.tests.iter().map(|t| (&t.program.ops, false));
+        for (ops, is_main) in std::iter::once((&program.ops, true)).chain(tests) {
+            let checked = check(ops);
+            for (op, fault) in ops.iter().zip(&checked.faults) {
+                if let Some(fault) = fault {
+                    out.push(diagnostic(op.idx, fault.to_string()));
+                }
+            }
+            // test blocks may leave data behind
+            if let (true, Some(remaining @ 1..), Some(last)) =
+                (is_main, checked.exit_depth, ops.last())
+            {
+                out.push(diagnostic(
+                    last.idx,
+                    Fault::Leftover { remaining }.to_string(),
+                ));
+            }
+        }
+        out
+    }
+
+    fn hover(&self, at: TokenIdx) -> Option<Json> {
+        let tok = self.tokens.iter().find(|t| {
+            t.idx.row == at.row && t.idx.col <= at.col && at.col < t.idx.col + t.token.len()
+        })?;
+        let mut text = match self.op_at(tok.idx) {
+            Some((op, depth)) => {
+                let depth = match depth {
+                    Some(d) => format!("stack depth here: {d}"),
+                    None => "never reached, every path here fails first".to_string(),
+                };
+                format!("`{}` `{}`\n\n{depth}", tok.token, effect(op))
+            }
+            // nothing to check against, but a lone word still has an effect
+            None => {
+                let op = match parse::literal(tok.token, "") {
+                    Some(n) => Op::Push(n.ok()?),
+                    None => parse::intrinsic(tok.token)?,
+                };
+                format!("`{}` `{}`", tok.token, effect(op))
+            }
+        };
+        if let Some(fault) = self.fault_at(tok.idx) {
+            text.push_str(&format!("\n\nfails here: {fault}"));
+        }
+        Some(object([
+            (
+                "contents",
+                object([("kind", "markdown".into()), ("value", text.into())]),
+            ),
+            ("range", self.range(tok.idx, tok.token.len())),
+        ]))
+    }
+
+    /// Every op list in the program, the main one first and then each test block's.
+    fn op_lists(&self) -> Vec<&[Span<Op>]> {
+        let Ok(program) = &self.program else {
+            return vec![];
+        };
+        let tests = program.tests.iter().map(|t| t.program.ops.as_slice());
+        std::iter::once(program.ops.as_slice())
+            .chain(tests)
+            .collect()
+    }
+
+    /// The op whose word starts at `at` and the stack depth it runs at.
+    fn op_at(&self, at: TokenIdx) -> Option<(Op, Option<usize>)> {
+        self.op_lists().into_iter().find_map(|ops| {
+            let i = ops
+                .iter()
+                .position(|op| op.idx.row == at.row && op.idx.col == at.col)?;
+            Some((ops[i].token, check(ops).depths[i]))
+        })
+    }
+
+    fn fault_at(&self, at: TokenIdx) -> Option<Fault> {
+        self.op_lists().into_iter().find_map(|ops| {
+            let i = ops
+                .iter()
+                .position(|op| op.idx.row == at.row && op.idx.col == at.col)?;
+            check(ops).faults[i]
+        })
+    }
+
+    /// Test blocks, spanning from `test` to its `end`.
+    fn symbols(&self) -> Vec<Json> {
+        let mut symbols = vec![];
+        let mut i = 0;
+        while i < self.tokens.len() {
+            let (test, name) = match (self.tokens[i], self.tokens.get(i + 1)) {
+                (test, Some(name)) if test.token == "test" && name.token.starts_with('"') => {
+                    (test, name)
+                }
+                _ => {
+                    i += 1;
+                    continue;
+                }
+            };
+            let mut depth = 0;
+            let mut end = self.tokens.len() - 1;
+            for (j, t) in self.tokens.iter().enumerate().skip(i + 2) {
+                match t.token {
+                    "if" | "test" => depth += 1,
+                    "end" if depth == 0 => {
+                        end = j;
+                        break;
+                    }
+                    "end" => depth -= 1,
+                    _ => {}
+                }
+            }
+            let last = self.tokens[end];
+            let full = object([
+                ("start", self.position(test.idx)),
+                (
+                    "end",
+                    self.position(TokenIdx {
+                        row: last.idx.row,
+                        col: last.idx.col + last.token.len(),
+                    }),
+                ),
+            ]);
+            symbols.push(object([
+                ("name", name.token.trim_matches('"').into()),
+                ("kind", 12.into()), // function
+                ("range", full),
+                ("selectionRange", self.range(name.idx, name.token.len())),
+            ]));
+            i = end + 1;
+        }
+        symbols
+    }
+
+    /// Five numbers per classified token: line and start relative to the previous one, length,
+    /// type and modifiers.
+    fn semantic_tokens(&self) -> Vec<Json> {
+        let mut data = vec![];
+        let (mut row, mut col) = (0, 0);
+        for Span { idx, token } in &self.tokens {
+            let Some(kind) = classify(token) else {
+                continue;
+            };
+            let start = self.utf16_col(*idx);
+            let delta_col = match idx.row == row {
+                true => start - col,
+                false => start,
+            };
+            for n in [
+                idx.row - row,
+                delta_col,
+                token.encode_utf16().count(),
+                kind,
+                0,
+            ] {
+                data.push(n.into());
+            }
+            (row, col) = (idx.row, start);
+        }
+        data
+    }
+}
+
+fn classify(token: &str) -> Option<usize> {
+    // the numbers of `assert-stack [1 2]` carry its brackets
+    let number = token.trim_start_matches('[').trim_end_matches(']');
+    match token {
+        t if t.starts_with('"') => Some(STRING),
+        _ if parse::literal(number, "").is_some() => Some(NUMBER),
+        t if parse::intrinsic(t).is_some() => Some(FUNCTION),
+        "if" | "end" | "test" | "assert" | "assert-stack" => Some(KEYWORD),
+        _ => None,
+    }
+}
+
+/// The stack effect of `op` on its own, as `check --effects` writes it.
+fn effect(op: Op) -> String {
+    let op = Span {
+        idx: TokenIdx::default(),
+        token: op,
+    };
+    effect::infer(&[op], &mut vec![]).to_string()
+}
+
+/// Splits the `file:row:col: ` stamp off a diagnostic, if it has one.
+fn locate<'m>(msg: &'m str, file_name: &str) -> (TokenIdx, &'m str) {
+    let stamped = || {
+        let rest = msg.strip_prefix(file_name)?.strip_prefix(':')?;
+        let (row, rest) = rest.split_once(':')?;
+        let (col, rest) = rest.split_once(": ")?;
+        let (row, col) = (row.parse::<usize>().ok()?, col.parse::<usize>().ok()?);
+        Some((
+            TokenIdx {
+                row: row.checked_sub(1)?,
+                col: col.checked_sub(1)?,
+            },
+            rest,
+        ))
+    };
+    stamped().unwrap_or((TokenIdx::default(), msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(body: &[u8]) -> Vec<u8> {
+        let mut msg = format!("Content-Length: {}\r\n\r\n", body.len()).into_bytes();
+        msg.extend_from_slice(body);
+        msg
+    }
+
+    /// Every message the server wrote, in order.
+    fn replies(mut output: &[u8]) -> Vec<Json> {
+        let mut out = vec![];
+        while let Some(msg) = read_message(&mut output).unwrap() {
+            out.push(msg.unwrap());
+        }
+        out
+    }
+
+    #[test]
+    fn serves_a_scripted_session() {
+        let uri = "file:///t.wa";
+        let text = "1 dup + .\n2\n";
+        let document = object([("uri", uri.into())]);
+        let requests = [
+            object([
+                ("jsonrpc", "2.0".into()),
+                ("id", 1.into()),
+                ("method", "initialize".into()),
+                ("params", object([])),
+            ]),
+            object([
+                ("jsonrpc", "2.0".into()),
+                ("method", "textDocument/didOpen".into()),
+                (
+                    "params",
+                    object([(
+                        "textDocument",
+                        object([("uri", uri.into()), ("text", text.into())]),
+                    )]),
+                ),
+            ]),
+            object([
+                ("jsonrpc", "2.0".into()),
+                ("id", 2.into()),
+                ("method", "textDocument/hover".into()),
+                (
+                    "params",
+                    object([
+                        ("textDocument", document.clone()),
+                        (
+                            "position",
+                            object([("line", 0.into()), ("character", 3.into())]),
+                        ),
+                    ]),
+                ),
+            ]),
+            object([
+                ("jsonrpc", "2.0".into()),
+                ("id", 3.into()),
+                ("method", "textDocument/semanticTokens/full".into()),
+                ("params", object([("textDocument", document)])),
+            ]),
+        ];
+        let mut input = requests
+            .iter()
+            .flat_map(|r| frame(r.to_string().as_bytes()))
+            .collect::<Vec<_>>();
+        input.extend(frame(b"{oops"));
+        input.extend(frame(b"\"\xff\""));
+        input.extend(b"Content-Length: many\r\n\r\n");
+        input.extend(b"Content-Type: text/plain\r\n\r\n");
+        input.extend(frame(br#"{"jsonrpc":"2.0","id":4,"method":"shutdown"}"#));
+        input.extend(frame(br#"{"jsonrpc":"2.0","method":"exit"}"#));
+        // never read
+        input.extend(frame(br#"{"jsonrpc":"2.0","id":5,"method":"shutdown"}"#));
+
+        let mut output = vec![];
+        run(input.as_slice(), &mut output).unwrap();
+        let replies = replies(&output);
+        assert_eq!(replies.len(), 9, "{replies:?}");
+
+        assert_eq!(replies[0].get("id"), Some(&Json::from(1)));
+        assert!(replies[0]
+            .get("result")
+            .and_then(|r| r.get("capabilities"))
+            .and_then(|c| c.get("hoverProvider"))
+            .is_some());
+
+        let diagnostics = replies[1]
+            .get("params")
+            .and_then(|p| p.get("diagnostics"))
+            .and_then(Json::as_array)
+            .unwrap();
+        assert_eq!(diagnostics.len(), 1, "{diagnostics:?}");
+        assert_eq!(
+            diagnostics[0].get("message").and_then(Json::as_str),
+            Some("Unhandled data on the stack. 1 element(s) remaining after last operation")
+        );
+        assert_eq!(
+            diagnostics[0].get("range").and_then(|r| r.get("start")),
+            Some(&object([("line", 1.into()), ("character", 0.into())]))
+        );
+
+        assert_eq!(replies[2].get("id"), Some(&Json::from(2)));
+        let hover = replies[2].get("result").unwrap();
+        assert_eq!(
+            hover.get("contents").and_then(|c| c.get("value")),
+            Some(&Json::from("`dup` `( a -- a a )`\n\nstack depth here: 1"))
+        );
+
+        assert_eq!(replies[3].get("id"), Some(&Json::from(3)));
+        let data = [
+            [0, 0, 1, NUMBER, 0],
+            [0, 2, 3, FUNCTION, 0],
+            [0, 4, 1, FUNCTION, 0],
+            [0, 2, 1, FUNCTION, 0],
+            [1, 0, 1, NUMBER, 0],
+        ];
+        assert_eq!(
+            replies[3].get("result").and_then(|r| r.get("data")),
+            Some(&Json::from(
+                data.iter().flatten().map(|&n| n.into()).collect::<Vec<_>>()
+            ))
+        );
+
+        for reply in &replies[4..8] {
+            assert_eq!(reply.get("id"), Some(&Json::Null), "{reply}");
+            assert_eq!(
+                reply.get("error").and_then(|e| e.get("code")),
+                Some(&Json::Number(PARSE_ERROR.into())),
+                "{reply}"
+            );
+        }
+        assert_eq!(replies[8].get("id"), Some(&Json::from(4)));
+        assert_eq!(replies[8].get("result"), Some(&Json::Null));
+    }
+
+    #[test]
+    fn hovers_with_the_effect_check_infers() {
+        let doc = Document::new("t.wa", "1 2 + 3 < .");
+        let hover = |col| {
+            let hover = doc.hover(TokenIdx { row: 0, col }).unwrap();
+            let value = hover.get("contents").and_then(|c| c.get("value"));
+            value.and_then(Json::as_str).unwrap().to_string()
+        };
+        assert_eq!(hover(4), "`+` `( int int -- int )`\n\nstack depth here: 2");
+        assert_eq!(hover(8), "`<` `( a b -- bool )`\n\nstack depth here: 2");
+        assert_eq!(hover(0), "`1` `( -- int )`\n\nstack depth here: 0");
+    }
+}
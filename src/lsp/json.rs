@@ -0,0 +1,351 @@
+//! Just enough JSON for the language server: a value type, a parser and a serialiser.
+
+use std::fmt::Write as _;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    /// Keys in the order they were written.
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut p = Parser {
+            src: text.as_bytes(),
+            pos: 0,
+        };
+        let value = p.value()?;
+        p.whitespace();
+        if p.pos != p.src.len() {
+            anyhow::bail!("trailing characters at offset {}", p.pos);
+        }
+        Ok(value)
+    }
+
+    /// The member `key` of an object, or `None` for anything else.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_usize(&self) -> Option<usize> {
+        match *self {
+            Json::Number(n) if n >= 0.0 && n.fract() == 0.0 => Some(n as usize),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+/// An object with `members`, in order.
+pub fn object<const N: usize>(members: [(&str, Json); N]) -> Json {
+    Json::Object(members.map(|(k, v)| (k.to_string(), v)).into())
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Self {
+        Json::String(s.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Self {
+        Json::String(s)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(n: usize) -> Self {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Self {
+        Json::Bool(b)
+    }
+}
+
+impl From<Vec<Json>> for Json {
+    fn from(items: Vec<Json>) -> Self {
+        Json::Array(items)
+    }
+}
+
+impl std::fmt::Display for Json {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{b}"),
+            Json::Number(n) if n.fract() == 0.0 && n.abs() < 2f64.powi(53) => {
+                write!(f, "{}", *n as i64)
+            }
+            Json::Number(n) => write!(f, "{n}"),
+            Json::String(s) => string(f, s),
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(members) => {
+                write!(f, "{{")?;
+                for (i, (k, v)) in members.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    string(f, k)?;
+                    write!(f, ":{v}")?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn string(f: &mut std::fmt::Formatter<'_>, s: &str) -> std::fmt::Result {
+    f.write_char('"')?;
+    for ch in s.chars() {
+        match ch {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            ch if (ch as u32) < 0x20 => write!(f, "\\u{:04x}", ch as u32)?,
+            ch => f.write_char(ch)?,
+        }
+    }
+    f.write_char('"')
+}
+
+struct Parser<'a> {
+    src: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn whitespace(&mut self) {
+        while self
+            .src
+            .get(self.pos)
+            .is_some_and(|b| b.is_ascii_whitespace())
+        {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, lit: &str) -> anyhow::Result<()> {
+        match self.src[self.pos..].starts_with(lit.as_bytes()) {
+            true => {
+                self.pos += lit.len();
+                Ok(())
+            }
+            false => anyhow::bail!("expected `{lit}` at offset {}", self.pos),
+        }
+    }
+
+    fn value(&mut self) -> anyhow::Result<Json> {
+        self.whitespace();
+        let value = match self.src.get(self.pos) {
+            Some(b'n') => self.expect("null").map(|_| Json::Null)?,
+            Some(b't') => self.expect("true").map(|_| Json::Bool(true))?,
+            Some(b'f') => self.expect("false").map(|_| Json::Bool(false))?,
+            Some(b'"') => Json::String(self.string()?),
+            Some(b'[') => Json::Array(self.array()?),
+            Some(b'{') => Json::Object(self.object()?),
+            Some(b'-' | b'0'..=b'9') => {
+                let start = self.pos;
+                let numeric = |b: &u8| matches!(b, b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9');
+                while self.src.get(self.pos).is_some_and(numeric) {
+                    self.pos += 1;
+                }
+                let text = std::str::from_utf8(&self.src[start..self.pos])?;
+                Json::Number(text.parse()?)
+            }
+            _ => anyhow::bail!("expected a value at offset {}", self.pos),
+        };
+        Ok(value)
+    }
+
+    fn array(&mut self) -> anyhow::Result<Vec<Json>> {
+        self.expect("[")?;
+        let mut items = vec![];
+        self.whitespace();
+        if self.src.get(self.pos) == Some(&b']') {
+            self.pos += 1;
+            return Ok(items);
+        }
+        loop {
+            items.push(self.value()?);
+            self.whitespace();
+            match self.src.get(self.pos) {
+                Some(b',') => self.pos += 1,
+                Some(b']') => break self.pos += 1,
+                _ => anyhow::bail!("expected `,` or `]` at offset {}", self.pos),
+            }
+        }
+        Ok(items)
+    }
+
+    fn object(&mut self) -> anyhow::Result<Vec<(String, Json)>> {
+        self.expect("{")?;
+        let mut members = vec![];
+        self.whitespace();
+        if self.src.get(self.pos) == Some(&b'}') {
+            self.pos += 1;
+            return Ok(members);
+        }
+        loop {
+            self.whitespace();
+            let key = self.string()?;
+            self.whitespace();
+            self.expect(":")?;
+            members.push((key, self.value()?));
+            self.whitespace();
+            match self.src.get(self.pos) {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => break self.pos += 1,
+                _ => anyhow::bail!("expected `,` or `}}` at offset {}", self.pos),
+            }
+        }
+        Ok(members)
+    }
+
+    fn string(&mut self) -> anyhow::Result<String> {
+        self.expect("\"")?;
+        let mut out = vec![];
+        loop {
+            let Some(&b) = self.src.get(self.pos) else {
+                anyhow::bail!("unterminated string");
+            };
+            self.pos += 1;
+            match b {
+                b'"' => return Ok(String::from_utf8(out)?),
+                b'\\' => {
+                    let Some(&esc) = self.src.get(self.pos) else {
+                        anyhow::bail!("unterminated string");
+                    };
+                    self.pos += 1;
+                    let ch = match esc {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let hi = self.hex4()?;
+                            let code = match hi {
+                                0xd800..=0xdbff => {
+                                    self.expect("\\u")?;
+                                    let lo = self.hex4()?;
+                                    0x10000 + ((hi - 0xd800) << 10) + (lo.wrapping_sub(0xdc00))
+                                }
+                                _ => hi,
+                            };
+                            char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER)
+                        }
+                        b => anyhow::bail!("unknown escape `\\{}`", b as char),
+                    };
+                    out.extend_from_slice(ch.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                b => out.push(b),
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> anyhow::Result<u32> {
+        let digits = self
+            .src
+            .get(self.pos..self.pos + 4)
+            .ok_or_else(|| anyhow::anyhow!("truncated \\u escape"))?;
+        self.pos += 4;
+        Ok(u32::from_str_radix(std::str::from_utf8(digits)?, 16)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_request() {
+        let text = r#" {"jsonrpc": "2.0", "id": 3, "method": "textDocument/hover",
+            "params": {"position": {"line": 0, "character": 12}, "flags": [true, null, -1.5e1]}} "#;
+        let json = Json::parse(text).unwrap();
+        assert_eq!(json.get("id").and_then(Json::as_usize), Some(3));
+        assert_eq!(
+            json.get("method").and_then(Json::as_str),
+            Some("textDocument/hover")
+        );
+        let params = json.get("params").unwrap();
+        let position = params.get("position").unwrap();
+        assert_eq!(position.get("character").and_then(Json::as_usize), Some(12));
+        assert_eq!(
+            params.get("flags").and_then(Json::as_array),
+            Some(&[Json::Bool(true), Json::Null, Json::Number(-15.0)][..])
+        );
+    }
+
+    #[test]
+    fn decodes_escapes() {
+        let json = Json::parse(r#""a\"\\\/\n\t\u00e9\ud83d\ude00""#).unwrap();
+        assert_eq!(json.as_str(), Some("a\"\\/\n\té\u{1f600}"));
+    }
+
+    #[test]
+    fn round_trips_through_display() {
+        let value = object([
+            ("s", "quote \" slash \\ bell \u{7} tab \t".into()),
+            ("n", 42.into()),
+            ("f", Json::Number(0.5)),
+            ("a", vec![Json::Null, true.into(), object([])].into()),
+        ]);
+        let text = value.to_string();
+        assert!(text.contains(r#"\u0007"#), "{text}");
+        assert_eq!(Json::parse(&text).unwrap(), value);
+    }
+
+    #[test]
+    fn rejects_malformed_text() {
+        for text in [
+            "",
+            "[1,]",
+            "{\"a\" 1}",
+            "\"open",
+            "\"\\q\"",
+            "nul",
+            "1 2",
+            "[1 2]",
+        ] {
+            assert!(Json::parse(text).is_err(), "{text:?} parsed");
+        }
+    }
+}
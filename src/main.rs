@@ -1,6 +1,23 @@
 use wa::{
+    check::check,
+    compile_program,
+    context::Context,
+    coverage::Lcov,
+    difftest::{self, Backend, Harness, Rng},
+    effect::{self, Block},
+    elf::Executable,
+    fmt,
+    golden::{self, Expectation, Status},
+    interp::Interpreter,
+    limits::Limits,
+    lint::{self, Levels},
+    opt,
     parse::{parse_ops, Program},
+    snapshot::Snapshot,
     tokenise::Tokeniser,
+    types::{self, TypeError},
+    vm::Bytecode,
+    wasm::Module,
 };
 
 fn usage(program: impl AsRef<str>, subcmd: Option<impl AsRef<str>>) -> anyhow::Result<()> {
@@ -9,11 +26,143 @@ fn usage(program: impl AsRef<str>, subcmd: Option<impl AsRef<str>>) -> anyhow::R
         Some(sc) => match sc.as_ref() {
             "interpret" | "interp" | "i" => {
                 println!("interpret, interp, i: construct and run wa IR");
-                println!("  flags: TODO");
+                println!("  flags:");
+                println!("    --trace: print each executed op and the resulting stack to stderr");
+                println!(
+                    "    --provenance: remember the op that pushed each value, listing the stack"
+                );
+                println!("                  with where each value came from on underflow or leftover data");
+                println!("    -O: run the peephole optimiser before executing");
+                println!("    --opt-report: like -O, printing each rewrite applied to stderr");
+                println!("    --max-steps=<n>: abort after executing <n> ops");
+                println!("    --max-stack=<n>: abort if the stack grows beyond <n> elements");
+                println!(
+                    "    --pause-after=<n>: stop after <n> ops and save the run to --snapshot"
+                );
+                println!("    --snapshot=<path>: file to write the paused run to");
+                println!("    --resume=<path>: continue a run saved with --snapshot");
+                println!(
+                    "    --profile: count how often each op and line runs, printing the hottest"
+                );
+                println!("               to stderr at exit");
+                println!(
+                    "    --flamegraph=<path>: like --profile, also writing the counts to <path> as"
+                );
+                println!("                         collapsed stacks for flamegraph tools");
+                println!(
+                    "    --coverage=<path>: write the lines and `if` branches that ran to <path>"
+                );
+                println!("                       as an lcov tracefile");
+                println!("    -W=<lint>, -A=<lint>, -D=<lint>: warn about, allow or deny a lint, see `help lint`");
+            }
+            "vm" | "v" => {
+                println!("vm, v: lower to bytecode and run it on the checked stack machine");
+                println!("  flags:");
+                println!("    -O: run the peephole optimiser before lowering");
+                println!("    -W=<lint>, -A=<lint>, -D=<lint>: warn about, allow or deny a lint, see `help lint`");
+            }
+            "jit" | "j" => {
+                println!("jit, j: compile to x86-64 in memory and run it in-process");
+                println!("  flags:");
+                println!("    -O: run the peephole optimiser before compiling");
+                println!("    -W=<lint>, -A=<lint>, -D=<lint>: warn about, allow or deny a lint, see `help lint`");
             }
             "compile" | "com" | "c" => {
-                println!("compile, com, c: compile generated bytecode");
-                println!("  flags: TODO");
+                println!(
+                    "compile, com, c: compile to a static x86-64 Linux executable, with DWARF line"
+                );
+                println!("  info so debuggers can step through the source");
+                println!("  flags:");
+                println!("    -o=<path>: where to write the output, defaults to <arg> with the extension of --emit");
+                println!("    --emit=exe|asm|c|wasm|wat|ir|dot: write an executable (default), the equivalent");
+                println!("                      fasm source, a self-contained C file, a wasm module in binary");
+                println!("                      or text form importing display from the host, the SSA IR, or");
+                println!("                      the control-flow graph in Graphviz DOT");
+                println!("    -O: run the peephole optimiser before compiling");
+                println!("    -W=<lint>, -A=<lint>, -D=<lint>: warn about, allow or deny a lint, see `help lint`");
+            }
+            "difftest" | "dt" => {
+                println!("difftest, dt: run <arg> random programs on every available backend and");
+                println!(
+                    "  compare them with the interpreter, shrinking the first disagreement found"
+                );
+                println!("  flags:");
+                println!("    --seed=<n>: seed of the first program, each one after it adds 1 (default 0)");
+                println!("    --size=<n>: roughly how many ops each program has (default 60)");
+            }
+            "test" | "t" => {
+                println!(
+                    "test, t: run every .wa file under <arg> and check what it prints against its"
+                );
+                println!("  `// expect: <line>` and `// expect-error: <diagnostic>` comments, or the same");
+                println!(
+                    "  lines without the `//` in a .expect file next to it, then run each of its"
+                );
+                println!(
+                    "  `test \"<name>\" ... end` blocks on an empty stack, failing on any `assert`"
+                );
+                println!("  that does not hold. <arg> may also be a single .wa file");
+                println!("  flags:");
+                println!("    --update: record .expect files for programs without expect comments");
+                println!(
+                    "    --coverage=<path>: write the lines and `if` branches every program and"
+                );
+                println!("                       test block ran to <path> as an lcov tracefile");
+            }
+            "fmt" => {
+                println!("fmt: rewrite <arg>, or every .wa file under it, in the canonical layout");
+                println!(
+                    "  of one space between tokens, four spaces of indentation per block, and"
+                );
+                println!("  trailing comments on neighbouring lines aligned. Files that do not");
+                println!("  parse are skipped");
+                println!("  flags:");
+                println!("    --check: change nothing, failing if any file is not formatted");
+            }
+            "lint" => {
+                println!(
+                    "lint: report code in <arg>, or every .wa file under it, that runs but most"
+                );
+                println!(
+                    "  likely not as intended. Runs also warn before they start, and stop if a"
+                );
+                println!(
+                    "  denied lint is found. Runs allow constant-if unless -W or -D turn it on."
+                );
+                println!("  Files under <arg> with an `// expect-error:` are skipped");
+                println!("  lints:");
+                println!("    redundant-pair: `dup drop` or `swap swap`, which do nothing");
+                println!("    constant-if: an `if` whose condition is the same on every run");
+                println!("    overflow: arithmetic on constants that wraps around");
+                println!("    unreachable: code after an op that always fails");
+                println!("  flags:");
+                println!(
+                    "    -W=<lint>: warn about <lint>, the default for every lint in `wa lint`"
+                );
+                println!("    -A=<lint>: allow <lint>");
+                println!("    -D=<lint>: deny <lint>, failing if it is found");
+                println!("    <lint> may also be `all`, and later flags override earlier ones");
+            }
+            "check" => {
+                println!(
+                    "check: report the type errors in <arg>, or every .wa file under it, and the"
+                );
+                println!(
+                    "  stack errors that are certain to happen when the code they are in runs."
+                );
+                println!("  Files under <arg> with an `// expect-error:` are skipped");
+                println!("  flags:");
+                println!(
+                    "    --effects: also print the stack effect, `( in -- out )` with types where"
+                );
+                println!("               known, of each program, `if` block and test block");
+            }
+            "lsp" => {
+                println!("lsp: run a language server over stdin and stdout, for editors. Takes no");
+                println!(
+                    "  <arg>, and publishes diagnostics, hover with stack effects and depths,"
+                );
+                println!("  test blocks as document symbols, and semantic tokens");
             }
             "dump" | "d" => {
                 println!("dump, d: dump generated bytecode to file");
@@ -26,8 +175,25 @@ fn usage(program: impl AsRef<str>, subcmd: Option<impl AsRef<str>>) -> anyhow::R
             println!("    subcommands:");
             println!("      - interpret, interp, i: construct and run wa IR");
             println!("          - <arg> is the path to the wa file");
-            println!("      - compile, com, c: compile generated bytecode");
+            println!("      - vm, v: lower to bytecode and run it on the checked stack machine");
+            println!("          - <arg> is the path to the wa file");
+            println!("      - jit, j: compile to x86-64 in memory and run it in-process");
+            println!("          - <arg> is the path to the wa file");
+            println!("      - compile, com, c: compile to a static x86-64 Linux executable");
             println!("          - <arg> is the path to the wa file");
+            println!("      - difftest, dt: compare every backend against the interpreter");
+            println!("          - <arg> is how many random programs to run");
+            println!("      - test, t: check programs against their expected output");
+            println!("          - <arg> is the directory to search for wa files, or one file");
+            println!("      - fmt: format wa source in the canonical layout");
+            println!("          - <arg> is a wa file or a directory to search for them");
+            println!("      - lint: report code that most likely does not do what was meant");
+            println!("          - <arg> is a wa file or a directory to search for them");
+            println!(
+                "      - check: report type and stack errors before running, and stack effects"
+            );
+            println!("          - <arg> is a wa file or a directory to search for them");
+            println!("      - lsp: run a language server over stdin and stdout");
             println!("      - dump, d: dump generated bytecode to file");
             println!("          - <arg> is the path to the wa file");
             println!("      - help, h: print help information");
@@ -39,10 +205,370 @@ fn usage(program: impl AsRef<str>, subcmd: Option<impl AsRef<str>>) -> anyhow::R
 
 fn parse_program_from_file(file_name: impl AsRef<str>) -> anyhow::Result<Program> {
     let file = std::fs::read(file_name.as_ref())?;
-    let ops = Tokeniser::new(file.as_ref())
-        .into_iter()
-        .collect::<Vec<_>>();
-    Ok(parse_ops(ops, file_name.as_ref())?)
+    let ops = Tokeniser::new(file.as_ref()).collect::<Vec<_>>();
+    parse_ops(ops, file_name.as_ref())
+}
+
+/// Parses `file_name` for a run, reporting its lints and type errors and failing if there are
+/// errors, unless `flags` resume a run that reported them already. Returns the program with the
+/// flags other than `-A=`, `-W=` and `-D=`, for the backend to parse.
+fn load_checked<'f>(
+    file_name: &str,
+    flags: &'f [String],
+) -> anyhow::Result<(Program, Vec<&'f str>)> {
+    let prog = parse_program_from_file(file_name)?;
+    let mut levels = Levels::for_runs();
+    let mut rest = vec![];
+    for flag in flags {
+        if !levels.apply(flag)? {
+            rest.push(flag.as_str());
+        }
+    }
+    if !rest.iter().any(|flag| flag.starts_with("--resume=")) {
+        lint::report(file_name, &prog, &levels, &mut std::io::stderr())?;
+        types::report(file_name, &prog, &mut std::io::stderr())?;
+    }
+    Ok((prog, rest))
+}
+
+fn interp_file(file_name: &str, flags: &[String]) -> anyhow::Result<()> {
+    let (mut prog, flags) = load_checked(file_name, flags)?;
+    let (mut trace, mut limits) = (false, Limits::default());
+    let (mut optimise, mut opt_report) = (false, false);
+    let (mut pause_after, mut snapshot_path, mut resume_path) = (None, None, None);
+    let (mut profile, mut flamegraph_path) = (false, None);
+    let (mut lcov_path, mut provenance) = (None, false);
+    for flag in flags {
+        match flag.split_once('=') {
+            None if flag == "--trace" => trace = true,
+            None if flag == "--provenance" => provenance = true,
+            None if flag == "--profile" => profile = true,
+            Some(("--flamegraph", path)) => (profile, flamegraph_path) = (true, Some(path)),
+            Some(("--coverage", path)) => lcov_path = Some(path),
+            None if flag == "-O" => optimise = true,
+            None if flag == "--opt-report" => (optimise, opt_report) = (true, true),
+            Some(("--max-steps", n)) => limits.max_steps = Some(n.parse()?),
+            Some(("--max-stack", n)) => limits.max_stack_depth = Some(n.parse()?),
+            Some(("--pause-after", n)) => pause_after = Some(n.parse::<usize>()?),
+            Some(("--snapshot", path)) => snapshot_path = Some(path),
+            Some(("--resume", path)) => resume_path = Some(path),
+            _ => anyhow::bail!("Unknown flag {flag}"),
+        }
+    }
+
+    if optimise {
+        let (optimised, rewrites) = opt::optimise(prog);
+        if opt_report {
+            for rewrite in rewrites {
+                eprintln!("{}", rewrite.as_stamp(file_name));
+            }
+        }
+        prog = optimised;
+    }
+
+    let mut interp = match resume_path {
+        Some(path) => {
+            let snapshot = Snapshot::from_bytes(&std::fs::read(path)?)?;
+            Interpreter::resume(file_name, prog, Context::std(), snapshot)?
+        }
+        None => Interpreter::new(file_name, prog, Context::std()),
+    };
+    interp.trace = trace;
+    interp.limits = limits;
+    if profile {
+        interp.start_profile();
+    }
+    if lcov_path.is_some() {
+        interp.start_coverage();
+    }
+    if provenance {
+        interp.track_provenance();
+    }
+
+    // the profile covers failed runs too, they may well be the slow ones
+    let result = match pause_after {
+        None => interp.run(),
+        Some(n) => pause(&mut interp, n, snapshot_path),
+    };
+    if let Some(profile) = interp.profile() {
+        let src = String::from_utf8_lossy(&std::fs::read(file_name)?).into_owned();
+        profile.hot_spots(file_name, &src, &mut std::io::stderr())?;
+        if let Some(path) = flamegraph_path {
+            std::fs::write(path, profile.collapsed(file_name))?;
+        }
+    }
+    if let (Some(path), Some(coverage)) = (lcov_path, interp.coverage()) {
+        let mut lcov = Lcov::default();
+        lcov.add(file_name, coverage);
+        std::fs::write(path, lcov.to_string())?;
+    }
+    result
+}
+
+/// Runs `n` ops and saves the run to `snapshot_path`, or finishes it if it ends sooner.
+fn pause(interp: &mut Interpreter, n: usize, snapshot_path: Option<&str>) -> anyhow::Result<()> {
+    let path = snapshot_path.ok_or(anyhow::anyhow!("--pause-after requires --snapshot=<path>"))?;
+    while interp.steps() < n {
+        if !interp.step()? {
+            return interp.run();
+        }
+    }
+    std::fs::write(path, interp.snapshot().to_bytes())?;
+    Ok(())
+}
+
+fn compile_file(file_name: &str, flags: &[String]) -> anyhow::Result<()> {
+    let (mut prog, flags) = load_checked(file_name, flags)?;
+    let (mut emit, mut out) = ("exe", None);
+    let mut optimise = false;
+    for flag in flags {
+        match flag.split_once('=') {
+            None if flag == "-O" => optimise = true,
+            Some(("-o", path)) => out = Some(std::path::PathBuf::from(path)),
+            Some(("--emit", kind @ ("exe" | "asm" | "c" | "wasm" | "wat" | "ir" | "dot"))) => {
+                emit = kind
+            }
+            _ => anyhow::bail!("Unknown flag {flag}"),
+        }
+    }
+    let out = out.unwrap_or_else(|| {
+        let ext = match emit {
+            "exe" => "",
+            kind => kind,
+        };
+        std::path::Path::new(file_name).with_extension(ext)
+    });
+    if out == std::path::Path::new(file_name) {
+        anyhow::bail!("refusing to overwrite {file_name}, pass -o=<path>");
+    }
+    if optimise {
+        prog = opt::optimise(prog).0;
+    }
+
+    match emit {
+        "asm" => std::fs::write(out, Executable::new(file_name, &prog).to_asm())?,
+        "c" => std::fs::write(out, wa::c::emit(file_name, &prog))?,
+        "wasm" => {
+            let module = Module::new(file_name, &prog).to_wasm();
+            wa::wasm::validate::validate(&module)?;
+            std::fs::write(out, module)?
+        }
+        "wat" => std::fs::write(out, Module::new(file_name, &prog).to_wat())?,
+        "ir" => std::fs::write(out, wa::ir::Function::new(&prog).to_text(file_name))?,
+        "dot" => std::fs::write(out, wa::dot::emit(file_name, &prog))?,
+        _ => compile_program(file_name, &prog, out)?,
+    }
+    Ok(())
+}
+
+fn difftest(count: &str, flags: &[String]) -> anyhow::Result<()> {
+    let count = count.parse::<u64>()?;
+    let (mut seed, mut size) = (0, 60);
+    for flag in flags {
+        match flag.split_once('=') {
+            Some(("--seed", n)) => seed = n.parse()?,
+            Some(("--size", n)) => size = n.parse()?,
+            _ => anyhow::bail!("Unknown flag {flag}"),
+        }
+    }
+
+    let harness = Harness::new(Backend::available())?;
+    let names = harness.backends.iter().map(|b| b.to_string());
+    println!("backends: {}", names.collect::<Vec<_>>().join(", "));
+    for seed in seed..seed + count {
+        let src = difftest::generate(&mut Rng::new(seed), size);
+        let Some(mismatch) = harness.compare(&src)?.into_iter().next() else {
+            continue;
+        };
+        println!(
+            "seed {seed}: {} disagrees with the interpreter on",
+            mismatch.backend
+        );
+        println!("{src}");
+        let small = harness.shrink(mismatch.backend, &src)?;
+        println!("shrunk to:\n{small}\n");
+        println!("interp:\n{}\n", harness.run(Backend::Interp, &small)?);
+        println!(
+            "{}:\n{}",
+            mismatch.backend,
+            harness.run(mismatch.backend, &small)?
+        );
+        anyhow::bail!("backends disagree");
+    }
+    println!("{count} programs, no disagreements");
+    Ok(())
+}
+
+/// The programs under `path`, or `path` itself if it is a file. With `runnable`, programs found
+/// in a directory that are meant to fail, having an `// expect-error:`, are left out.
+fn programs(path: &str, runnable: bool) -> anyhow::Result<Vec<std::path::PathBuf>> {
+    let path = std::path::Path::new(path);
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut found = vec![];
+    for path in golden::programs(path)? {
+        let src = std::fs::read_to_string(&path)?;
+        let expected = Expectation::from_annotations(&path.to_string_lossy(), &src)?;
+        match expected.and_then(|e| e.error) {
+            Some(_) if runnable => println!("skipped {}, it expects an error", path.display()),
+            _ => found.push(path),
+        }
+    }
+    Ok(found)
+}
+
+fn fmt_files(path: &str, flags: &[String]) -> anyhow::Result<()> {
+    let mut check = false;
+    for flag in flags {
+        match flag.as_str() {
+            "--check" => check = true,
+            _ => anyhow::bail!("Unknown flag {flag}"),
+        }
+    }
+
+    let paths = programs(path, false)?;
+    let mut unformatted = 0;
+    for path in paths {
+        let src = std::fs::read(&path)?;
+        let formatted = match fmt::format(&path.to_string_lossy(), &src) {
+            Ok(formatted) => formatted,
+            Err(e) => {
+                println!("skipped, it does not parse: {e}");
+                continue;
+            }
+        };
+        if formatted.as_bytes() == src {
+            continue;
+        }
+        unformatted += 1;
+        match check {
+            true => println!("{} is not formatted", path.display()),
+            false => {
+                std::fs::write(&path, formatted)?;
+                println!("formatted {}", path.display());
+            }
+        }
+    }
+    if check && unformatted > 0 {
+        anyhow::bail!("{unformatted} file(s) need formatting, run `wa fmt` on them");
+    }
+    Ok(())
+}
+
+fn lint_files(path: &str, flags: &[String]) -> anyhow::Result<()> {
+    let mut levels = Levels::default();
+    for flag in flags {
+        if !levels.apply(flag)? {
+            anyhow::bail!("Unknown flag {flag}");
+        }
+    }
+
+    let paths = programs(path, true)?;
+    let mut failed = 0;
+    for path in paths {
+        let name = path.to_string_lossy();
+        let result = parse_program_from_file(&name)
+            .and_then(|prog| lint::report(&name, &prog, &levels, &mut std::io::stdout()));
+        if let Err(e) = result {
+            failed += 1;
+            println!("Error: {e}");
+        }
+    }
+    if failed > 0 {
+        anyhow::bail!("{failed} file(s) failed to lint");
+    }
+    Ok(())
+}
+
+fn check_files(path: &str, flags: &[String]) -> anyhow::Result<()> {
+    let mut effects = false;
+    for flag in flags {
+        match flag.as_str() {
+            "--effects" => effects = true,
+            _ => anyhow::bail!("Unknown flag {flag}"),
+        }
+    }
+
+    let paths = programs(path, true)?;
+    let mut failed = 0;
+    for path in paths {
+        let name = path.to_string_lossy();
+        let prog = match parse_program_from_file(&name) {
+            Ok(prog) => prog,
+            Err(e) => {
+                failed += 1;
+                println!("Error: {e}");
+                continue;
+            }
+        };
+        let tests = prog.tests.iter().map(|t| &t.program.ops);
+        let mut faults = 0;
+        for ops in std::iter::once(&prog.ops).chain(tests) {
+            for (op, fault) in ops.iter().zip(check(ops).faults) {
+                if let Some(fault) = fault {
+                    faults += 1;
+                    println!("error: {}: {fault}", op.idx.as_stamp(&name));
+                }
+            }
+        }
+        for TypeError { at, message } in types::check(&prog) {
+            faults += 1;
+            println!("error: {}: {message}", at.as_stamp(&name));
+        }
+        failed += (faults > 0) as usize;
+        if effects {
+            for Block { at, kind, effect } in effect::effects(&prog) {
+                println!("{}: {kind} {effect}", at.as_stamp(&name));
+            }
+        }
+    }
+    if failed > 0 {
+        anyhow::bail!("{failed} file(s) failed to check");
+    }
+    Ok(())
+}
+
+fn test_dir(dir: &str, flags: &[String]) -> anyhow::Result<()> {
+    let (mut update, mut lcov_path) = (false, None);
+    for flag in flags {
+        match flag.split_once('=') {
+            None if flag == "--update" => update = true,
+            Some(("--coverage", path)) => lcov_path = Some(path),
+            _ => anyhow::bail!("Unknown flag {flag}"),
+        }
+    }
+
+    let mut lcov = lcov_path.map(|_| Lcov::default());
+    let results = golden::run_dir(std::path::Path::new(dir), update, lcov.as_mut())?;
+    if let (Some(path), Some(lcov)) = (lcov_path, lcov) {
+        std::fs::write(path, lcov.to_string())?;
+    }
+    let mut failed = 0;
+    for (path, status) in &results {
+        match status {
+            Status::Passed => println!("ok       {path}"),
+            Status::Recorded => println!("recorded {path}"),
+            Status::Missing => {
+                failed += 1;
+                println!("MISSING  {path}: no expectations, record them with --update");
+            }
+            Status::Failed { expected, got } => {
+                failed += 1;
+                println!("FAIL     {path}");
+                println!("expected:\n{expected}\ngot:\n{got}\n");
+            }
+            Status::Errored(e) => {
+                failed += 1;
+                println!("FAIL     {path}: {e}");
+            }
+        }
+    }
+    println!("{} passed, {failed} failed", results.len() - failed);
+    if failed > 0 {
+        anyhow::bail!("{failed} test(s) failed");
+    }
+    Ok(())
 }
 
 fn main() -> anyhow::Result<()> {
@@ -50,6 +576,11 @@ fn main() -> anyhow::Result<()> {
 
     let program = args.next().unwrap_or("wa".into());
 
+    // editors start the server without an argument, or with --stdio
+    if std::env::args().nth(1).is_some_and(|sc| sc == "lsp") {
+        return wa::lsp::run(std::io::stdin().lock(), std::io::stdout().lock());
+    }
+
     if std::env::args().len() < 3 {
         usage(&program, None::<&str>)?;
         anyhow::bail!("Not enough arguments");
@@ -57,13 +588,47 @@ fn main() -> anyhow::Result<()> {
 
     let subcmd = args.next().unwrap();
     let file_name = args.next().unwrap();
+    let flags = args.filter(|a| a != "--").collect::<Vec<_>>();
 
     match subcmd.as_ref() {
         "interpret" | "interp" | "i" => {
-            let prog = parse_program_from_file(&file_name)?;
-            wa::interp_program(&file_name, prog)?;
+            interp_file(&file_name, &flags)?;
+        }
+        "vm" | "v" => {
+            let (mut prog, flags) = load_checked(&file_name, &flags)?;
+            let mut optimise = false;
+            for flag in flags {
+                match flag {
+                    "-O" => optimise = true,
+                    _ => anyhow::bail!("Unknown flag {flag}"),
+                }
+            }
+            if optimise {
+                prog = opt::optimise(prog).0;
+            }
+            Bytecode::new(&file_name, &prog).run(&mut Context::std())?;
+        }
+        #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+        "jit" | "j" => {
+            let (mut prog, flags) = load_checked(&file_name, &flags)?;
+            let mut optimise = false;
+            for flag in flags {
+                match flag {
+                    "-O" => optimise = true,
+                    _ => anyhow::bail!("Unknown flag {flag}"),
+                }
+            }
+            if optimise {
+                prog = opt::optimise(prog).0;
+            }
+            wa::jit::Jit::new(&file_name, &prog)?.run(&mut Context::std())?;
         }
-        "compile" | "com" | "c" => todo!("compilation"),
+        "compile" | "com" | "c" => compile_file(&file_name, &flags)?,
+        "difftest" | "dt" => difftest(&file_name, &flags)?,
+        "test" | "t" => test_dir(&file_name, &flags)?,
+        "fmt" => fmt_files(&file_name, &flags)?,
+        "lint" => lint_files(&file_name, &flags)?,
+        "check" => check_files(&file_name, &flags)?,
         "dump" | "d" => todo!("dump"),
         "help" | "h" => usage(&program, Some(&file_name))?,
         _ => todo!(),
@@ -0,0 +1,270 @@
+use crate::{
+    check::{check, Fault},
+    ops::{Op, Op1_0, Op1_2, Op2_1, Op2_2},
+    tokenise::Span,
+    x86::{Alu, Asm, Cond, Inst, Label, Reg},
+};
+
+/// A runtime error raised by native code, reported against the op at the index it is raised
+/// with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trap {
+    Fault(Fault),
+    DivZero,
+    /// the offending value is in `rax`
+    NotBool,
+    Leftover(usize),
+}
+
+/// How lowered code talks to the world it runs in: the JIT calls back into Rust, while a
+/// standalone executable carries its own routines.
+pub trait Runtime {
+    /// Emitted before the first op. Afterwards `rbp` holds the bottom of the data stack, which
+    /// grows down from there on the machine stack.
+    fn prologue(&mut self, asm: &mut Asm);
+    /// Emitted when the last op finishes with an empty stack.
+    fn epilogue(&mut self, asm: &mut Asm);
+    /// Displays the value in `rax`, preserving `rbx` and `rbp`.
+    fn display(&mut self, asm: &mut Asm, op: usize);
+    /// Reports `trap` against the op at index `op` and never returns.
+    fn trap(&mut self, asm: &mut Asm, op: usize, trap: Trap);
+}
+
+/// How `lower` maps the data stack onto the machine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Lowering {
+    /// Every op pops its inputs from and pushes its outputs to the machine stack.
+    Naive,
+    /// The top of the data stack lives in registers and is only written to the machine stack
+    /// when they run out, at block boundaries and around calls into the runtime.
+    #[default]
+    StackCached,
+}
+
+/// Registers that hold the top of the data stack. `rax`, `rcx` and `rdx` stay free for
+/// division and for handing values to the runtime.
+const CACHE: [Reg; 6] = [Reg::R8, Reg::R9, Reg::R10, Reg::R11, Reg::Rsi, Reg::Rdi];
+
+/// The registers currently holding the top of the data stack, bottom first. Everything below
+/// them is on the machine stack.
+#[derive(Debug, Default)]
+struct Cache {
+    regs: Vec<Reg>,
+}
+
+impl Cache {
+    /// A free register, spilling the bottom of the cache if there is none.
+    fn alloc(&mut self, asm: &mut Asm) -> Reg {
+        match CACHE.into_iter().find(|r| !self.regs.contains(r)) {
+            Some(r) => r,
+            None => {
+                let bottom = self.regs.remove(0);
+                asm.emit(Inst::Push(bottom));
+                bottom
+            }
+        }
+    }
+
+    /// Makes sure the top `n` elements are in registers.
+    fn load(&mut self, asm: &mut Asm, n: usize) {
+        while self.regs.len() < n {
+            let r = self.alloc(asm);
+            asm.emit(Inst::Pop(r));
+            self.regs.insert(0, r);
+        }
+    }
+
+    /// Takes the top element out of the cache, leaving its register free.
+    fn pop(&mut self, asm: &mut Asm) -> Reg {
+        self.load(asm, 1);
+        self.regs.pop().expect("loaded above")
+    }
+
+    fn spill(&mut self, asm: &mut Asm) {
+        for r in self.regs.drain(..) {
+            asm.emit(Inst::Push(r));
+        }
+    }
+}
+
+/// Lowers `ops` to x86-64 that keeps the data stack on the machine stack, with the top of it
+/// in registers when `lowering` asks for it. Stack faults are decided by [`check`], so only
+/// division and `if` conditions are checked at runtime, on out-of-line paths.
+///
+/// The cache is empty wherever control flow joins: on entry to a block, at its `end`, and
+/// after anything that never returns.
+///
+/// Returns a label at the start of the code for every op and every out-of-line path, each with
+/// the index of the op it belongs to, in the order they were emitted.
+pub fn lower(
+    asm: &mut Asm,
+    ops: &[Span<Op>],
+    rt: &mut impl Runtime,
+    lowering: Lowering,
+) -> Vec<(Label, usize)> {
+    let checked = check(ops);
+    let op_labels = (0..=ops.len()).map(|_| asm.label()).collect::<Vec<_>>();
+    let mut marks = (0..ops.len())
+        .map(|i| (op_labels[i], i))
+        .collect::<Vec<_>>();
+    let mut stubs: Vec<(Label, usize, Trap)> = vec![];
+    let mut stub = |asm: &mut Asm, op: usize, trap: Trap| {
+        let l = asm.label();
+        stubs.push((l, op, trap));
+        l
+    };
+    let mut cache = Cache::default();
+
+    rt.prologue(asm);
+    for (i, Span { token: op, .. }) in ops.iter().enumerate() {
+        asm.emit(Inst::Label(op_labels[i]));
+        if let Some(fault) = checked.faults[i] {
+            rt.trap(asm, i, Trap::Fault(fault));
+            cache.regs.clear();
+            continue;
+        }
+        if checked.depths[i].is_none() {
+            cache.regs.clear();
+            continue;
+        }
+        match *op {
+            Op::Push(n) => {
+                let r = cache.alloc(asm);
+                asm.emit(Inst::MovImm(r, n as i64));
+                cache.regs.push(r);
+            }
+            Op::Intr1_0(Op1_0::Display) => {
+                let t = cache.pop(asm);
+                cache.spill(asm);
+                asm.emit(Inst::Mov(Reg::Rax, t));
+                rt.display(asm, i);
+            }
+            Op::Intr1_0(Op1_0::Drop) => match cache.regs.pop() {
+                Some(_) => {}
+                None => asm.emit(Inst::AluImm(Alu::Add, Reg::Rsp, 8)),
+            },
+            Op::Intr1_2(Op1_2::Duplicate) => {
+                cache.load(asm, 1);
+                let t = *cache.regs.last().expect("loaded above");
+                let r = cache.alloc(asm);
+                asm.emit(Inst::Mov(r, t));
+                cache.regs.push(r);
+            }
+            Op::Intr2_1(op_id) => {
+                cache.load(asm, 2);
+                let (t, t1) = (cache.pop(asm), cache.pop(asm));
+                let res = match op_id {
+                    Op2_1::Add => {
+                        asm.emit(Inst::Alu(Alu::Add, t1, t));
+                        t1
+                    }
+                    Op2_1::Sub => {
+                        asm.emit(Inst::Alu(Alu::Sub, t, t1));
+                        t
+                    }
+                    Op2_1::Mul => {
+                        asm.emit(Inst::Imul(t1, t));
+                        t1
+                    }
+                    Op2_1::Div | Op2_1::Mod => {
+                        let div_zero = stub(asm, i, Trap::DivZero);
+                        asm.emit(Inst::Mov(Reg::Rax, t));
+                        asm.emit(Inst::Mov(Reg::Rcx, t1));
+                        divide(asm, div_zero);
+                        let res = match op_id {
+                            Op2_1::Mod => Reg::Rdx,
+                            _ => Reg::Rax,
+                        };
+                        asm.emit(Inst::Mov(t1, res));
+                        t1
+                    }
+                    Op2_1::Equ => compare(asm, Cond::E, t, t1),
+                    Op2_1::Less => compare(asm, Cond::L, t, t1),
+                    Op2_1::Greater => compare(asm, Cond::G, t, t1),
+                    Op2_1::LessEqu => compare(asm, Cond::Le, t, t1),
+                    Op2_1::GreaterEqu => compare(asm, Cond::Ge, t, t1),
+                };
+                cache.regs.push(res);
+            }
+            Op::Intr2_2(Op2_2::DivMod) => {
+                cache.load(asm, 2);
+                let (t, t1) = (cache.pop(asm), cache.pop(asm));
+                let div_zero = stub(asm, i, Trap::DivZero);
+                asm.emit(Inst::Mov(Reg::Rax, t));
+                asm.emit(Inst::Mov(Reg::Rcx, t1));
+                divide(asm, div_zero);
+                asm.emit(Inst::Mov(t1, Reg::Rdx));
+                asm.emit(Inst::Mov(t, Reg::Rax));
+                cache.regs.extend([t1, t]);
+            }
+            Op::Intr2_2(Op2_2::Swap) => {
+                cache.load(asm, 2);
+                let n = cache.regs.len();
+                cache.regs.swap(n - 1, n - 2);
+            }
+            Op::If(target) => {
+                // a skipped block that closes the program blames the `if` for leftover data
+                let skip = match (target.0 == ops.len(), checked.exit_depth) {
+                    (true, Some(d)) if d > 0 => stub(asm, i, Trap::Leftover(d)),
+                    _ => op_labels[target.0],
+                };
+                let not_bool = stub(asm, i, Trap::NotBool);
+                let c = cache.pop(asm);
+                cache.spill(asm);
+                asm.emit(Inst::Mov(Reg::Rax, c));
+                asm.emit(Inst::Test(Reg::Rax, Reg::Rax));
+                asm.emit(Inst::Jcc(Cond::E, skip));
+                asm.emit(Inst::AluImm(Alu::Cmp, Reg::Rax, 1));
+                asm.emit(Inst::Jcc(Cond::Ne, not_bool));
+            }
+            Op::End => cache.spill(asm),
+            Op::Cast(_) => {}
+            Op::Assert(_) | Op::AssertStack(_) => {
+                unreachable!("assertions only parse inside test blocks")
+            }
+        }
+        if lowering == Lowering::Naive {
+            cache.spill(asm);
+        }
+    }
+
+    asm.emit(Inst::Label(op_labels[ops.len()]));
+    if let Some(last) = ops.len().checked_sub(1) {
+        marks.push((op_labels[ops.len()], last));
+    }
+    match checked.exit_depth {
+        Some(d) if d > 0 => rt.trap(asm, ops.len().saturating_sub(1), Trap::Leftover(d)),
+        _ => rt.epilogue(asm),
+    }
+    for (l, op, trap) in stubs {
+        asm.emit(Inst::Label(l));
+        rt.trap(asm, op, trap);
+        marks.push((l, op));
+    }
+    marks
+}
+
+/// `rax / rcx` into `rax` with the remainder in `rdx`, wrapping like the interpreter does.
+fn divide(asm: &mut Asm, div_zero: Label) {
+    let (idiv, done) = (asm.label(), asm.label());
+    asm.emit(Inst::Test(Reg::Rcx, Reg::Rcx));
+    asm.emit(Inst::Jcc(Cond::E, div_zero));
+    // `idiv` faults on isize::MIN / -1, which wraps to isize::MIN remainder 0
+    asm.emit(Inst::AluImm(Alu::Cmp, Reg::Rcx, -1));
+    asm.emit(Inst::Jcc(Cond::Ne, idiv));
+    asm.emit(Inst::Neg(Reg::Rax));
+    asm.emit(Inst::Zero(Reg::Rdx));
+    asm.emit(Inst::Jmp(done));
+    asm.emit(Inst::Label(idiv));
+    asm.emit(Inst::Cqo);
+    asm.emit(Inst::Idiv(Reg::Rcx));
+    asm.emit(Inst::Label(done));
+}
+
+/// `t <cond> t1` into `t` as 0 or 1.
+fn compare(asm: &mut Asm, cond: Cond, t: Reg, t1: Reg) -> Reg {
+    asm.emit(Inst::Alu(Alu::Cmp, t, t1));
+    asm.emit(Inst::Setcc(cond, t));
+    asm.emit(Inst::Movzx8(t, t));
+    t
+}
@@ -1,4 +1,6 @@
-use std::isize;
+use std::io::Write;
+
+use crate::types::Type;
 
 #[derive(Debug, Clone, Copy)]
 pub struct OpIdx(pub usize);
@@ -24,6 +26,14 @@ pub enum Op {
     Intr2_2(Op2_2),
     If(OpIdx),
     End,
+    /// Pops a bool and fails unless it is 1, with the message at this index of
+    /// [`Program::messages`](crate::parse::Program::messages) if there is one.
+    Assert(Option<usize>),
+    /// Gives the top value this type, leaving the value itself as it is.
+    Cast(Type),
+    /// Fails unless the stack holds exactly [`Program::stacks`](crate::parse::Program::stacks)
+    /// at this index.
+    AssertStack(usize),
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -69,9 +79,11 @@ impl std::fmt::Display for Op {
                     Op1_0::Drop => "DROP",
                 }
             ),
-            Op::Intr1_2(op_id) => write!(f, "{}",
+            Op::Intr1_2(op_id) => write!(
+                f,
+                "{}",
                 match op_id {
-                    Op1_2::Duplicate => "DUP"
+                    Op1_2::Duplicate => "DUP",
                 }
             ),
             Op::Intr2_1(op_id) => write!(
@@ -100,18 +112,48 @@ impl std::fmt::Display for Op {
             ),
             Op::If(jmp_idx) => write!(f, "IF => {jmp_idx}"),
             Op::End => write!(f, "END"),
+            Op::Assert(None) => write!(f, "ASSERT"),
+            Op::Assert(Some(msg_idx)) => write!(f, "ASSERT #{msg_idx}"),
+            Op::AssertStack(stack_idx) => write!(f, "ASSERT_STACK #{stack_idx}"),
+            Op::Cast(to) => write!(f, "CAST {to}"),
+        }
+    }
+}
+
+impl Op {
+    /// The number of elements the op pops and pushes, counting the condition popped by `If`.
+    pub fn arity(&self) -> (usize, usize) {
+        match self {
+            Op::Push(_) => (0, 1),
+            Op::Intr1_0(_) => (1, 0),
+            Op::Intr1_2(_) => (1, 2),
+            Op::Intr2_1(_) => (2, 1),
+            Op::Intr2_2(_) => (2, 2),
+            Op::If(_) => (1, 0),
+            Op::End => (0, 0),
+            Op::Assert(_) => (1, 0),
+            Op::AssertStack(_) => (0, 0),
+            Op::Cast(_) => (1, 1),
         }
     }
 }
 
+/// Arithmetic wraps on overflow, matching the native backends, but dividing by zero is an error.
+fn divisor(t1: isize) -> anyhow::Result<isize> {
+    match t1 {
+        0 => anyhow::bail!("division by zero"),
+        t1 => Ok(t1),
+    }
+}
+
 impl Op1_0 {
     pub fn into_op(self) -> crate::stack::VirtStackOp<1, 0> {
         match self {
-            Op1_0::Display => |[t]| {
-                println!("{t}");
-                []
+            Op1_0::Display => |ctx, [t]| {
+                writeln!(ctx.stdout, "{t}")?;
+                Ok([])
             },
-            Op1_0::Drop => |[_]| [],
+            Op1_0::Drop => |_, [_]| Ok([]),
         }
     }
 }
@@ -119,7 +161,7 @@ impl Op1_0 {
 impl Op1_2 {
     pub fn into_op(self) -> crate::stack::VirtStackOp<1, 2> {
         match self {
-            Op1_2::Duplicate => |[t]| [t, t],
+            Op1_2::Duplicate => |_, [t]| Ok([t, t]),
         }
     }
 }
@@ -127,16 +169,16 @@ impl Op1_2 {
 impl Op2_1 {
     pub fn into_op(self) -> crate::stack::VirtStackOp<2, 1> {
         match self {
-            Op2_1::Add => |[t, t1]| [t + t1],
-            Op2_1::Sub => |[t, t1]| [t - t1],
-            Op2_1::Mul => |[t, t1]| [t * t1],
-            Op2_1::Div => |[t, t1]| [t / t1],
-            Op2_1::Mod => |[t, t1]| [t % t1],
-            Op2_1::Equ => |[t, t1]| [(t == t1) as isize],
-            Op2_1::Less => |[t, t1]| [(t < t1) as isize],
-            Op2_1::Greater => |[t, t1]| [(t > t1) as isize],
-            Op2_1::LessEqu => |[t, t1]| [(t <= t1) as isize],
-            Op2_1::GreaterEqu => |[t, t1]| [(t >= t1) as isize],
+            Op2_1::Add => |_, [t, t1]| Ok([t.wrapping_add(t1)]),
+            Op2_1::Sub => |_, [t, t1]| Ok([t.wrapping_sub(t1)]),
+            Op2_1::Mul => |_, [t, t1]| Ok([t.wrapping_mul(t1)]),
+            Op2_1::Div => |_, [t, t1]| Ok([t.wrapping_div(divisor(t1)?)]),
+            Op2_1::Mod => |_, [t, t1]| Ok([t.wrapping_rem(divisor(t1)?)]),
+            Op2_1::Equ => |_, [t, t1]| Ok([(t == t1) as isize]),
+            Op2_1::Less => |_, [t, t1]| Ok([(t < t1) as isize]),
+            Op2_1::Greater => |_, [t, t1]| Ok([(t > t1) as isize]),
+            Op2_1::LessEqu => |_, [t, t1]| Ok([(t <= t1) as isize]),
+            Op2_1::GreaterEqu => |_, [t, t1]| Ok([(t >= t1) as isize]),
         }
     }
 }
@@ -144,8 +186,11 @@ impl Op2_1 {
 impl Op2_2 {
     pub fn into_op(self) -> crate::stack::VirtStackOp<2, 2> {
         match self {
-            Op2_2::DivMod => |[t, t1]| [t / t1, t % t1],
-            Op2_2::Swap => |[t, t1]| [t1, t],
+            Op2_2::DivMod => |_, [t, t1]| {
+                let t1 = divisor(t1)?;
+                Ok([t.wrapping_div(t1), t.wrapping_rem(t1)])
+            },
+            Op2_2::Swap => |_, [t, t1]| Ok([t1, t]),
         }
     }
 }
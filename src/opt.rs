@@ -0,0 +1,413 @@
+use std::collections::HashSet;
+
+use crate::{
+    ops::{Op, Op1_0, Op1_2, Op2_1, Op2_2, OpIdx},
+    parse::Program,
+    tokenise::{Span, TokenIdx},
+    types::Type,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub enum RewriteKind {
+    Fold {
+        lhs: isize,
+        rhs: isize,
+        op: Op,
+        res: isize,
+    },
+    Cancel(Op, Op),
+    /// a cast of a constant, which only matters before running
+    Cast(Type),
+    ConstIf(bool),
+}
+
+/// A single rewrite applied by [`optimise`], stamped with the position of the first op it replaced.
+#[derive(Debug, Clone, Copy)]
+pub struct Rewrite {
+    pub at: TokenIdx,
+    pub kind: RewriteKind,
+}
+
+impl Rewrite {
+    pub fn as_stamp(&self, file_name: impl AsRef<str>) -> String {
+        let at = self.at.as_stamp(file_name);
+        match self.kind {
+            RewriteKind::Fold { lhs, rhs, op, res } => {
+                format!("{at}: folded PUSH {lhs} PUSH {rhs} {op} into PUSH {res}")
+            }
+            RewriteKind::Cancel(a, b) => format!("{at}: cancelled {a} {b}"),
+            RewriteKind::Cast(to) => format!("{at}: removed CAST {to} of a constant"),
+            RewriteKind::ConstIf(true) => format!("{at}: inlined IF with constant true condition"),
+            RewriteKind::ConstIf(false) => {
+                format!("{at}: removed IF with constant false condition")
+            }
+        }
+    }
+}
+
+/// Mirrors `Op2_1::into_op`, refusing to fold anything that would fault or overflow at runtime so
+/// that the error is still reported from the original op.
+fn fold(op: Op2_1, t: isize, t1: isize) -> Option<isize> {
+    match op {
+        Op2_1::Add => t.checked_add(t1),
+        Op2_1::Sub => t.checked_sub(t1),
+        Op2_1::Mul => t.checked_mul(t1),
+        Op2_1::Div => t.checked_div(t1),
+        Op2_1::Mod => t.checked_rem(t1),
+        Op2_1::Equ => Some((t == t1) as isize),
+        Op2_1::Less => Some((t < t1) as isize),
+        Op2_1::Greater => Some((t > t1) as isize),
+        Op2_1::LessEqu => Some((t <= t1) as isize),
+        Op2_1::GreaterEqu => Some((t >= t1) as isize),
+    }
+}
+
+/// Finds the `End` closing the `If` at `at`.
+fn matching_end(ops: &[Span<Op>], at: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (i, Span { token, .. }) in ops.iter().enumerate().skip(at + 1) {
+        match token {
+            Op::If(_) => depth += 1,
+            Op::End if depth == 0 => return Some(i),
+            Op::End => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Net stack effect of `ops`, assuming any nested blocks leave the stack as they found it.
+fn net_effect(ops: &[Span<Op>]) -> isize {
+    ops.iter()
+        .map(|Span { token, .. }| {
+            let (i, o) = token.arity();
+            o as isize - i as isize
+        })
+        .sum()
+}
+
+/// Whether the op left last in `rest` can take over the span of a consumed op that ended the
+/// program. Only a `Push` can, since any other op may still fail and has to keep its own span.
+fn takes_end_span(rest: &[Span<Op>]) -> bool {
+    matches!(
+        rest.last(),
+        None | Some(Span {
+            token: Op::Push(_),
+            ..
+        })
+    )
+}
+
+/// Rewrites the tail of `out` until no pattern matches. `at_end` is whether the tail ends the
+/// program, in which case the op left last keeps the span of the last op a rewrite consumed.
+fn reduce(out: &mut Vec<Span<Op>>, rewrites: &mut Vec<Rewrite>, at_end: bool) {
+    loop {
+        let rewrite = match out.as_slice() {
+            [.., Span {
+                idx,
+                token: Op::Push(lhs),
+            }, Span {
+                token: Op::Push(rhs),
+                ..
+            }, Span {
+                token: op @ Op::Intr2_1(op_id),
+                ..
+            }] => fold(*op_id, *rhs, *lhs).map(|res| {
+                let kind = RewriteKind::Fold {
+                    lhs: *lhs,
+                    rhs: *rhs,
+                    op: *op,
+                    res,
+                };
+                (3, Rewrite { at: *idx, kind })
+            }),
+            _ => None,
+        };
+        let rewrite = rewrite.or(match out.as_slice() {
+            [.., Span {
+                idx,
+                token: a @ Op::Intr2_2(Op2_2::Swap),
+            }, Span {
+                token: b @ Op::Intr2_2(Op2_2::Swap),
+                ..
+            }]
+            | [.., Span {
+                idx,
+                token: a @ Op::Intr1_2(Op1_2::Duplicate),
+            }, Span {
+                token: b @ Op::Intr1_0(Op1_0::Drop),
+                ..
+            }] => Some((
+                2,
+                Rewrite {
+                    at: *idx,
+                    kind: RewriteKind::Cancel(*a, *b),
+                },
+            )),
+            _ => None,
+        });
+        let rewrite = rewrite.or(match out.as_slice() {
+            [.., Span {
+                token: Op::Push(_), ..
+            }, Span {
+                idx,
+                token: Op::Cast(to),
+            }] => Some((
+                1,
+                Rewrite {
+                    at: *idx,
+                    kind: RewriteKind::Cast(*to),
+                },
+            )),
+            _ => None,
+        });
+
+        let Some((len, rewrite)) = rewrite else {
+            return;
+        };
+        let end_span = out[out.len() - 1].idx;
+        let fold = match rewrite.kind {
+            RewriteKind::Fold { res, .. } => Some(res),
+            _ => None,
+        };
+        if at_end && fold.is_none() && !takes_end_span(&out[..out.len() - len]) {
+            return;
+        }
+        out.truncate(out.len() - len);
+        if let Some(res) = fold {
+            out.push(Span {
+                idx: rewrite.at,
+                token: Op::Push(res),
+            });
+        }
+        if let (true, Some(last)) = (at_end, out.last_mut()) {
+            last.idx = end_span;
+        }
+        rewrites.push(rewrite);
+    }
+}
+
+/// Peephole pass over the parsed ops: folds constant arithmetic, cancels `swap swap` and
+/// `dup drop`, removes casts of constants, and resolves `if` blocks with constant conditions,
+/// then recomputes every `If` target. Cancelled pairs no longer fault on a short stack, so an
+/// underflow they would have raised is reported from the next op that needs the missing elements
+/// instead. Data left over at the end is blamed on the op that ran last, so a rewrite that ends
+/// the program leaves its span on the `Push` before it, and is not made if another op is there.
+pub fn optimise(
+    Program {
+        ops,
+        branches,
+        tests,
+        messages,
+        stacks,
+    }: Program,
+) -> (Program, Vec<Rewrite>) {
+    let mut out: Vec<Span<Op>> = Vec::with_capacity(ops.len());
+    let mut rewrites = vec![];
+    let mut inlined_ends = HashSet::new();
+
+    let mut i = 0;
+    while i < ops.len() {
+        let op = ops[i];
+        match (op.token, out.last().map(|s| s.token)) {
+            (Op::If(_), Some(Op::Push(c @ (0 | 1)))) => {
+                if let Some(end) = matching_end(&ops, i) {
+                    let at_end = end + 1 == ops.len();
+                    // a skipped block that ends the program is blamed on its `if`
+                    if c == 0 && (!at_end || takes_end_span(&out[..out.len() - 1])) {
+                        out.pop();
+                        if let (true, Some(last)) = (at_end, out.last_mut()) {
+                            last.idx = op.idx;
+                        }
+                        rewrites.push(Rewrite {
+                            at: op.idx,
+                            kind: RewriteKind::ConstIf(false),
+                        });
+                        i = end + 1;
+                        continue;
+                    }
+                    // the interpreter checks a taken block leaves the stack as it found it, so
+                    // only inline blocks that are known to pass that check, and one that ends the
+                    // program only if a `Push` is left last to take the span of its `end`
+                    let ends_with_push = matches!(
+                        ops[i + 1..end].last(),
+                        Some(Span {
+                            token: Op::Push(_),
+                            ..
+                        })
+                    );
+                    if c == 1 && net_effect(&ops[i + 1..end]) == 0 && (!at_end || ends_with_push) {
+                        out.pop();
+                        rewrites.push(Rewrite {
+                            at: op.idx,
+                            kind: RewriteKind::ConstIf(true),
+                        });
+                        inlined_ends.insert(end);
+                        i += 1;
+                        continue;
+                    }
+                }
+                out.push(op);
+            }
+            (Op::End, _) if inlined_ends.contains(&i) => {
+                if let (true, Some(last)) = (i + 1 == ops.len(), out.last_mut()) {
+                    last.idx = op.idx;
+                }
+            }
+            _ => {
+                out.push(op);
+                reduce(&mut out, &mut rewrites, i + 1 == ops.len());
+            }
+        }
+        i += 1;
+    }
+
+    let mut open = vec![];
+    for i in 0..out.len() {
+        match out[i].token {
+            Op::If(_) => open.push(i),
+            Op::End => {
+                if let Some(at) = open.pop() {
+                    out[at].token = Op::If(OpIdx::new(i + 1));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let program = Program {
+        ops: out,
+        branches,
+        tests,
+        messages,
+        stacks,
+    };
+    (program, rewrites)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{context::Context, interp::Interpreter, parse::parse_str};
+
+    /// The optimised ops, as `wa dump` shows them.
+    fn ops(src: &str) -> Vec<String> {
+        let (program, _) = optimise(parse_str(src).unwrap());
+        program.ops.iter().map(|s| s.token.to_string()).collect()
+    }
+
+    fn report(src: &str) -> Vec<String> {
+        let (_, rewrites) = optimise(parse_str(src).unwrap());
+        rewrites.iter().map(|r| r.as_stamp("t.wa")).collect()
+    }
+
+    /// The error running `src` ends with, optimised or not.
+    fn error(src: &str, optimised: bool) -> String {
+        let mut program = parse_str(src).unwrap();
+        if optimised {
+            program = optimise(program).0;
+        }
+        let ctx = Context::new(std::io::empty(), std::io::sink(), std::io::sink());
+        match Interpreter::new("t.wa", program, ctx).run() {
+            Ok(()) => String::new(),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn folds_constant_arithmetic() {
+        assert_eq!(ops("1 2 +"), ["PUSH 3"]);
+        assert_eq!(ops("1 2 + 3 * ."), ["PUSH 9", "DISPLAY"]);
+        // left for the runtime to report
+        assert_eq!(ops("0 1 /"), ["PUSH 0", "PUSH 1", "DIV"]);
+    }
+
+    #[test]
+    fn cancels_swap_swap_and_dup_drop() {
+        assert_eq!(
+            ops("1 2 swap swap . ."),
+            ["PUSH 1", "PUSH 2", "DISPLAY", "DISPLAY"]
+        );
+        assert_eq!(ops("1 dup drop ."), ["PUSH 1", "DISPLAY"]);
+    }
+
+    #[test]
+    fn resolves_constant_ifs() {
+        assert_eq!(ops("0 if 5 . end 6 ."), ["PUSH 6", "DISPLAY"]);
+        assert_eq!(
+            ops("1 if 5 . end 6 ."),
+            ["PUSH 5", "DISPLAY", "PUSH 6", "DISPLAY"]
+        );
+        // a taken block that changes the depth fails at its `end`, so it is kept
+        assert_eq!(
+            ops("1 if 5 end ."),
+            ["PUSH 1", "IF => 4", "PUSH 5", "END", "DISPLAY"]
+        );
+    }
+
+    #[test]
+    fn removes_casts_of_constants() {
+        assert_eq!(ops("3 int ."), ["PUSH 3", "DISPLAY"]);
+        assert_eq!(
+            report("3 int ."),
+            ["t.wa:1:3: removed CAST int of a constant"]
+        );
+        assert_eq!(error("1 2 int", true), error("1 2 int", false));
+        assert_eq!(
+            ops("dup bool if end"),
+            ["DUP", "CAST bool", "IF => 4", "END"]
+        );
+        assert_eq!(ops("0 bool if 5 . end 6 ."), ["PUSH 6", "DISPLAY"]);
+        assert_eq!(
+            ops("1 bool if 5 . end 6 ."),
+            ["PUSH 5", "DISPLAY", "PUSH 6", "DISPLAY"]
+        );
+    }
+
+    #[test]
+    fn recomputes_if_targets() {
+        assert_eq!(
+            ops("dup dup drop if 1 2 + . dup dup drop if 7 . end end 8 ."),
+            [
+                "DUP", "IF => 10", "PUSH 3", "DISPLAY", "DUP", "IF => 9", "PUSH 7", "DISPLAY",
+                "END", "END", "PUSH 8", "DISPLAY"
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_each_rewrite() {
+        assert_eq!(
+            report("1 2 + .\n3 dup drop swap swap\n0 if 4 . end 1 if 5 . end ."),
+            [
+                "t.wa:1:1: folded PUSH 1 PUSH 2 ADD into PUSH 3",
+                "t.wa:2:3: cancelled DUP DROP",
+                "t.wa:2:12: cancelled SWAP SWAP",
+                "t.wa:3:3: removed IF with constant false condition",
+                "t.wa:3:16: inlined IF with constant true condition",
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_the_blame_for_leftover_data() {
+        for src in [
+            "1 2 3 +",
+            "1 2 dup drop",
+            "1 2 3 + swap swap",
+            "5 0 if 1 . end",
+            "5 1 if drop 7 end",
+            // the op before the pair could still fail, so the pair stays
+            "1 2 + 3 / dup drop",
+            "0 2 / dup drop",
+            "5 dup 0 if 1 . end",
+        ] {
+            let expected = error(src, false);
+            assert!(
+                expected.contains("Unhandled") || expected.contains("division"),
+                "{src}"
+            );
+            assert_eq!(error(src, true), expected, "{src}");
+        }
+    }
+}
@@ -2,13 +2,27 @@ use anyhow::Context;
 
 use crate::{
     ops::{Op, Op1_0, Op1_2, Op2_1, Op2_2, OpIdx},
-    tokenise::Span,
+    tokenise::{Span, TokenIdx},
+    types::Type,
     utils::Chunk,
 };
 
 pub struct Program {
     pub ops: Vec<Span<Op>>,
     pub branches: Vec<Branch>,
+    /// `test` blocks, which normal runs skip and `wa test` runs each on a fresh stack.
+    pub tests: Vec<Test>,
+    /// Messages of `assert`s, indexed by [`Op::Assert`].
+    pub messages: Vec<String>,
+    /// What `assert-stack`s expect the stack to hold, bottom first, indexed by [`Op::AssertStack`].
+    pub stacks: Vec<Vec<isize>>,
+}
+
+/// A `test "name" ... end` block. Only its body may use `assert` and `assert-stack`.
+pub struct Test {
+    pub name: String,
+    pub at: TokenIdx,
+    pub program: Program,
 }
 
 pub enum Branch {
@@ -20,77 +34,108 @@ pub enum Branch {
 }
 
 pub fn parse_ops(tokens: Vec<Span<&str>>, file_name: impl AsRef<str>) -> anyhow::Result<Program> {
-    let file_name = file_name.as_ref();
+    parse_block(tokens, file_name.as_ref(), false)
+}
+
+fn parse_block(tokens: Vec<Span<&str>>, file_name: &str, in_test: bool) -> anyhow::Result<Program> {
     let mut it = crate::utils::Descend(tokens.into_iter().enumerate());
-    let mut ops = vec![];
+    let mut ops: Vec<Span<Op>> = vec![];
     let branches = vec![];
-    let parse_depth = 0;
+    let (mut tests, mut messages, mut stacks) = (vec![], vec![], vec![]);
+    // `if`s waiting for their `end`, which is where they jump to when not taken
+    let mut open = vec![];
 
     loop {
         match it.chop_opt::<1>() {
             Chunk::AllOf([Some((_, Span { idx: tok_id, token }))]) => {
-                println!("{file_name}:{tok_id}: {token}");
                 let at = tok_id.as_stamp(file_name);
-                let op = match token {
-                    s if s.len() > 2 && (&s[0..2] == "0x" || &s[0..2] == "0b") => {
-                        let base = match s.chars().skip(1).next() {
-                            Some('x') => 16,
-                            Some('b') => 2,
-                            _ => unreachable!(),
-                        };
-                        let (_, num) = s.split_at(2);
-                        Op::Push(isize::from_str_radix(num, base).with_context(|| {
-                            format!("{at}: unable to parse \"{s}\" as base-{base} numeric literal",)
-                        })?)
-                    }
-                    s if s.len() > 1
-                        && s.chars().next().filter(|&ch| ch == '-').is_some()
-                        && s.chars().skip(1).all(|ch| ch.is_digit(10)) =>
-                    {
-                        Op::Push(-s[1..].parse::<isize>().with_context(|| {
-                            format!("{at}: unable to parse \"{s}\" as negative numeric literal",)
-                        })?)
-                    }
-                    s if s.len() > 0 && s.chars().all(|ch| ch.is_digit(10)) => {
-                        Op::Push(s.parse::<isize>().with_context(|| {
-                            format!("{at}: unable to parse \"{s}\" as numeric literal",)
-                        })?)
-                    }
-                    "." => Op::Intr1_0(Op1_0::Display),
-                    "+" => Op::Intr2_1(Op2_1::Add),
-                    "-" => Op::Intr2_1(Op2_1::Sub),
-                    "*" => Op::Intr2_1(Op2_1::Mul),
-                    "/" => Op::Intr2_1(Op2_1::Div),
-                    "%" => Op::Intr2_1(Op2_1::Mod),
-                    "=" => Op::Intr2_1(Op2_1::Equ),
-                    "<" => Op::Intr2_1(Op2_1::Less),
-                    ">" => Op::Intr2_1(Op2_1::Greater),
-                    "<=" => Op::Intr2_1(Op2_1::LessEqu),
-                    ">=" => Op::Intr2_1(Op2_1::GreaterEqu),
-                    "/%" => Op::Intr2_2(Op2_2::DivMod),
-                    "drop" => Op::Intr1_0(Op1_0::Drop),
-                    "dup" => Op::Intr1_2(Op1_2::Duplicate),
-                    "swap" => Op::Intr2_2(Op2_2::Swap),
-                    "if" => {
-                        let mut t = it.clone();
-                        let end_addr = loop {
-                            match t.chop_opt::<1>() {
-                                Chunk::AllOf([Some((e_id, Span { idx: _, token }))]) => match token
-                                {
-                                    "end" | "else" => break e_id,
-                                    _ => {}
-                                },
-                                Chunk::NoneOf => anyhow::bail!(
-                                    "{at}: Unbalanced IF expression",
-                                    at = tok_id.as_stamp(&file_name)
-                                ),
-                                _ => unreachable!("Chunk::<1, I>::SomeOf??"),
+                let op = match (literal(token, &at), intrinsic(token)) {
+                    (Some(n), _) => Op::Push(n?),
+                    (None, Some(op)) => op,
+                    (None, None) => match token {
+                        "if" => {
+                            let mut t = it.clone();
+                            let mut depth = 0;
+                            loop {
+                                match t.chop_opt::<1>() {
+                                    Chunk::AllOf([Some((_, Span { idx: _, token }))]) => {
+                                        match token {
+                                            "if" | "test" => depth += 1,
+                                            "end" | "else" if depth == 0 => break,
+                                            "end" => depth -= 1,
+                                            _ => {}
+                                        }
+                                    }
+                                    Chunk::NoneOf => anyhow::bail!(
+                                        "{at}: Unbalanced IF expression",
+                                        at = tok_id.as_stamp(file_name)
+                                    ),
+                                    _ => unreachable!("Chunk::<1, I>::SomeOf??"),
+                                }
+                            }
+                            open.push(ops.len());
+                            // patched once its `end` is parsed
+                            Op::If(OpIdx::new(0))
+                        }
+                        "end" => {
+                            if let Some(i) = open.pop() {
+                                ops[i].token = Op::If(OpIdx::new(ops.len() + 1));
+                            }
+                            Op::End
+                        }
+                        "test" if in_test => anyhow::bail!("{at}: test blocks cannot be nested"),
+                        "test" if !open.is_empty() => {
+                            anyhow::bail!("{at}: test blocks must be at the top level")
+                        }
+                        "test" => {
+                            let name = match it.chop_opt::<1>() {
+                                Chunk::AllOf([Some((_, name))]) => string(name, file_name)?,
+                                _ => anyhow::bail!("{at}: expected the name of the test"),
+                            };
+                            let mut body = vec![];
+                            let mut depth = 0;
+                            loop {
+                                match it.chop_opt::<1>() {
+                                    Chunk::AllOf([Some((_, tok))]) => {
+                                        match tok.token {
+                                            "if" | "test" => depth += 1,
+                                            "end" if depth == 0 => break,
+                                            "end" => depth -= 1,
+                                            _ => {}
+                                        }
+                                        body.push(tok);
+                                    }
+                                    Chunk::NoneOf => anyhow::bail!("{at}: Unbalanced TEST block"),
+                                    _ => unreachable!("Chunk::<1, I>::SomeOf??"),
+                                }
+                            }
+                            tests.push(Test {
+                                name,
+                                at: tok_id,
+                                program: parse_block(body, file_name, true)?,
+                            });
+                            continue;
+                        }
+                        "assert" | "assert-stack" if !in_test => {
+                            anyhow::bail!("{at}: \"{token}\" can only be used inside test blocks")
+                        }
+                        "assert" => match it.peek_opt::<1>() {
+                            Chunk::AllOf([Some((_, msg))]) if msg.token.starts_with('"') => {
+                                it.chop_opt::<1>();
+                                messages.push(string(msg, file_name)?);
+                                Op::Assert(Some(messages.len() - 1))
                             }
-                        };
-                        Op::If(OpIdx::new(end_addr + 1))
-                    }
-                    "end" => Op::End,
-                    t => anyhow::bail!("{at}: unknown token \"{t}\""),
+                            _ => Op::Assert(None),
+                        },
+                        "assert-stack" => {
+                            stacks.push(expected_stack(&mut it, &at, file_name)?);
+                            Op::AssertStack(stacks.len() - 1)
+                        }
+                        s if s.starts_with('"') => anyhow::bail!(
+                            "{at}: string literals can only name a test or follow \"assert\""
+                        ),
+                        t => anyhow::bail!("{at}: unknown token \"{t}\""),
+                    },
                 };
                 ops.push(Span {
                     idx: tok_id,
@@ -101,5 +146,124 @@ pub fn parse_ops(tokens: Vec<Span<&str>>, file_name: impl AsRef<str>) -> anyhow:
             _ => unreachable!(),
         }
     }
-    Ok(Program { ops, branches })
+    Ok(Program {
+        ops,
+        branches,
+        tests,
+        messages,
+        stacks,
+    })
+}
+
+/// The op a word that needs nothing around it stands for, if `token` is one.
+pub fn intrinsic(token: &str) -> Option<Op> {
+    let op = match token {
+        "." => Op::Intr1_0(Op1_0::Display),
+        "+" => Op::Intr2_1(Op2_1::Add),
+        "-" => Op::Intr2_1(Op2_1::Sub),
+        "*" => Op::Intr2_1(Op2_1::Mul),
+        "/" => Op::Intr2_1(Op2_1::Div),
+        "%" => Op::Intr2_1(Op2_1::Mod),
+        "=" => Op::Intr2_1(Op2_1::Equ),
+        "<" => Op::Intr2_1(Op2_1::Less),
+        ">" => Op::Intr2_1(Op2_1::Greater),
+        "<=" => Op::Intr2_1(Op2_1::LessEqu),
+        ">=" => Op::Intr2_1(Op2_1::GreaterEqu),
+        "/%" => Op::Intr2_2(Op2_2::DivMod),
+        "drop" => Op::Intr1_0(Op1_0::Drop),
+        "dup" => Op::Intr1_2(Op1_2::Duplicate),
+        "swap" => Op::Intr2_2(Op2_2::Swap),
+        "int" => Op::Cast(Type::Int),
+        "bool" => Op::Cast(Type::Bool),
+        _ => return None,
+    };
+    Some(op)
+}
+
+/// Parses `s` if it is a numeric literal, `at` being where it appears.
+pub fn literal(s: &str, at: &str) -> Option<anyhow::Result<isize>> {
+    let n = match s {
+        s if s.len() > 2 && (&s[0..2] == "0x" || &s[0..2] == "0b") => {
+            let base = match s.chars().nth(1) {
+                Some('x') => 16,
+                Some('b') => 2,
+                _ => unreachable!(),
+            };
+            let (_, num) = s.split_at(2);
+            isize::from_str_radix(num, base).with_context(|| {
+                format!("{at}: unable to parse \"{s}\" as base-{base} numeric literal",)
+            })
+        }
+        s if s.len() > 1
+            && s.chars().next().filter(|&ch| ch == '-').is_some()
+            && s.chars().skip(1).all(|ch| ch.is_ascii_digit()) =>
+        {
+            s[1..].parse::<isize>().map(|n| -n).with_context(|| {
+                format!("{at}: unable to parse \"{s}\" as negative numeric literal",)
+            })
+        }
+        s if !s.is_empty() && s.chars().all(|ch| ch.is_ascii_digit()) => s
+            .parse::<isize>()
+            .with_context(|| format!("{at}: unable to parse \"{s}\" as numeric literal",)),
+        _ => return None,
+    };
+    Some(n)
+}
+
+/// The contents of a `"..."` token.
+fn string(Span { idx, token }: Span<&str>, file_name: &str) -> anyhow::Result<String> {
+    let at = idx.as_stamp(file_name);
+    match token.strip_prefix('"') {
+        Some(s) => match s.strip_suffix('"') {
+            Some(s) => Ok(s.to_string()),
+            None => anyhow::bail!("{at}: unterminated string literal"),
+        },
+        None => anyhow::bail!("{at}: expected a string literal, got \"{token}\""),
+    }
+}
+
+/// The `[a b ...]` following an `assert-stack` at `at`.
+fn expected_stack<'a>(
+    it: &mut crate::utils::Descend<
+        (usize, Span<&'a str>),
+        impl Iterator<Item = (usize, Span<&'a str>)> + Clone,
+    >,
+    at: &str,
+    file_name: &str,
+) -> anyhow::Result<Vec<isize>> {
+    let mut expected = vec![];
+    let mut first = true;
+    loop {
+        let Chunk::AllOf([Some((_, Span { idx, token }))]) = it.chop_opt::<1>() else {
+            anyhow::bail!("{at}: unclosed stack, expected \"]\"");
+        };
+        let mut item = token;
+        if first {
+            item = item.strip_prefix('[').with_context(|| {
+                format!("{at}: expected the stack to assert, like [1 2 3], got \"{token}\"")
+            })?;
+            first = false;
+        }
+        let last = item.ends_with(']');
+        let item = item.strip_suffix(']').unwrap_or(item);
+        if !item.is_empty() {
+            let at = idx.as_stamp(file_name);
+            match literal(item, &at) {
+                Some(n) => expected.push(n?),
+                None => anyhow::bail!("{at}: expected a numeric literal, got \"{item}\""),
+            }
+        }
+        if last {
+            return Ok(expected);
+        }
+    }
+}
+
+/// Parses `src` as the file `t.wa`.
+#[cfg(test)]
+pub fn parse_str(src: &str) -> anyhow::Result<Program> {
+    parse_ops(
+        crate::tokenise::Tokeniser::new(src.as_bytes()).collect(),
+        "t.wa",
+    )
 }
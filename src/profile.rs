@@ -0,0 +1,159 @@
+//! Execution counts for `interp --profile`: how often each op ran, and from that how often each
+//! source line did.
+
+use std::io::Write;
+
+use crate::{ops::Op, tokenise::Span};
+
+/// How many rows each table printed by [`Profile::hot_spots`] has at most.
+const HOT_SPOTS: usize = 10;
+
+#[derive(Debug, Clone)]
+pub struct Profile {
+    ops: Vec<Span<Op>>,
+    counts: Vec<usize>,
+}
+
+impl Profile {
+    pub fn new(ops: &[Span<Op>]) -> Self {
+        Self {
+            ops: ops.to_vec(),
+            counts: vec![0; ops.len()],
+        }
+    }
+
+    /// Counts one execution of the op at `ip`.
+    pub fn record(&mut self, ip: usize) {
+        self.counts[ip] += 1;
+    }
+
+    pub fn total(&self) -> usize {
+        self.counts.iter().sum()
+    }
+
+    /// Executions of each source line that ran at all, by row.
+    pub fn lines(&self) -> Vec<(usize, usize)> {
+        let mut lines = std::collections::BTreeMap::new();
+        for (op, &count) in self.ops.iter().zip(&self.counts) {
+            *lines.entry(op.idx.row).or_insert(0) += count;
+        }
+        lines.into_iter().filter(|&(_, n)| n > 0).collect()
+    }
+
+    /// Writes the lines and ops that ran most, hottest first. `src` is the program's source, to
+    /// show each line as it was written.
+    pub fn hot_spots(
+        &self,
+        file_name: &str,
+        src: &str,
+        out: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let total = self.total();
+        let share = |n: usize| n as f64 * 100.0 / total.max(1) as f64;
+        writeln!(out, "profile: {total} op(s) executed")?;
+
+        let mut lines = self.lines();
+        lines.sort_by_key(|&(row, n)| (std::cmp::Reverse(n), row));
+        let text = src.lines().collect::<Vec<_>>();
+        writeln!(out, "hottest lines:")?;
+        writeln!(out, "{:>12} {:>7} {:>6}", "count", "share", "line")?;
+        for &(row, n) in lines.iter().take(HOT_SPOTS) {
+            let code = text.get(row).map_or("", |l| l.trim());
+            writeln!(out, "{n:>12} {:>6.1}% {:>6} | {code}", share(n), row + 1)?;
+        }
+
+        let mut ops = self
+            .ops
+            .iter()
+            .zip(&self.counts)
+            .filter(|&(_, &n)| n > 0)
+            .collect::<Vec<_>>();
+        ops.sort_by_key(|&(op, &n)| (std::cmp::Reverse(n), op.idx.row, op.idx.col));
+        writeln!(out, "hottest ops:")?;
+        writeln!(out, "{:>12} {:>7}  op", "count", "share")?;
+        for (op, &n) in ops.into_iter().take(HOT_SPOTS) {
+            let at = op.idx.as_stamp(file_name);
+            writeln!(out, "{n:>12} {:>6.1}%  {at}: {}", share(n), op.token)?;
+        }
+        Ok(())
+    }
+
+    /// The counts as collapsed stacks, `file;line:<row>;<op>@<row>:<col> <count>`, the input
+    /// format of flamegraph tools.
+    pub fn collapsed(&self, file_name: &str) -> String {
+        let mut out = String::new();
+        for (Span { idx, token }, &n) in self.ops.iter().zip(&self.counts) {
+            if n == 0 {
+                continue;
+            }
+            // the mnemonic, the position tells ops apart
+            let op = token.to_string();
+            let op = op.split_whitespace().next().unwrap_or_default();
+            out.push_str(&format!(
+                "{file_name};line:{};{op}@{idx} {n}\n",
+                idx.row + 1
+            ));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse_str;
+
+    const SRC: &str = "1 2 +\n  .\n5 drop\n";
+
+    /// A profile of [`SRC`] with each op at index `i` run `counts[i]` times.
+    fn profile(counts: [usize; 6]) -> Profile {
+        let mut profile = Profile::new(&parse_str(SRC).unwrap().ops);
+        for (ip, n) in counts.into_iter().enumerate() {
+            (0..n).for_each(|_| profile.record(ip));
+        }
+        profile
+    }
+
+    #[test]
+    fn adds_up_each_line() {
+        let profile = profile([1, 1, 3, 5, 0, 0]);
+        assert_eq!(profile.total(), 10);
+        // the last line never ran, so it is left out
+        assert_eq!(profile.lines(), [(0, 5), (1, 5)]);
+    }
+
+    #[test]
+    fn sorts_hot_spots_by_count_then_position() {
+        let mut out = vec![];
+        profile([1, 1, 2, 5, 1, 0])
+            .hot_spots("t.wa", SRC, &mut out)
+            .unwrap();
+        let expected = "\
+profile: 10 op(s) executed
+hottest lines:
+       count   share   line
+           5   50.0%      2 | .
+           4   40.0%      1 | 1 2 +
+           1   10.0%      3 | 5 drop
+hottest ops:
+       count   share  op
+           5   50.0%  t.wa:2:3: DISPLAY
+           2   20.0%  t.wa:1:5: ADD
+           1   10.0%  t.wa:1:1: PUSH 1
+           1   10.0%  t.wa:1:3: PUSH 2
+           1   10.0%  t.wa:3:1: PUSH 5
+";
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+
+    #[test]
+    fn collapses_stacks_into_file_line_and_op() {
+        let expected = "\
+t.wa;line:1;PUSH@1:1 1
+t.wa;line:1;PUSH@1:3 1
+t.wa;line:1;ADD@1:5 3
+t.wa;line:2;DISPLAY@2:3 5
+";
+        assert_eq!(profile([1, 1, 3, 5, 0, 0]).collapsed("t.wa"), expected);
+    }
+}
@@ -0,0 +1,173 @@
+use crate::{
+    ops::Op,
+    tokenise::{Span, TokenIdx},
+};
+
+const MAGIC: &[u8; 4] = b"WASN";
+const VERSION: u8 = 1;
+
+/// The complete state of an in-progress run, detached from the `Program` it belongs to.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub fingerprint: u64,
+    pub ip: usize,
+    pub steps: usize,
+    pub prev_tok_id: Option<TokenIdx>,
+    pub stack: Vec<isize>,
+    pub jmp_check: Vec<usize>,
+}
+
+/// FNV-1a over the ops and their source positions, stable across processes and toolchains.
+pub fn fingerprint(ops: &[Span<Op>]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for Span { idx, token } in ops {
+        for b in format!("{idx}:{token};").bytes() {
+            h ^= b as u64;
+            h = h.wrapping_mul(0x100000001b3);
+        }
+    }
+    h
+}
+
+impl Snapshot {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        let mut put = |n: u64| out.extend_from_slice(&n.to_le_bytes());
+        put(self.fingerprint);
+        put(self.ip as u64);
+        put(self.steps as u64);
+        match self.prev_tok_id {
+            Some(TokenIdx { row, col }) => {
+                put(1);
+                put(row as u64);
+                put(col as u64);
+            }
+            None => put(0),
+        }
+        put(self.stack.len() as u64);
+        for &n in &self.stack {
+            put(n as u64);
+        }
+        put(self.jmp_check.len() as u64);
+        for &n in &self.jmp_check {
+            put(n as u64);
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let Some(rest) = bytes.strip_prefix(MAGIC) else {
+            anyhow::bail!("not a wa snapshot");
+        };
+        let Some((&version, rest)) = rest.split_first() else {
+            anyhow::bail!("truncated snapshot");
+        };
+        if version != VERSION {
+            anyhow::bail!("unsupported snapshot version {version}, expected {VERSION}");
+        }
+        let mut words = rest.chunks(8);
+        let mut get = || -> anyhow::Result<u64> {
+            match words.next() {
+                Some(w) if w.len() == 8 => Ok(u64::from_le_bytes(w.try_into()?)),
+                _ => anyhow::bail!("truncated snapshot"),
+            }
+        };
+        let fingerprint = get()?;
+        let ip = get()? as usize;
+        let steps = get()? as usize;
+        let prev_tok_id = match get()? {
+            0 => None,
+            _ => Some(TokenIdx {
+                row: get()? as usize,
+                col: get()? as usize,
+            }),
+        };
+        let stack = (0..get()?)
+            .map(|_| get().map(|n| n as isize))
+            .collect::<anyhow::Result<_>>()?;
+        let jmp_check = (0..get()?)
+            .map(|_| get().map(|n| n as usize))
+            .collect::<anyhow::Result<_>>()?;
+        if words.next().is_some() {
+            anyhow::bail!("trailing data after snapshot");
+        }
+        Ok(Self {
+            fingerprint,
+            ip,
+            steps,
+            prev_tok_id,
+            stack,
+            jmp_check,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{context::Context, interp::Interpreter, parse::parse_str};
+
+    const SRC: &str = "1 2 + . 3 4 < if 5 6 * . end 7 dup * .";
+
+    fn run(interp: &mut Interpreter) {
+        while interp.step().unwrap() {}
+    }
+
+    fn interpreter<'a>(stdout: &'a mut Vec<u8>, snapshot: Option<Snapshot>) -> Interpreter<'a> {
+        let prog = parse_str(SRC).unwrap();
+        let ctx = Context::new(std::io::empty(), stdout, std::io::sink());
+        match snapshot {
+            Some(snapshot) => Interpreter::resume("t.wa", prog, ctx, snapshot).unwrap(),
+            None => Interpreter::new("t.wa", prog, ctx),
+        }
+    }
+
+    #[test]
+    fn round_trips_through_bytes() {
+        let snapshot = Snapshot {
+            fingerprint: 0xdead_beef,
+            ip: 7,
+            steps: 12,
+            prev_tok_id: Some(TokenIdx { row: 3, col: 9 }),
+            stack: vec![-1, 0, isize::MAX],
+            jmp_check: vec![2, 4],
+        };
+        let bytes = snapshot.to_bytes();
+        let read = Snapshot::from_bytes(&bytes).unwrap();
+        assert_eq!(read.to_bytes(), bytes);
+        assert_eq!(read.stack, snapshot.stack);
+        assert_eq!(read.jmp_check, snapshot.jmp_check);
+    }
+
+    #[test]
+    fn rejects_truncated_and_trailing_data() {
+        let bytes = interpreter(&mut vec![], None).snapshot().to_bytes();
+        assert!(Snapshot::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Snapshot::from_bytes(&[&bytes[..], &[0; 8]].concat()).is_err());
+        assert!(Snapshot::from_bytes(b"WASM").is_err());
+    }
+
+    #[test]
+    fn resuming_finishes_the_run_the_same_way() {
+        let mut whole = vec![];
+        run(&mut interpreter(&mut whole, None));
+
+        for pause in 1..8 {
+            let (mut before, mut after) = (vec![], vec![]);
+            let mut interp = interpreter(&mut before, None);
+            for _ in 0..pause {
+                interp.step().unwrap();
+            }
+            let bytes = interp.snapshot().to_bytes();
+            drop(interp);
+            let snapshot = Snapshot::from_bytes(&bytes).unwrap();
+            run(&mut interpreter(&mut after, Some(snapshot)));
+            assert_eq!(
+                [before, after].concat(),
+                whole,
+                "paused after {pause} op(s)"
+            );
+        }
+    }
+}
@@ -1,23 +1,36 @@
-use crate::tokenise::Span;
+use crate::{context::Context, tokenise::Span};
 
 #[derive(Debug)]
 pub struct Stack<T>(Vec<T>);
 
-pub type VirtStackOp<const IN: usize, const OUT: usize, T = isize> = fn([T; IN]) -> [T; OUT];
+pub type VirtStackOp<const IN: usize, const OUT: usize, T = isize> =
+    fn(&mut Context, [T; IN]) -> anyhow::Result<[T; OUT]>;
 
 impl<T: std::fmt::Display> std::fmt::Display for Stack<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "-----\\/ STACK\n")?;
+        writeln!(f, "-----\\/ STACK")?;
         for (i, n) in self.0.iter().rev().enumerate() {
-            write!(f, "@{:5}|= {}\n", i, n)?;
+            writeln!(f, "@{:5}|= {}", i, n)?;
         }
-        write!(f, "-----/\\\n")?;
+        writeln!(f, "-----/\\")?;
         write!(f, "{}", 1)
     }
 }
 
 pub trait StackOp<const IN: usize, const OUT: usize, T> {
-    fn op(self, input: [T; IN]) -> [T; OUT];
+    fn op(self, ctx: &mut Context, input: [T; IN]) -> anyhow::Result<[T; OUT]>;
+}
+
+impl<T> From<Vec<T>> for Stack<T> {
+    fn from(values: Vec<T>) -> Self {
+        Self(values)
+    }
+}
+
+impl<T: Copy + Default> Default for Stack<T> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<T: Copy + Default> Stack<T> {
@@ -29,6 +42,15 @@ impl<T: Copy + Default> Stack<T> {
         self.0.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The elements on the stack, bottom first.
+    pub fn as_slice(&self) -> &[T] {
+        &self.0
+    }
+
     pub fn push<const N: usize>(&mut self, values: [T; N]) {
         for i in 0..N {
             self.0.push(unsafe { *values.get_unchecked(N - i - 1) });
@@ -43,11 +65,13 @@ impl<T: Copy + Default> Stack<T> {
         for i in 0..N {
             let ret = unsafe { ret.get_unchecked_mut(i) };
 
-            *ret = self.0.pop().ok_or(anyhow::anyhow!(
-                "{}: Stack Underflow, expected at least {} element(s), got {i}",
-                idx.as_stamp(token),
-                N,
-            ))?;
+            *ret = self.0.pop().ok_or_else(|| {
+                anyhow::anyhow!(
+                    "{}: Stack Underflow, expected at least {} element(s), got {i}",
+                    idx.as_stamp(token),
+                    N,
+                )
+            })?;
         }
         Ok(ret)
     }
@@ -55,19 +79,23 @@ impl<T: Copy + Default> Stack<T> {
     pub fn run<const IN: usize, const OUT: usize>(
         &mut self,
         stack_op: impl StackOp<IN, OUT, T>,
+        ctx: &mut Context,
         at: Span<&str>,
     ) -> anyhow::Result<()> {
         let s = self.pop::<IN>(at)?;
-        self.push(stack_op.op(s));
+        let out = stack_op
+            .op(ctx, s)
+            .map_err(|e| anyhow::anyhow!("{}: {e}", at.idx.as_stamp(at.token)))?;
+        self.push(out);
         Ok(())
     }
 }
 
 impl<const IN: usize, const OUT: usize, T, F> StackOp<IN, OUT, T> for F
 where
-    F: FnOnce([T; IN]) -> [T; OUT],
+    F: FnOnce(&mut Context, [T; IN]) -> anyhow::Result<[T; OUT]>,
 {
-    fn op(self, input: [T; IN]) -> [T; OUT] {
-        (self)(input)
+    fn op(self, ctx: &mut Context, input: [T; IN]) -> anyhow::Result<[T; OUT]> {
+        (self)(ctx, input)
     }
 }
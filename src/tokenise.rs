@@ -32,7 +32,28 @@ impl<'a> Iterator for Tokeniser<'a> {
 
     fn next(&mut self) -> Option<Self::Item> {
         self.trim_whitespace()?;
+        // comments run from `//` to the end of the line
+        while self.file_contents.starts_with(b"//") {
+            self.split_by_predicate(|ch| ch != b'\n');
+            self.trim_whitespace()?;
+        }
         let t = self.cur_tok_id;
+        // string literals keep their quotes and may hold whitespace, but not a newline
+        if self.file_contents.starts_with(b"\"") {
+            let rest = &self.file_contents[1..];
+            let len = match rest.iter().position(|&ch| ch == b'"' || ch == b'\n') {
+                Some(i) if rest[i] == b'"' => i + 2,
+                Some(i) => i + 1,
+                None => self.file_contents.len(),
+            };
+            let (token, rest) = self.file_contents.split_at(len);
+            self.file_contents = rest;
+            self.cur_tok_id.col += len;
+            return Some(Span {
+                idx: t,
+                token: std::str::from_utf8(token).expect("Non Utf-8 chars"),
+            });
+        }
         self.split_by_predicate(|ch| !ch.is_ascii_whitespace())
             .map(|token| Span {
                 idx: t,
@@ -41,6 +62,41 @@ impl<'a> Iterator for Tokeniser<'a> {
     }
 }
 
+/// A piece of source as [`Lossless`] sees it. Concatenating every piece gives the source back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Piece<'a> {
+    Token(&'a str),
+    /// From `//` up to, but not including, the end of the line.
+    Comment(&'a str),
+    Whitespace(&'a str),
+}
+
+/// A [`Tokeniser`] that keeps the whitespace and comments between tokens.
+#[derive(Debug, Clone)]
+pub struct Lossless<'a>(Tokeniser<'a>);
+
+impl<'a> Iterator for Lossless<'a> {
+    type Item = Span<Piece<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let t = self.0.cur_tok_id;
+        let text = |bytes| std::str::from_utf8(bytes).expect("Non Utf-8 chars");
+        let piece = match self.0.file_contents.first()? {
+            ch if ch.is_ascii_whitespace() => Piece::Whitespace(text(
+                self.0.split_by_predicate(|ch| ch.is_ascii_whitespace())?,
+            )),
+            _ if self.0.file_contents.starts_with(b"//") => {
+                Piece::Comment(text(self.0.split_by_predicate(|ch| ch != b'\n')?))
+            }
+            _ => Piece::Token(self.0.next()?.token),
+        };
+        Some(Span {
+            idx: t,
+            token: piece,
+        })
+    }
+}
+
 impl<'a> Tokeniser<'a> {
     pub fn new(file_contents: &'a [u8]) -> Self {
         Self {
@@ -49,6 +105,11 @@ impl<'a> Tokeniser<'a> {
         }
     }
 
+    /// Keeps whitespace and comments instead of skipping them.
+    pub fn lossless(self) -> Lossless<'a> {
+        Lossless(self)
+    }
+
     pub fn trim_whitespace(&mut self) -> Option<()> {
         let mut i = 0;
         let l = self.file_contents.len();
@@ -58,7 +119,6 @@ impl<'a> Tokeniser<'a> {
                 c if c.is_ascii_whitespace() => self.cur_tok_id.tick_col(),
                 _ => break,
             }
-            println!("{}", self.cur_tok_id);
             i += 1;
         }
         self.file_contents = self.file_contents.split_at(i).1;
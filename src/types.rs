@@ -0,0 +1,221 @@
+//! Static types of the values on the stack, checked before a program runs.
+//!
+//! Every value is an `int` or a `bool`. Literals and arithmetic make ints and comparisons make
+//! bools; arithmetic and ordering take ints, `=` takes two values of the same type, and `if` and
+//! `assert` take a bool. The cast words `int` and `bool` change the type of the top value but
+//! not the value itself. `bool` rejects a literal that is neither 0 nor 1, but a computed value
+//! is only known at runtime, so `if` still fails there on a bool that is neither 0 nor 1. A
+//! block must leave the types on the stack as it found them, since running it or skipping it
+//! has to end the same way.
+
+use std::io::Write;
+
+use crate::{
+    ops::{Op, Op1_0, Op1_2, Op2_1, Op2_2},
+    parse::Program,
+    tokenise::{Span, TokenIdx},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Type {
+    Int,
+    Bool,
+}
+
+impl std::fmt::Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Type::Int => write!(f, "int"),
+            Type::Bool => write!(f, "bool"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TypeError {
+    pub at: TokenIdx,
+    pub message: String,
+}
+
+/// Every type error in `program` and its test blocks, in source order.
+pub fn check(program: &Program) -> Vec<TypeError> {
+    let mut errors = vec![];
+    let tests = program.tests.iter().map(|t| &t.program.ops);
+    for ops in std::iter::once(&program.ops).chain(tests) {
+        check_ops(ops, &mut errors);
+    }
+    errors.sort_by_key(|e| (e.at.row, e.at.col));
+    errors
+}
+
+/// Writes the type errors in `program` to `out`, failing if there are any.
+pub fn report(file_name: &str, program: &Program, out: &mut impl Write) -> anyhow::Result<()> {
+    let errors = check(program);
+    for TypeError { at, message } in &errors {
+        writeln!(out, "error: {}: {message}", at.as_stamp(file_name))?;
+    }
+    if !errors.is_empty() {
+        anyhow::bail!("{file_name}: {} type error(s)", errors.len());
+    }
+    Ok(())
+}
+
+/// A value on the stack, with the literal it came from if it is one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Slot {
+    ty: Type,
+    literal: Option<isize>,
+}
+
+impl From<Type> for Slot {
+    fn from(ty: Type) -> Self {
+        Self { ty, literal: None }
+    }
+}
+
+fn check_ops(ops: &[Span<Op>], errors: &mut Vec<TypeError>) {
+    let mut stack: Vec<Slot> = vec![];
+    // the stack each open `if` would leave by skipping its block
+    let mut blocks: Vec<Vec<Slot>> = vec![];
+    let pop = |stack: &mut Vec<Slot>| stack.pop().unwrap().ty;
+    for &Span { idx: at, token: op } in ops {
+        // the stack is too shallow here on every run, which is `check`'s to report, and nothing
+        // is known about what follows
+        if stack.len() < op.arity().0 {
+            return;
+        }
+        let mut error = |message| errors.push(TypeError { at, message });
+        match op {
+            Op::Push(n) => stack.push(Slot {
+                ty: Type::Int,
+                literal: Some(n),
+            }),
+            Op::Intr1_0(Op1_0::Display | Op1_0::Drop) => {
+                stack.pop();
+            }
+            Op::Intr1_2(Op1_2::Duplicate) => stack.push(stack[stack.len() - 1]),
+            Op::Intr2_2(Op2_2::Swap) => {
+                let n = stack.len();
+                stack.swap(n - 1, n - 2);
+            }
+            Op::Intr2_1(Op2_1::Equ) => {
+                let (t, t1) = (pop(&mut stack), pop(&mut stack));
+                if t != t1 {
+                    error(format!(
+                        "{op} compares values of one type, got {t1} and {t}"
+                    ));
+                }
+                stack.push(Type::Bool.into());
+            }
+            Op::Intr2_1(op_id) => {
+                let (t, t1) = (pop(&mut stack), pop(&mut stack));
+                if (t, t1) != (Type::Int, Type::Int) {
+                    error(format!("{op} expects int and int, got {t1} and {t}"));
+                }
+                let comparison = matches!(
+                    op_id,
+                    Op2_1::Less | Op2_1::Greater | Op2_1::LessEqu | Op2_1::GreaterEqu
+                );
+                stack.push(if comparison { Type::Bool } else { Type::Int }.into());
+            }
+            Op::Intr2_2(Op2_2::DivMod) => {
+                let (t, t1) = (pop(&mut stack), pop(&mut stack));
+                if (t, t1) != (Type::Int, Type::Int) {
+                    error(format!("{op} expects int and int, got {t1} and {t}"));
+                }
+                stack.extend([Slot::from(Type::Int); 2]);
+            }
+            Op::Cast(to) => {
+                let Slot { literal, .. } = stack.pop().unwrap();
+                match literal {
+                    Some(n @ (..=-1 | 2..)) if to == Type::Bool => {
+                        error(format!("`bool` casts 0 or 1, got the literal {n}"));
+                    }
+                    _ => {}
+                }
+                stack.push(Slot { ty: to, literal });
+            }
+            Op::Assert(_) => {
+                if let t @ Type::Int = pop(&mut stack) {
+                    error(format!("`assert` expects bool, got {t}"));
+                }
+            }
+            Op::AssertStack(_) => {}
+            Op::If(_) => {
+                if let t @ Type::Int = pop(&mut stack) {
+                    error(format!(
+                        "`if` expects bool, got {t}, cast it with `bool` if it is 0 or 1"
+                    ));
+                }
+                blocks.push(stack.clone());
+            }
+            Op::End => {
+                let Some(skipped) = blocks.pop() else {
+                    continue;
+                };
+                // a block that changes the depth fails at its `end` instead
+                let (found, left) = (types(&skipped), types(&stack));
+                if found.len() == left.len() && found != left {
+                    error(format!(
+                        "`if` block leaves {} where it found {}",
+                        show(&left),
+                        show(&found)
+                    ));
+                }
+                // a literal survives the block only if the block left it alone
+                stack = match skipped.len() == stack.len() {
+                    true => skipped
+                        .iter()
+                        .zip(&stack)
+                        .map(|(&s, t)| Slot {
+                            literal: s.literal.filter(|_| s.literal == t.literal),
+                            ..s
+                        })
+                        .collect(),
+                    false => skipped,
+                };
+            }
+        }
+    }
+}
+
+fn types(stack: &[Slot]) -> Vec<Type> {
+    stack.iter().map(|s| s.ty).collect()
+}
+
+/// `[a b ...]`, bottom first, the way `assert-stack` spells a stack.
+fn show(stack: &[Type]) -> String {
+    let items = stack.iter().map(Type::to_string).collect::<Vec<_>>();
+    format!("[{}]", items.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse_str;
+
+    fn errors(src: &str) -> Vec<String> {
+        let program = parse_str(src).unwrap();
+        check(&program).into_iter().map(|e| e.message).collect()
+    }
+
+    #[test]
+    fn bool_accepts_zero_one_and_computed_values() {
+        assert!(errors("1 bool if end 0 bool if end").is_empty());
+        assert!(errors("3 dup - bool if end").is_empty());
+    }
+
+    #[test]
+    fn bool_rejects_literals_that_are_not_zero_or_one() {
+        assert_eq!(
+            errors("5 bool if end"),
+            ["`bool` casts 0 or 1, got the literal 5"]
+        );
+        assert_eq!(errors("-1 2 swap drop bool drop").len(), 1);
+    }
+
+    #[test]
+    fn if_rejects_ints() {
+        assert_eq!(errors("1 if end").len(), 1);
+    }
+}
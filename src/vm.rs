@@ -0,0 +1,281 @@
+use std::io::Write;
+
+use crate::{
+    check::{check, Fault},
+    context::Context,
+    ops::{Op, Op1_0, Op1_2, Op2_1, Op2_2},
+    parse::Program,
+    tokenise::{Span, TokenIdx},
+};
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy)]
+enum Opcode {
+    /// followed by the `i64` to push
+    Push,
+    Display,
+    Drop,
+    Dup,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Equ,
+    Less,
+    Greater,
+    LessEqu,
+    GreaterEqu,
+    DivMod,
+    Swap,
+    /// followed by the `u32` code offset to jump to when the condition is false
+    If,
+    /// followed by the `u32` index into `Bytecode::faults`
+    Trap,
+    /// followed by the `u32` index of the op blamed for leftover stack data
+    Halt,
+    Unreachable,
+}
+
+const OPCODES: [Opcode; 20] = [
+    Opcode::Push,
+    Opcode::Display,
+    Opcode::Drop,
+    Opcode::Dup,
+    Opcode::Add,
+    Opcode::Sub,
+    Opcode::Mul,
+    Opcode::Div,
+    Opcode::Mod,
+    Opcode::Equ,
+    Opcode::Less,
+    Opcode::Greater,
+    Opcode::LessEqu,
+    Opcode::GreaterEqu,
+    Opcode::DivMod,
+    Opcode::Swap,
+    Opcode::If,
+    Opcode::Trap,
+    Opcode::Halt,
+    Opcode::Unreachable,
+];
+
+/// A `Program` lowered to a linear encoding of opcode bytes with inline operands.
+///
+/// Every stack access has been proven in bounds by [`check`], so the run loop touches the stack
+/// without underflow checks; ops that would fault are replaced by `Trap`. Spans live in side
+/// tables that are only read when reporting an error.
+pub struct Bytecode {
+    file_name: String,
+    code: Vec<u8>,
+    max_depth: usize,
+    /// code offset of each op, ascending
+    offsets: Vec<u32>,
+    spans: Vec<TokenIdx>,
+    faults: Vec<(usize, Fault)>,
+}
+
+impl Bytecode {
+    pub fn new(file_name: impl AsRef<str>, Program { ops, .. }: &Program) -> Self {
+        let checked = check(ops);
+        let mut code = vec![];
+        let mut offsets = Vec::with_capacity(ops.len());
+        let mut faults = vec![];
+        let mut patches = vec![];
+
+        for (i, Span { token: op, .. }) in ops.iter().enumerate() {
+            offsets.push(code.len() as u32);
+            if let Some(fault) = checked.faults[i] {
+                code.push(Opcode::Trap as u8);
+                code.extend_from_slice(&(faults.len() as u32).to_le_bytes());
+                faults.push((i, fault));
+                continue;
+            }
+            if checked.depths[i].is_none() {
+                code.push(Opcode::Unreachable as u8);
+                continue;
+            }
+            let opcode = match op {
+                Op::Push(n) => {
+                    code.push(Opcode::Push as u8);
+                    code.extend_from_slice(&(*n as i64).to_le_bytes());
+                    continue;
+                }
+                Op::If(target) => {
+                    code.push(Opcode::If as u8);
+                    patches.push((code.len(), target.0, i));
+                    code.extend_from_slice(&[0; 4]);
+                    continue;
+                }
+                Op::End | Op::Cast(_) => continue,
+                Op::Assert(_) | Op::AssertStack(_) => {
+                    unreachable!("assertions only parse inside test blocks")
+                }
+                Op::Intr1_0(Op1_0::Display) => Opcode::Display,
+                Op::Intr1_0(Op1_0::Drop) => Opcode::Drop,
+                Op::Intr1_2(Op1_2::Duplicate) => Opcode::Dup,
+                Op::Intr2_1(Op2_1::Add) => Opcode::Add,
+                Op::Intr2_1(Op2_1::Sub) => Opcode::Sub,
+                Op::Intr2_1(Op2_1::Mul) => Opcode::Mul,
+                Op::Intr2_1(Op2_1::Div) => Opcode::Div,
+                Op::Intr2_1(Op2_1::Mod) => Opcode::Mod,
+                Op::Intr2_1(Op2_1::Equ) => Opcode::Equ,
+                Op::Intr2_1(Op2_1::Less) => Opcode::Less,
+                Op::Intr2_1(Op2_1::Greater) => Opcode::Greater,
+                Op::Intr2_1(Op2_1::LessEqu) => Opcode::LessEqu,
+                Op::Intr2_1(Op2_1::GreaterEqu) => Opcode::GreaterEqu,
+                Op::Intr2_2(Op2_2::DivMod) => Opcode::DivMod,
+                Op::Intr2_2(Op2_2::Swap) => Opcode::Swap,
+            };
+            code.push(opcode as u8);
+        }
+
+        // falling off the end blames the last op, while a skipped block that closes the program
+        // blames the `if` that skipped it, as the interpreter does
+        code.push(Opcode::Halt as u8);
+        code.extend_from_slice(&(ops.len().saturating_sub(1) as u32).to_le_bytes());
+        for (at, target, i) in patches {
+            let to = match offsets.get(target) {
+                Some(&to) => to,
+                None => {
+                    let to = code.len() as u32;
+                    code.push(Opcode::Halt as u8);
+                    code.extend_from_slice(&(i as u32).to_le_bytes());
+                    to
+                }
+            };
+            code[at..at + 4].copy_from_slice(&to.to_le_bytes());
+        }
+
+        Self {
+            file_name: file_name.as_ref().to_string(),
+            code,
+            max_depth: checked.max_depth,
+            offsets,
+            spans: ops.iter().map(|s| s.idx).collect(),
+            faults,
+        }
+    }
+
+    fn stamp_op(&self, i: usize) -> String {
+        self.spans
+            .get(i)
+            .copied()
+            .unwrap_or_default()
+            .as_stamp(&self.file_name)
+    }
+
+    fn stamp_pc(&self, pc: usize) -> String {
+        let i = self.offsets.partition_point(|&o| o as usize <= pc);
+        self.stamp_op(i.saturating_sub(1))
+    }
+
+    pub fn run(&self, ctx: &mut Context) -> anyhow::Result<()> {
+        let code = self.code.as_slice();
+        let mut stack = vec![0isize; self.max_depth + 1];
+        let mut sp = 0usize;
+        let mut pc = 0usize;
+
+        // SAFETY: `code` was produced by `Bytecode::new`, so every operand is in bounds, and every
+        // reachable op was checked to have its inputs on a stack no deeper than `max_depth`.
+        macro_rules! peek {
+            ($t:ty) => {
+                unsafe { std::ptr::read_unaligned(code.as_ptr().add(pc) as *const $t) }
+            };
+        }
+        macro_rules! operand {
+            ($t:ty) => {{
+                let v = peek!($t);
+                pc += std::mem::size_of::<$t>();
+                v
+            }};
+        }
+        macro_rules! at {
+            ($i:expr) => {
+                *unsafe { stack.get_unchecked_mut($i) }
+            };
+        }
+        macro_rules! binop {
+            (|$t:ident, $t1:ident| $e:expr) => {{
+                let ($t, $t1) = (at!(sp - 1), at!(sp - 2));
+                sp -= 1;
+                at!(sp - 1) = $e;
+            }};
+        }
+
+        loop {
+            let opcode = OPCODES[*unsafe { code.get_unchecked(pc) } as usize];
+            let op_pc = pc;
+            pc += 1;
+            match opcode {
+                Opcode::Push => {
+                    at!(sp) = operand!(i64) as isize;
+                    sp += 1;
+                }
+                Opcode::Display => {
+                    sp -= 1;
+                    writeln!(ctx.stdout, "{}", at!(sp))
+                        .map_err(|e| anyhow::anyhow!("{}: {e}", self.stamp_pc(op_pc)))?;
+                }
+                Opcode::Drop => sp -= 1,
+                Opcode::Dup => {
+                    at!(sp) = at!(sp - 1);
+                    sp += 1;
+                }
+                Opcode::Add => binop!(|t, t1| t.wrapping_add(t1)),
+                Opcode::Sub => binop!(|t, t1| t.wrapping_sub(t1)),
+                Opcode::Mul => binop!(|t, t1| t.wrapping_mul(t1)),
+                Opcode::Div | Opcode::Mod | Opcode::DivMod if at!(sp - 2) == 0 => {
+                    anyhow::bail!("{}: division by zero", self.stamp_pc(op_pc))
+                }
+                Opcode::Div => binop!(|t, t1| t.wrapping_div(t1)),
+                Opcode::Mod => binop!(|t, t1| t.wrapping_rem(t1)),
+                Opcode::Equ => binop!(|t, t1| (t == t1) as isize),
+                Opcode::Less => binop!(|t, t1| (t < t1) as isize),
+                Opcode::Greater => binop!(|t, t1| (t > t1) as isize),
+                Opcode::LessEqu => binop!(|t, t1| (t <= t1) as isize),
+                Opcode::GreaterEqu => binop!(|t, t1| (t >= t1) as isize),
+                Opcode::DivMod => {
+                    let (t, t1) = (at!(sp - 1), at!(sp - 2));
+                    at!(sp - 1) = t.wrapping_div(t1);
+                    at!(sp - 2) = t.wrapping_rem(t1);
+                }
+                Opcode::Swap => {
+                    let (t, t1) = (at!(sp - 1), at!(sp - 2));
+                    at!(sp - 1) = t1;
+                    at!(sp - 2) = t;
+                }
+                Opcode::If => {
+                    let target = operand!(u32);
+                    sp -= 1;
+                    match at!(sp) {
+                        1 => {}
+                        0 => pc = target as usize,
+                        i => anyhow::bail!("{}: expected bool, got {i}", self.stamp_pc(op_pc)),
+                    }
+                }
+                Opcode::Trap => {
+                    let (i, fault) = self.faults[peek!(u32) as usize];
+                    anyhow::bail!("{}: {fault}", self.stamp_op(i))
+                }
+                Opcode::Halt => {
+                    let blame = peek!(u32) as usize;
+                    if sp != 0 {
+                        anyhow::bail!(
+                            "{}: {}",
+                            self.stamp_op(blame),
+                            Fault::Leftover { remaining: sp }
+                        )
+                    }
+                    break;
+                }
+                Opcode::Unreachable => {
+                    unreachable!("executed an op the checker proved unreachable")
+                }
+            }
+        }
+
+        ctx.stdout.flush()?;
+        Ok(())
+    }
+}
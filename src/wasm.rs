@@ -0,0 +1,650 @@
+pub mod validate;
+
+use std::fmt::Write;
+
+use crate::{
+    check::{check, Checked, Fault},
+    ops::{Op, Op1_0, Op1_2, Op2_1, Op2_2},
+    parse::Program,
+    tokenise::Span,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValType {
+    I32,
+    I64,
+}
+
+impl ValType {
+    fn code(self) -> u8 {
+        match self {
+            ValType::I32 => 0x7f,
+            ValType::I64 => 0x7e,
+        }
+    }
+}
+
+impl std::fmt::Display for ValType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValType::I32 => write!(f, "i32"),
+            ValType::I64 => write!(f, "i64"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FuncType {
+    pub params: Vec<ValType>,
+    pub results: Vec<ValType>,
+}
+
+impl std::fmt::Display for FuncType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "(func")?;
+        if !self.params.is_empty() {
+            write!(f, " (param")?;
+            for p in &self.params {
+                write!(f, " {p}")?;
+            }
+            write!(f, ")")?;
+        }
+        if !self.results.is_empty() {
+            write!(f, " (result")?;
+            for r in &self.results {
+                write!(f, " {r}")?;
+            }
+            write!(f, ")")?;
+        }
+        write!(f, ")")
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum BlockType {
+    Empty,
+    Value(ValType),
+    /// index into the type section, for blocks that take their operands as params
+    Type(u32),
+}
+
+/// The subset of wasm instructions the backend emits.
+#[derive(Debug, Clone, Copy)]
+pub enum Instr {
+    Unreachable,
+    If(BlockType),
+    Else,
+    End,
+    Call(u32),
+    Drop,
+    LocalGet(u32),
+    LocalSet(u32),
+    LocalTee(u32),
+    I32Const(i32),
+    I64Const(i64),
+    I64Eqz,
+    I64Eq,
+    I64LtS,
+    I64GtS,
+    I64GtU,
+    I64LeS,
+    I64GeS,
+    I64Add,
+    I64Sub,
+    I64Mul,
+    I64DivS,
+    I64RemS,
+    I32WrapI64,
+    I64ExtendI32U,
+}
+
+impl Instr {
+    fn opcode(self) -> u8 {
+        match self {
+            Instr::Unreachable => 0x00,
+            Instr::If(_) => 0x04,
+            Instr::Else => 0x05,
+            Instr::End => 0x0b,
+            Instr::Call(_) => 0x10,
+            Instr::Drop => 0x1a,
+            Instr::LocalGet(_) => 0x20,
+            Instr::LocalSet(_) => 0x21,
+            Instr::LocalTee(_) => 0x22,
+            Instr::I32Const(_) => 0x41,
+            Instr::I64Const(_) => 0x42,
+            Instr::I64Eqz => 0x50,
+            Instr::I64Eq => 0x51,
+            Instr::I64LtS => 0x53,
+            Instr::I64GtS => 0x55,
+            Instr::I64GtU => 0x56,
+            Instr::I64LeS => 0x57,
+            Instr::I64GeS => 0x59,
+            Instr::I64Add => 0x7c,
+            Instr::I64Sub => 0x7d,
+            Instr::I64Mul => 0x7e,
+            Instr::I64DivS => 0x7f,
+            Instr::I64RemS => 0x81,
+            Instr::I32WrapI64 => 0xa7,
+            Instr::I64ExtendI32U => 0xad,
+        }
+    }
+
+    fn encode(self, out: &mut Vec<u8>) {
+        out.push(self.opcode());
+        match self {
+            Instr::If(BlockType::Empty) => out.push(0x40),
+            Instr::If(BlockType::Value(t)) => out.push(t.code()),
+            Instr::If(BlockType::Type(i)) => leb_i64(out, i as i64),
+            Instr::Call(i) | Instr::LocalGet(i) | Instr::LocalSet(i) | Instr::LocalTee(i) => {
+                leb_u32(out, i)
+            }
+            Instr::I32Const(n) => leb_i64(out, n as i64),
+            Instr::I64Const(n) => leb_i64(out, n),
+            _ => {}
+        }
+    }
+
+    fn mnemonic(self) -> &'static str {
+        match self {
+            Instr::Unreachable => "unreachable",
+            Instr::If(_) => "if",
+            Instr::Else => "else",
+            Instr::End => "end",
+            Instr::Call(_) => "call",
+            Instr::Drop => "drop",
+            Instr::LocalGet(_) => "local.get",
+            Instr::LocalSet(_) => "local.set",
+            Instr::LocalTee(_) => "local.tee",
+            Instr::I32Const(_) => "i32.const",
+            Instr::I64Const(_) => "i64.const",
+            Instr::I64Eqz => "i64.eqz",
+            Instr::I64Eq => "i64.eq",
+            Instr::I64LtS => "i64.lt_s",
+            Instr::I64GtS => "i64.gt_s",
+            Instr::I64GtU => "i64.gt_u",
+            Instr::I64LeS => "i64.le_s",
+            Instr::I64GeS => "i64.ge_s",
+            Instr::I64Add => "i64.add",
+            Instr::I64Sub => "i64.sub",
+            Instr::I64Mul => "i64.mul",
+            Instr::I64DivS => "i64.div_s",
+            Instr::I64RemS => "i64.rem_s",
+            Instr::I32WrapI64 => "i32.wrap_i64",
+            Instr::I64ExtendI32U => "i64.extend_i32_u",
+        }
+    }
+}
+
+fn leb_u32(out: &mut Vec<u8>, mut n: u32) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        match n {
+            0 => return out.push(byte),
+            _ => out.push(byte | 0x80),
+        }
+    }
+}
+
+fn leb_i64(out: &mut Vec<u8>, mut n: i64) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        let done = (n == 0 && byte & 0x40 == 0) || (n == -1 && byte & 0x40 != 0);
+        match done {
+            true => return out.push(byte),
+            false => out.push(byte | 0x80),
+        }
+    }
+}
+
+fn name(out: &mut Vec<u8>, s: &str) {
+    leb_u32(out, s.len() as u32);
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn section(out: &mut Vec<u8>, id: u8, contents: Vec<u8>) {
+    out.push(id);
+    leb_u32(out, contents.len() as u32);
+    out.extend_from_slice(&contents);
+}
+
+/// Host functions the module imports from `env`, in function index order.
+const IMPORTS: [(&str, u32); 3] = [("display", 0), ("error", 1), ("error_value", 0)];
+/// `display(value: i64)` writes `value` and a newline to stdout.
+const DISPLAY: u32 = 0;
+/// `error(ptr: i32, len: i32)` writes the bytes at `ptr` in the exported memory to stderr.
+const ERROR: u32 = 1;
+/// `error_value(value: i64)` writes `value` and a newline to stderr.
+const ERROR_VALUE: u32 = 2;
+const MAIN: u32 = 3;
+const MAIN_TYPE: u32 = 2;
+
+const T: u32 = 0;
+const T1: u32 = 1;
+
+/// A `Program` lowered to a wasm module exporting `main: () -> ()` and its `memory`.
+///
+/// Data stays on wasm's operand stack: the depth at every op is known statically, so each `if`
+/// block takes the values it reaches below its entry depth as params and hands them back as
+/// results. Errors are written through the `env` imports and end in `unreachable`, which the
+/// host reports as exit status 1.
+pub struct Module {
+    types: Vec<FuncType>,
+    locals: Vec<ValType>,
+    body: Vec<Instr>,
+    data: Vec<u8>,
+}
+
+impl Module {
+    pub fn new(file_name: impl AsRef<str>, Program { ops, .. }: &Program) -> Self {
+        let types = vec![
+            FuncType {
+                params: vec![ValType::I64],
+                results: vec![],
+            },
+            FuncType {
+                params: vec![ValType::I32, ValType::I32],
+                results: vec![],
+            },
+            FuncType {
+                params: vec![],
+                results: vec![],
+            },
+        ];
+        let mut lower = Lower {
+            file_name: file_name.as_ref(),
+            ops,
+            checked: check(ops),
+            module: Module {
+                types,
+                locals: vec![ValType::I64, ValType::I64],
+                body: vec![],
+                data: vec![],
+            },
+        };
+        lower.lower();
+        lower.module
+    }
+
+    fn func_name(i: u32) -> &'static str {
+        match i {
+            MAIN => "main",
+            i => IMPORTS[i as usize].0,
+        }
+    }
+
+    pub fn to_wasm(&self) -> Vec<u8> {
+        let mut out = b"\0asm".to_vec();
+        out.extend_from_slice(&1u32.to_le_bytes());
+
+        let mut s = vec![];
+        leb_u32(&mut s, self.types.len() as u32);
+        for FuncType { params, results } in &self.types {
+            s.push(0x60);
+            for vals in [params, results] {
+                leb_u32(&mut s, vals.len() as u32);
+                s.extend(vals.iter().map(|v| v.code()));
+            }
+        }
+        section(&mut out, 1, s);
+
+        let mut s = vec![];
+        leb_u32(&mut s, IMPORTS.len() as u32);
+        for (field, ty) in IMPORTS {
+            name(&mut s, "env");
+            name(&mut s, field);
+            s.push(0x00);
+            leb_u32(&mut s, ty);
+        }
+        section(&mut out, 2, s);
+
+        let mut s = vec![1];
+        leb_u32(&mut s, MAIN_TYPE);
+        section(&mut out, 3, s);
+        let mut s = vec![1, 0x00];
+        leb_u32(&mut s, self.pages());
+        section(&mut out, 5, s);
+
+        let mut s = vec![2];
+        name(&mut s, "main");
+        s.push(0x00);
+        leb_u32(&mut s, MAIN);
+        name(&mut s, "memory");
+        s.push(0x02);
+        leb_u32(&mut s, 0);
+        section(&mut out, 7, s);
+
+        let mut func = vec![];
+        leb_u32(&mut func, self.locals.len() as u32);
+        for local in &self.locals {
+            leb_u32(&mut func, 1);
+            func.push(local.code());
+        }
+        for instr in &self.body {
+            instr.encode(&mut func);
+        }
+        let mut s = vec![1];
+        leb_u32(&mut s, func.len() as u32);
+        s.extend_from_slice(&func);
+        section(&mut out, 10, s);
+
+        if !self.data.is_empty() {
+            let mut s = vec![1, 0x00];
+            Instr::I32Const(0).encode(&mut s);
+            Instr::End.encode(&mut s);
+            leb_u32(&mut s, self.data.len() as u32);
+            s.extend_from_slice(&self.data);
+            section(&mut out, 11, s);
+        }
+        out
+    }
+
+    pub fn to_wat(&self) -> String {
+        let mut s = String::from("(module\n");
+        for (i, ty) in self.types.iter().enumerate() {
+            let _ = writeln!(s, "  (type (;{i};) {ty})");
+        }
+        for (field, ty) in IMPORTS {
+            let _ = writeln!(
+                s,
+                "  (import \"env\" \"{field}\" (func ${field} (type {ty})))"
+            );
+        }
+        let _ = writeln!(s, "  (memory (export \"memory\") {})", self.pages());
+        let _ = write!(s, "  (func $main (export \"main\") (type {MAIN_TYPE})");
+        for (i, local) in self.locals.iter().enumerate() {
+            let _ = write!(s, " (local ${} {local})", ["t", "t1"][i]);
+        }
+        s.push('\n');
+        let mut depth = 2;
+        // the text format closes the function body with a paren rather than its final `end`
+        for instr in &self.body[..self.body.len() - 1] {
+            if let Instr::Else | Instr::End = instr {
+                depth -= 1;
+            }
+            let _ = write!(s, "{}{}", "  ".repeat(depth), instr.mnemonic());
+            match *instr {
+                Instr::If(BlockType::Empty) => {}
+                Instr::If(BlockType::Value(t)) => {
+                    let _ = write!(s, " (result {t})");
+                }
+                Instr::If(BlockType::Type(i)) => {
+                    let _ = write!(s, " (type {i})");
+                }
+                Instr::Call(i) => {
+                    let _ = write!(s, " ${}", Self::func_name(i));
+                }
+                Instr::LocalGet(i) | Instr::LocalSet(i) | Instr::LocalTee(i) => {
+                    let _ = write!(s, " ${}", ["t", "t1"][i as usize]);
+                }
+                Instr::I32Const(n) => {
+                    let _ = write!(s, " {n}");
+                }
+                Instr::I64Const(n) => {
+                    let _ = write!(s, " {n}");
+                }
+                _ => {}
+            }
+            s.push('\n');
+            if let Instr::If(_) | Instr::Else = instr {
+                depth += 1;
+            }
+        }
+        s.push_str("  )\n");
+        if !self.data.is_empty() {
+            let _ = write!(s, "  (data (i32.const 0) \"");
+            for &b in &self.data {
+                match b {
+                    b'"' | b'\\' => {
+                        let _ = write!(s, "\\{}", b as char);
+                    }
+                    b' '..=b'~' => s.push(b as char),
+                    b => {
+                        let _ = write!(s, "\\{b:02x}");
+                    }
+                }
+            }
+            s.push_str("\")\n");
+        }
+        s.push_str(")\n");
+        s
+    }
+
+    fn pages(&self) -> u32 {
+        (self.data.len() as u32).div_ceil(0x10000).max(1)
+    }
+}
+
+struct Lower<'a> {
+    file_name: &'a str,
+    ops: &'a [Span<Op>],
+    checked: Checked,
+    module: Module,
+}
+
+impl Lower<'_> {
+    fn emit(&mut self, instrs: &[Instr]) {
+        self.module.body.extend_from_slice(instrs);
+    }
+
+    fn stamp(&self, op: usize) -> String {
+        self.ops[op].idx.as_stamp(self.file_name)
+    }
+
+    /// Writes `msg` to stderr from a data segment.
+    fn error(&mut self, msg: String) {
+        let ptr = self.module.data.len() as i32;
+        self.module.data.extend_from_slice(msg.as_bytes());
+        self.emit(&[
+            Instr::I32Const(ptr),
+            Instr::I32Const(msg.len() as i32),
+            Instr::Call(ERROR),
+        ]);
+    }
+
+    fn fail(&mut self, msg: String) {
+        self.error(msg);
+        self.emit(&[Instr::Unreachable]);
+    }
+
+    fn block_type(&mut self, params: usize) -> BlockType {
+        if params == 0 {
+            return BlockType::Empty;
+        }
+        let ty = FuncType {
+            params: vec![ValType::I64; params],
+            results: vec![ValType::I64; params],
+        };
+        let types = &mut self.module.types;
+        let i = match types.iter().position(|t| *t == ty) {
+            Some(i) => i,
+            None => {
+                types.push(ty);
+                types.len() - 1
+            }
+        };
+        BlockType::Type(i as u32)
+    }
+
+    /// How many values below its entry depth the block opened by the `if` at `at` reaches.
+    fn block_params(&self, at: usize, end: usize) -> usize {
+        let entry = self.checked.depths[at].map_or(0, |d| d - 1);
+        let low = (at + 1..end)
+            .filter_map(|j| {
+                let d = self.checked.depths[j]?;
+                match self.checked.faults[j] {
+                    Some(_) => Some(d),
+                    None => Some(d - self.ops[j].token.arity().0),
+                }
+            })
+            .fold(entry, usize::min);
+        entry - low
+    }
+
+    /// Leaves `t` and `t1` in their locals, failing if `t1` is zero.
+    fn operands_nonzero(&mut self, at: usize) {
+        self.emit(&[
+            Instr::LocalSet(T),
+            Instr::LocalSet(T1),
+            Instr::LocalGet(T1),
+            Instr::I64Eqz,
+            Instr::If(BlockType::Empty),
+        ]);
+        let at = self.stamp(at);
+        self.fail(format!("Error: {at}: division by zero\n"));
+        self.emit(&[Instr::End]);
+    }
+
+    fn quot(&mut self) {
+        // `i64.div_s` traps on i64::MIN / -1, which wraps to i64::MIN
+        self.emit(&[
+            Instr::LocalGet(T1),
+            Instr::I64Const(-1),
+            Instr::I64Eq,
+            Instr::If(BlockType::Value(ValType::I64)),
+            Instr::I64Const(0),
+            Instr::LocalGet(T),
+            Instr::I64Sub,
+            Instr::Else,
+            Instr::LocalGet(T),
+            Instr::LocalGet(T1),
+            Instr::I64DivS,
+            Instr::End,
+        ]);
+    }
+
+    fn rem(&mut self) {
+        self.emit(&[Instr::LocalGet(T), Instr::LocalGet(T1), Instr::I64RemS]);
+    }
+
+    /// Closes the block ended by the `end` at `at`.
+    fn close(&mut self, at: usize) {
+        // a skipped block that closes the program blames the `if` for leftover data
+        let opened = self.ops[..at].iter().rposition(|s| match s.token {
+            Op::If(target) => target.0 == at + 1,
+            _ => false,
+        });
+        match (at + 1 == self.ops.len(), self.checked.exit_depth, opened) {
+            (true, Some(remaining), Some(blame)) if remaining > 0 => {
+                let blame = self.stamp(blame);
+                self.emit(&[Instr::Else]);
+                self.fail(format!(
+                    "Error: {blame}: {}\n",
+                    Fault::Leftover { remaining }
+                ));
+            }
+            _ => {}
+        }
+        self.emit(&[Instr::End]);
+    }
+
+    fn lower(&mut self) {
+        let ops = self.ops;
+        // whether each open `if` was emitted, and so needs closing
+        let mut blocks = vec![];
+        for (i, Span { token: op, .. }) in ops.iter().enumerate() {
+            if let Some(fault) = self.checked.faults[i] {
+                let at = self.stamp(i);
+                self.fail(format!("Error: {at}: {fault}\n"));
+                match op {
+                    Op::If(_) => blocks.push(false),
+                    Op::End if blocks.pop() == Some(true) => self.close(i),
+                    _ => {}
+                }
+                continue;
+            }
+            if self.checked.depths[i].is_none() {
+                match op {
+                    Op::If(_) => blocks.push(false),
+                    Op::End if blocks.pop() == Some(true) => self.close(i),
+                    _ => {}
+                }
+                continue;
+            }
+            match *op {
+                Op::Push(n) => self.emit(&[Instr::I64Const(n as i64)]),
+                Op::Intr1_0(Op1_0::Display) => self.emit(&[Instr::Call(DISPLAY)]),
+                Op::Intr1_0(Op1_0::Drop) => self.emit(&[Instr::Drop]),
+                Op::Intr1_2(Op1_2::Duplicate) => {
+                    self.emit(&[Instr::LocalTee(T), Instr::LocalGet(T)])
+                }
+                Op::Intr2_1(Op2_1::Add) => self.emit(&[Instr::I64Add]),
+                Op::Intr2_1(Op2_1::Mul) => self.emit(&[Instr::I64Mul]),
+                Op::Intr2_1(Op2_1::Sub) => self.emit(&[
+                    Instr::LocalSet(T),
+                    Instr::LocalSet(T1),
+                    Instr::LocalGet(T),
+                    Instr::LocalGet(T1),
+                    Instr::I64Sub,
+                ]),
+                Op::Intr2_1(Op2_1::Div) => {
+                    self.operands_nonzero(i);
+                    self.quot();
+                }
+                Op::Intr2_1(Op2_1::Mod) => {
+                    self.operands_nonzero(i);
+                    self.rem();
+                }
+                // `t` is on top, so `t < t1` compares the top of wasm's stack with the one below
+                Op::Intr2_1(Op2_1::Equ) => self.emit(&[Instr::I64Eq, Instr::I64ExtendI32U]),
+                Op::Intr2_1(Op2_1::Less) => self.emit(&[Instr::I64GtS, Instr::I64ExtendI32U]),
+                Op::Intr2_1(Op2_1::Greater) => self.emit(&[Instr::I64LtS, Instr::I64ExtendI32U]),
+                Op::Intr2_1(Op2_1::LessEqu) => self.emit(&[Instr::I64GeS, Instr::I64ExtendI32U]),
+                Op::Intr2_1(Op2_1::GreaterEqu) => self.emit(&[Instr::I64LeS, Instr::I64ExtendI32U]),
+                Op::Intr2_2(Op2_2::DivMod) => {
+                    self.operands_nonzero(i);
+                    self.rem();
+                    self.quot();
+                }
+                Op::Intr2_2(Op2_2::Swap) => self.emit(&[
+                    Instr::LocalSet(T),
+                    Instr::LocalSet(T1),
+                    Instr::LocalGet(T),
+                    Instr::LocalGet(T1),
+                ]),
+                Op::If(target) => {
+                    let at = self.stamp(i);
+                    self.emit(&[
+                        Instr::LocalSet(T),
+                        Instr::LocalGet(T),
+                        Instr::I64Const(1),
+                        Instr::I64GtU,
+                        Instr::If(BlockType::Empty),
+                    ]);
+                    self.error(format!("Error: {at}: expected bool, got "));
+                    self.emit(&[
+                        Instr::LocalGet(T),
+                        Instr::Call(ERROR_VALUE),
+                        Instr::Unreachable,
+                        Instr::End,
+                        Instr::LocalGet(T),
+                        Instr::I32WrapI64,
+                    ]);
+                    let params = self.block_params(i, target.0 - 1);
+                    let bt = self.block_type(params);
+                    self.emit(&[Instr::If(bt)]);
+                    blocks.push(true);
+                }
+                Op::End => {
+                    blocks.pop();
+                    self.close(i);
+                }
+                Op::Cast(_) => {}
+                Op::Assert(_) | Op::AssertStack(_) => {
+                    unreachable!("assertions only parse inside test blocks")
+                }
+            }
+        }
+
+        match self.checked.exit_depth {
+            Some(0) => {}
+            Some(remaining) => {
+                let at = self.stamp(ops.len() - 1);
+                self.fail(format!("Error: {at}: {}\n", Fault::Leftover { remaining }));
+            }
+            None => self.emit(&[Instr::Unreachable]),
+        }
+        self.emit(&[Instr::End]);
+    }
+}
@@ -0,0 +1,524 @@
+//! A structural validator for the subset of wasm the backend emits, so modules can be checked
+//! offline without a runtime.
+//!
+//! The binary is decoded independently of the encoder: sections must appear in order with
+//! their declared sizes, every index must be in bounds, and every function body must type
+//! check against the operand and control stacks as the spec's validation algorithm describes.
+
+use super::{FuncType, ValType};
+
+struct Reader<'b> {
+    bytes: &'b [u8],
+    pos: usize,
+}
+
+impl<'b> Reader<'b> {
+    fn byte(&mut self) -> anyhow::Result<u8> {
+        let b = *self
+            .bytes
+            .get(self.pos)
+            .ok_or_else(|| anyhow::anyhow!("offset {}: unexpected end of module", self.pos))?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn bytes(&mut self, n: usize) -> anyhow::Result<&'b [u8]> {
+        let end = self.pos.checked_add(n).filter(|&e| e <= self.bytes.len());
+        let end =
+            end.ok_or_else(|| anyhow::anyhow!("offset {}: unexpected end of module", self.pos))?;
+        let b = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(b)
+    }
+
+    fn leb(&mut self, bits: u32, signed: bool) -> anyhow::Result<i128> {
+        let at = self.pos;
+        let (mut n, mut shift) = (0i128, 0);
+        loop {
+            let b = self.byte()?;
+            n |= ((b & 0x7f) as i128) << shift;
+            shift += 7;
+            if b & 0x80 == 0 {
+                if signed && shift < 128 && b & 0x40 != 0 {
+                    n |= -1i128 << shift;
+                }
+                break;
+            }
+            if shift >= bits {
+                anyhow::bail!("offset {at}: integer representation too long");
+            }
+        }
+        let fits = match signed {
+            true => n >= -(1i128 << (bits - 1)) && n < 1i128 << (bits - 1),
+            false => n < 1i128 << bits,
+        };
+        match fits {
+            true => Ok(n),
+            false => anyhow::bail!("offset {at}: integer too large"),
+        }
+    }
+
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(self.leb(32, false)? as u32)
+    }
+
+    fn val_type(&mut self) -> anyhow::Result<ValType> {
+        match self.byte()? {
+            0x7f => Ok(ValType::I32),
+            0x7e => Ok(ValType::I64),
+            b => anyhow::bail!("offset {}: unsupported value type {b:#04x}", self.pos - 1),
+        }
+    }
+
+    fn name(&mut self) -> anyhow::Result<&'b str> {
+        let len = self.u32()? as usize;
+        let at = self.pos;
+        std::str::from_utf8(self.bytes(len)?)
+            .map_err(|_| anyhow::anyhow!("offset {at}: name is not valid UTF-8"))
+    }
+
+    fn done(&self) -> bool {
+        self.pos == self.bytes.len()
+    }
+}
+
+#[derive(Debug)]
+struct Frame {
+    params: Vec<ValType>,
+    results: Vec<ValType>,
+    height: usize,
+    unreachable: bool,
+    is_if: bool,
+}
+
+/// Type checks one function body against the operand and control stacks.
+struct Body<'m> {
+    types: &'m [FuncType],
+    funcs: &'m [u32],
+    locals: Vec<ValType>,
+    vals: Vec<ValType>,
+    ctrls: Vec<Frame>,
+}
+
+impl Body<'_> {
+    fn pop(&mut self, at: usize, expect: ValType) -> anyhow::Result<()> {
+        let frame = self.ctrls.last().expect("inside a frame");
+        if self.vals.len() == frame.height {
+            return match frame.unreachable {
+                true => Ok(()),
+                false => anyhow::bail!(
+                    "offset {at}: type mismatch, expected {expect} but the stack is empty"
+                ),
+            };
+        }
+        match self.vals.pop() {
+            Some(v) if v == expect => Ok(()),
+            Some(v) => anyhow::bail!("offset {at}: type mismatch, expected {expect}, got {v}"),
+            None => unreachable!(),
+        }
+    }
+
+    fn pop_all(&mut self, at: usize, types: &[ValType]) -> anyhow::Result<()> {
+        for &t in types.iter().rev() {
+            self.pop(at, t)?;
+        }
+        Ok(())
+    }
+
+    fn push_frame(&mut self, params: Vec<ValType>, results: Vec<ValType>, is_if: bool) {
+        let height = self.vals.len();
+        self.vals.extend_from_slice(&params);
+        self.ctrls.push(Frame {
+            params,
+            results,
+            height,
+            unreachable: false,
+            is_if,
+        });
+    }
+
+    fn pop_frame(&mut self, at: usize) -> anyhow::Result<Frame> {
+        let results = match self.ctrls.last() {
+            Some(frame) => frame.results.clone(),
+            None => anyhow::bail!("offset {at}: END without an open block"),
+        };
+        self.pop_all(at, &results)?;
+        let frame = self.ctrls.pop().expect("checked above");
+        if self.vals.len() != frame.height {
+            anyhow::bail!(
+                "offset {at}: block leaves {} extra value(s) on the stack",
+                self.vals.len() - frame.height
+            );
+        }
+        Ok(frame)
+    }
+
+    fn unreachable(&mut self) {
+        let frame = self.ctrls.last_mut().expect("inside a frame");
+        self.vals.truncate(frame.height);
+        frame.unreachable = true;
+    }
+
+    fn local(&self, at: usize, i: u32) -> anyhow::Result<ValType> {
+        match self.locals.get(i as usize) {
+            Some(&t) => Ok(t),
+            None => anyhow::bail!("offset {at}: unknown local {i}"),
+        }
+    }
+
+    fn block_type(&self, r: &mut Reader) -> anyhow::Result<FuncType> {
+        let at = r.pos;
+        let (params, results) = match r.bytes.get(r.pos) {
+            Some(0x40) => {
+                r.pos += 1;
+                (vec![], vec![])
+            }
+            Some(0x7f | 0x7e) => (vec![], vec![r.val_type()?]),
+            _ => {
+                let i = r.leb(33, true)?;
+                match usize::try_from(i).ok().and_then(|i| self.types.get(i)) {
+                    Some(ty) => (ty.params.clone(), ty.results.clone()),
+                    None => anyhow::bail!("offset {at}: unknown block type {i}"),
+                }
+            }
+        };
+        Ok(FuncType { params, results })
+    }
+
+    fn validate(mut self, r: &mut Reader, ty: &FuncType) -> anyhow::Result<()> {
+        use ValType::*;
+        self.push_frame(vec![], ty.results.clone(), false);
+        while !self.ctrls.is_empty() {
+            let at = r.pos;
+            match r.byte()? {
+                0x00 => self.unreachable(),
+                0x01 => {}
+                0x04 => {
+                    let FuncType { params, results } = self.block_type(r)?;
+                    self.pop(at, I32)?;
+                    self.pop_all(at, &params)?;
+                    self.push_frame(params, results, true);
+                }
+                0x05 => {
+                    let frame = self.pop_frame(at)?;
+                    if !frame.is_if {
+                        anyhow::bail!("offset {at}: ELSE without a matching IF");
+                    }
+                    self.push_frame(frame.params, frame.results, false);
+                }
+                0x0b => {
+                    let frame = self.pop_frame(at)?;
+                    if frame.is_if && frame.params != frame.results {
+                        anyhow::bail!("offset {at}: IF without ELSE must not change the stack");
+                    }
+                    self.vals.extend_from_slice(&frame.results);
+                }
+                0x10 => {
+                    let f = r.u32()?;
+                    let ty = match self.funcs.get(f as usize) {
+                        Some(&ty) => &self.types[ty as usize],
+                        None => anyhow::bail!("offset {at}: unknown function {f}"),
+                    };
+                    let (params, results) = (ty.params.clone(), ty.results.clone());
+                    self.pop_all(at, &params)?;
+                    self.vals.extend_from_slice(&results);
+                }
+                0x1a => {
+                    let frame = self.ctrls.last().expect("inside a frame");
+                    match self.vals.len() == frame.height {
+                        true if frame.unreachable => {}
+                        true => anyhow::bail!("offset {at}: DROP on an empty stack"),
+                        false => {
+                            self.vals.pop();
+                        }
+                    }
+                }
+                0x20 => {
+                    let t = self.local(at, r.u32()?)?;
+                    self.vals.push(t);
+                }
+                0x21 => {
+                    let t = self.local(at, r.u32()?)?;
+                    self.pop(at, t)?;
+                }
+                0x22 => {
+                    let t = self.local(at, r.u32()?)?;
+                    self.pop(at, t)?;
+                    self.vals.push(t);
+                }
+                0x41 => {
+                    r.leb(32, true)?;
+                    self.vals.push(I32);
+                }
+                0x42 => {
+                    r.leb(64, true)?;
+                    self.vals.push(I64);
+                }
+                0x45 => {
+                    self.pop(at, I32)?;
+                    self.vals.push(I32);
+                }
+                0x50 => {
+                    self.pop(at, I64)?;
+                    self.vals.push(I32);
+                }
+                0x46..=0x4f => {
+                    self.pop_all(at, &[I32, I32])?;
+                    self.vals.push(I32);
+                }
+                0x51..=0x5a => {
+                    self.pop_all(at, &[I64, I64])?;
+                    self.vals.push(I32);
+                }
+                0x6a..=0x78 => {
+                    self.pop_all(at, &[I32, I32])?;
+                    self.vals.push(I32);
+                }
+                0x7c..=0x8a => {
+                    self.pop_all(at, &[I64, I64])?;
+                    self.vals.push(I64);
+                }
+                0xa7 => {
+                    self.pop(at, I64)?;
+                    self.vals.push(I32);
+                }
+                0xac | 0xad => {
+                    self.pop(at, I32)?;
+                    self.vals.push(I64);
+                }
+                op => anyhow::bail!("offset {at}: unsupported opcode {op:#04x}"),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks that `bytes` is a well formed module whose functions all type check.
+pub fn validate(bytes: &[u8]) -> anyhow::Result<()> {
+    let mut r = Reader { bytes, pos: 0 };
+    if r.bytes(4)? != b"\0asm" {
+        anyhow::bail!("offset 0: missing wasm magic number");
+    }
+    if r.bytes(4)? != 1u32.to_le_bytes() {
+        anyhow::bail!("offset 4: unsupported wasm version");
+    }
+
+    let mut types = vec![];
+    let mut funcs = vec![];
+    let mut imported = 0;
+    let mut memory = None;
+    let mut bodies = 0;
+    let mut last_id = 0;
+    while !r.done() {
+        let at = r.pos;
+        let id = r.byte()?;
+        let len = r.u32()? as usize;
+        let mut s = Reader {
+            bytes: r.bytes(len)?,
+            pos: 0,
+        };
+        if id != 0 {
+            if id <= last_id {
+                anyhow::bail!("offset {at}: section {id} out of order");
+            }
+            last_id = id;
+        }
+        let base = r.pos - len;
+        let err = |e: anyhow::Error| anyhow::anyhow!("section {id} at {base}: {e}");
+
+        match id {
+            0 => continue,
+            1 => {
+                for _ in 0..s.u32().map_err(err)? {
+                    if s.byte().map_err(err)? != 0x60 {
+                        return Err(err(anyhow::anyhow!("expected a function type")));
+                    }
+                    let mut vals = || -> anyhow::Result<Vec<ValType>> {
+                        (0..s.u32()?).map(|_| s.val_type()).collect()
+                    };
+                    let params = vals().map_err(err)?;
+                    let results = vals().map_err(err)?;
+                    types.push(FuncType { params, results });
+                }
+            }
+            2 => {
+                for _ in 0..s.u32().map_err(err)? {
+                    s.name().map_err(err)?;
+                    s.name().map_err(err)?;
+                    match s.byte().map_err(err)? {
+                        0x00 => {
+                            let ty = s.u32().map_err(err)?;
+                            if ty as usize >= types.len() {
+                                return Err(err(anyhow::anyhow!("unknown type {ty}")));
+                            }
+                            funcs.push(ty);
+                            imported += 1;
+                        }
+                        k => return Err(err(anyhow::anyhow!("unsupported import kind {k}"))),
+                    }
+                }
+            }
+            3 => {
+                for _ in 0..s.u32().map_err(err)? {
+                    let ty = s.u32().map_err(err)?;
+                    if ty as usize >= types.len() {
+                        return Err(err(anyhow::anyhow!("unknown type {ty}")));
+                    }
+                    funcs.push(ty);
+                }
+            }
+            5 => {
+                let n = s.u32().map_err(err)?;
+                if n > 1 {
+                    return Err(err(anyhow::anyhow!("at most one memory is allowed")));
+                }
+                for _ in 0..n {
+                    let (min, max) = match s.byte().map_err(err)? {
+                        0x00 => (s.u32().map_err(err)?, None),
+                        0x01 => (s.u32().map_err(err)?, Some(s.u32().map_err(err)?)),
+                        b => return Err(err(anyhow::anyhow!("bad limits flag {b:#04x}"))),
+                    };
+                    if min > 0x10000 || max.is_some_and(|max| max < min || max > 0x10000) {
+                        return Err(err(anyhow::anyhow!("memory limits out of range")));
+                    }
+                    memory = Some(min);
+                }
+            }
+            7 => {
+                let mut names = std::collections::HashSet::new();
+                for _ in 0..s.u32().map_err(err)? {
+                    let name = s.name().map_err(err)?;
+                    if !names.insert(name) {
+                        return Err(err(anyhow::anyhow!("duplicate export \"{name}\"")));
+                    }
+                    let (kind, i) = (s.byte().map_err(err)?, s.u32().map_err(err)?);
+                    let in_range = match kind {
+                        0x00 => (i as usize) < funcs.len(),
+                        0x02 => i == 0 && memory.is_some(),
+                        k => return Err(err(anyhow::anyhow!("unsupported export kind {k}"))),
+                    };
+                    if !in_range {
+                        return Err(err(anyhow::anyhow!("export \"{name}\" out of range")));
+                    }
+                }
+            }
+            10 => {
+                let n = s.u32().map_err(err)? as usize;
+                if n != funcs.len() - imported {
+                    return Err(err(anyhow::anyhow!(
+                        "{n} bodies for {} functions",
+                        funcs.len() - imported
+                    )));
+                }
+                for f in imported..funcs.len() {
+                    let len = s.u32().map_err(err)? as usize;
+                    let mut b = Reader {
+                        bytes: s.bytes(len).map_err(err)?,
+                        pos: 0,
+                    };
+                    let ty = &types[funcs[f] as usize];
+                    let mut locals = ty.params.clone();
+                    for _ in 0..b.u32().map_err(err)? {
+                        let count = b.u32().map_err(err)?;
+                        let t = b.val_type().map_err(err)?;
+                        locals.extend(std::iter::repeat_n(t, count as usize));
+                    }
+                    let body = Body {
+                        types: &types,
+                        funcs: &funcs,
+                        locals,
+                        vals: vec![],
+                        ctrls: vec![],
+                    };
+                    body.validate(&mut b, ty)
+                        .map_err(|e| anyhow::anyhow!("function {f}: {e}"))?;
+                    if !b.done() {
+                        return Err(err(anyhow::anyhow!(
+                            "function {f}: trailing bytes after END"
+                        )));
+                    }
+                    bodies += 1;
+                }
+            }
+            11 => {
+                for _ in 0..s.u32().map_err(err)? {
+                    if s.byte().map_err(err)? != 0x00 {
+                        return Err(err(anyhow::anyhow!("only active segments are supported")));
+                    }
+                    let Some(pages) = memory else {
+                        return Err(err(anyhow::anyhow!("data segment without a memory")));
+                    };
+                    let offset = match (s.byte(), s.leb(32, true), s.byte()) {
+                        (Ok(0x41), Ok(n), Ok(0x0b)) => n as i32 as u32 as u64,
+                        _ => return Err(err(anyhow::anyhow!("offset is not a constant"))),
+                    };
+                    let len = s.u32().map_err(err)? as u64;
+                    s.bytes(len as usize).map_err(err)?;
+                    if offset + len > pages as u64 * 0x10000 {
+                        return Err(err(anyhow::anyhow!("data segment out of bounds")));
+                    }
+                }
+            }
+            _ => return Err(err(anyhow::anyhow!("unsupported section"))),
+        }
+        if !s.done() {
+            return Err(err(anyhow::anyhow!("section size mismatch")));
+        }
+    }
+    if bodies != funcs.len() - imported {
+        anyhow::bail!(
+            "{} function(s) without a body",
+            funcs.len() - imported - bodies
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse::parse_str, wasm::Module};
+
+    /// A module of one `() -> i32` function with the given body, locals and END included.
+    fn module(body: &[u8]) -> Vec<u8> {
+        let mut bytes = b"\0asm".to_vec();
+        bytes.extend(1u32.to_le_bytes());
+        bytes.extend([1, 5, 1, 0x60, 0, 1, 0x7f]);
+        bytes.extend([3, 2, 1, 0]);
+        bytes.extend([10, body.len() as u8 + 2, 1, body.len() as u8]);
+        bytes.extend(body);
+        bytes
+    }
+
+    #[test]
+    fn accepts_what_the_backend_emits() {
+        let src = "1 2 + . 3 4 < if 5 . end 7 2 /% . .";
+        let prog = parse_str(src).unwrap();
+        validate(&Module::new("t.wa", &prog).to_wasm()).unwrap();
+    }
+
+    #[test]
+    fn accepts_a_well_typed_body() {
+        validate(&module(&[0, 0x41, 0, 0x0b])).unwrap();
+    }
+
+    #[test]
+    fn rejects_a_result_of_the_wrong_type() {
+        let e = validate(&module(&[0, 0x42, 0, 0x0b])).unwrap_err();
+        assert!(e.to_string().contains("expected i32, got i64"), "{e}");
+    }
+
+    #[test]
+    fn rejects_leftover_values() {
+        let e = validate(&module(&[0, 0x41, 0, 0x41, 0, 0x0b])).unwrap_err();
+        assert!(e.to_string().contains("extra value"), "{e}");
+    }
+
+    #[test]
+    fn rejects_bad_headers_and_sizes() {
+        assert!(validate(b"\0wasm\x01\0\0\0").is_err());
+        let mut bytes = module(&[0, 0x41, 0, 0x0b]);
+        bytes.pop();
+        assert!(validate(&bytes).is_err());
+    }
+}
@@ -0,0 +1,493 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reg {
+    Rax,
+    Rcx,
+    Rdx,
+    Rbx,
+    Rsp,
+    Rbp,
+    Rsi,
+    Rdi,
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15,
+}
+
+impl Reg {
+    fn code(self) -> u8 {
+        self as u8 & 7
+    }
+
+    fn ext(self) -> u8 {
+        self as u8 >> 3
+    }
+
+    fn name8(self) -> &'static str {
+        match self {
+            Reg::Rax => "al",
+            Reg::Rcx => "cl",
+            Reg::Rdx => "dl",
+            Reg::Rbx => "bl",
+            Reg::Rsp => "spl",
+            Reg::Rbp => "bpl",
+            Reg::Rsi => "sil",
+            Reg::Rdi => "dil",
+            Reg::R8 => "r8b",
+            Reg::R9 => "r9b",
+            Reg::R10 => "r10b",
+            Reg::R11 => "r11b",
+            Reg::R12 => "r12b",
+            Reg::R13 => "r13b",
+            Reg::R14 => "r14b",
+            Reg::R15 => "r15b",
+        }
+    }
+
+    fn name32(self) -> String {
+        match self {
+            Reg::R8 | Reg::R9 | Reg::R10 | Reg::R11 | Reg::R12 | Reg::R13 | Reg::R14 | Reg::R15 => {
+                format!("{self}d")
+            }
+            r => format!("e{}", &r.to_string()[1..]),
+        }
+    }
+}
+
+impl std::fmt::Display for Reg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", format!("{self:?}").to_lowercase())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cond {
+    E,
+    Ne,
+    L,
+    Ge,
+    Le,
+    G,
+}
+
+impl Cond {
+    fn code(self) -> u8 {
+        match self {
+            Cond::E => 0x4,
+            Cond::Ne => 0x5,
+            Cond::L => 0xc,
+            Cond::Ge => 0xd,
+            Cond::Le => 0xe,
+            Cond::G => 0xf,
+        }
+    }
+}
+
+impl std::fmt::Display for Cond {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", format!("{self:?}").to_lowercase())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Label(pub usize);
+
+impl std::fmt::Display for Label {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "L_{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mem {
+    /// `[base + disp]`
+    Base(Reg, i32),
+    /// `[rip + label]`, which may live outside the assembled code
+    Rip(Label),
+}
+
+impl std::fmt::Display for Mem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Mem::Base(r, 0) => write!(f, "[{r}]"),
+            Mem::Base(r, d) if *d < 0 => write!(f, "[{r}-{}]", -(*d as i64)),
+            Mem::Base(r, d) => write!(f, "[{r}+{d}]"),
+            Mem::Rip(l) => write!(f, "[{l}]"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alu {
+    Add,
+    Or,
+    And,
+    Sub,
+    Xor,
+    Cmp,
+}
+
+impl Alu {
+    fn digit(self) -> u8 {
+        match self {
+            Alu::Add => 0,
+            Alu::Or => 1,
+            Alu::And => 4,
+            Alu::Sub => 5,
+            Alu::Xor => 6,
+            Alu::Cmp => 7,
+        }
+    }
+}
+
+impl std::fmt::Display for Alu {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", format!("{self:?}").to_lowercase())
+    }
+}
+
+/// The subset of x86-64 the native backends lower to. Operands are 64 bit unless noted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Inst {
+    Label(Label),
+    Push(Reg),
+    PushImm(i32),
+    Pop(Reg),
+    MovImm(Reg, i64),
+    Mov(Reg, Reg),
+    Load(Reg, Mem),
+    Store(Mem, Reg),
+    /// `movzx r64, byte [mem]`
+    LoadByte(Reg, Mem),
+    /// `mov byte [mem], r8`
+    StoreByte(Mem, Reg),
+    Lea(Reg, Mem),
+    Alu(Alu, Reg, Reg),
+    AluImm(Alu, Reg, i32),
+    Test(Reg, Reg),
+    Imul(Reg, Reg),
+    Cqo,
+    Idiv(Reg),
+    /// unsigned `rdx:rax / r`
+    Div(Reg),
+    Neg(Reg),
+    /// `xor r32, r32`, zeroing the whole register
+    Zero(Reg),
+    Setcc(Cond, Reg),
+    /// `movzx r64, r8`
+    Movzx8(Reg, Reg),
+    Jmp(Label),
+    Jcc(Cond, Label),
+    Call(Label),
+    CallReg(Reg),
+    Ret,
+    Syscall,
+}
+
+/// Formats the instruction in fasm syntax.
+impl std::fmt::Display for Inst {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Inst::Label(l) => write!(f, "{l}:"),
+            Inst::Push(r) => write!(f, "    push {r}"),
+            Inst::PushImm(n) => write!(f, "    push qword {n}"),
+            Inst::Pop(r) => write!(f, "    pop {r}"),
+            Inst::MovImm(r, n) => write!(f, "    mov {r}, {n}"),
+            Inst::Mov(d, s) => write!(f, "    mov {d}, {s}"),
+            Inst::Load(d, m) => write!(f, "    mov {d}, qword {m}"),
+            Inst::Store(m, s) => write!(f, "    mov qword {m}, {s}"),
+            Inst::LoadByte(d, m) => write!(f, "    movzx {d}, byte {m}"),
+            Inst::StoreByte(m, s) => write!(f, "    mov byte {m}, {}", s.name8()),
+            Inst::Lea(d, m) => write!(f, "    lea {d}, {m}"),
+            Inst::Alu(op, d, s) => write!(f, "    {op} {d}, {s}"),
+            Inst::AluImm(op, d, n) => write!(f, "    {op} {d}, {n}"),
+            Inst::Test(d, s) => write!(f, "    test {d}, {s}"),
+            Inst::Imul(d, s) => write!(f, "    imul {d}, {s}"),
+            Inst::Cqo => write!(f, "    cqo"),
+            Inst::Idiv(r) => write!(f, "    idiv {r}"),
+            Inst::Div(r) => write!(f, "    div {r}"),
+            Inst::Neg(r) => write!(f, "    neg {r}"),
+            Inst::Zero(r) => write!(f, "    xor {0}, {0}", r.name32()),
+            Inst::Setcc(c, r) => write!(f, "    set{c} {}", r.name8()),
+            Inst::Movzx8(d, s) => write!(f, "    movzx {d}, {}", s.name8()),
+            Inst::Jmp(l) => write!(f, "    jmp {l}"),
+            Inst::Jcc(c, l) => write!(f, "    j{c} {l}"),
+            Inst::Call(l) => write!(f, "    call {l}"),
+            Inst::CallReg(r) => write!(f, "    call {r}"),
+            Inst::Ret => write!(f, "    ret"),
+            Inst::Syscall => write!(f, "    syscall"),
+        }
+    }
+}
+
+/// A growing instruction stream with a label allocator.
+#[derive(Debug, Default)]
+pub struct Asm {
+    pub insts: Vec<Inst>,
+    next_label: usize,
+}
+
+/// Machine code for an [`Asm`], with the offset of every label it defined.
+#[derive(Debug)]
+pub struct Assembled {
+    pub code: Vec<u8>,
+    pub labels: Vec<Option<usize>>,
+    /// `(offset, label)` of each rel32 displacement to a label defined outside the code, still
+    /// holding the displacement from the end of the field to the start of the code
+    pub relocs: Vec<(usize, Label)>,
+}
+
+impl Asm {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn label(&mut self) -> Label {
+        self.next_label += 1;
+        Label(self.next_label - 1)
+    }
+
+    pub fn emit(&mut self, inst: Inst) {
+        self.insts.push(inst);
+    }
+
+    pub fn assemble(&self) -> Assembled {
+        let mut code = vec![];
+        let mut labels = vec![None; self.next_label];
+        let mut fixups = vec![];
+        for inst in &self.insts {
+            if let Inst::Label(l) = inst {
+                labels[l.0] = Some(code.len());
+            }
+            if let Some(l) = encode(inst, &mut code) {
+                fixups.push((code.len() - 4, l));
+            }
+        }
+
+        let mut relocs = vec![];
+        for (at, l) in fixups {
+            let rel = match labels[l.0] {
+                Some(to) => to as i64 - (at as i64 + 4),
+                None => {
+                    relocs.push((at, l));
+                    -(at as i64 + 4)
+                }
+            };
+            code[at..at + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+        }
+        Assembled {
+            code,
+            labels,
+            relocs,
+        }
+    }
+}
+
+fn rex(code: &mut Vec<u8>, w: bool, reg: u8, base: u8) {
+    code.push(0x40 | (w as u8) << 3 | (reg & 8) >> 1 | (base & 8) >> 3);
+}
+
+/// `op reg, rm` with both operands registers.
+fn rr(code: &mut Vec<u8>, w: bool, op: &[u8], reg: u8, rm: Reg) {
+    if w || reg >= 8 || rm.ext() != 0 {
+        rex(code, w, reg, rm as u8);
+    }
+    code.extend_from_slice(op);
+    code.push(0xc0 | (reg & 7) << 3 | rm.code());
+}
+
+/// `op reg, [mem]`, returning the label of a rip-relative displacement left to patch.
+fn rm(code: &mut Vec<u8>, op: &[u8], reg: u8, mem: Mem) -> Option<Label> {
+    match mem {
+        Mem::Base(base, disp) => {
+            rex(code, true, reg, base as u8);
+            code.extend_from_slice(op);
+            let (md, short) = match i8::try_from(disp) {
+                Ok(_) => (0x40, true),
+                Err(_) => (0x80, false),
+            };
+            code.push(md | (reg & 7) << 3 | base.code());
+            if base.code() == 4 {
+                code.push(0x24);
+            }
+            match short {
+                true => code.push(disp as i8 as u8),
+                false => code.extend_from_slice(&disp.to_le_bytes()),
+            }
+            None
+        }
+        Mem::Rip(l) => {
+            rex(code, true, reg, 0);
+            code.extend_from_slice(op);
+            code.push((reg & 7) << 3 | 0b101);
+            code.extend_from_slice(&[0; 4]);
+            Some(l)
+        }
+    }
+}
+
+/// Appends the encoding of `inst`, returning the label of a trailing rel32 left to patch.
+fn encode(inst: &Inst, code: &mut Vec<u8>) -> Option<Label> {
+    match *inst {
+        Inst::Label(_) => {}
+        Inst::Push(r) | Inst::Pop(r) => {
+            if r.ext() != 0 {
+                code.push(0x41);
+            }
+            let base = if let Inst::Push(_) = inst { 0x50 } else { 0x58 };
+            code.push(base + r.code());
+        }
+        Inst::PushImm(n) => {
+            code.push(0x68);
+            code.extend_from_slice(&n.to_le_bytes());
+        }
+        Inst::MovImm(r, n) => match i32::try_from(n) {
+            Ok(n) => {
+                rr(code, true, &[0xc7], 0, r);
+                code.extend_from_slice(&n.to_le_bytes());
+            }
+            Err(_) => {
+                rex(code, true, 0, r as u8);
+                code.push(0xb8 + r.code());
+                code.extend_from_slice(&n.to_le_bytes());
+            }
+        },
+        Inst::Mov(d, s) => rr(code, true, &[0x89], s as u8, d),
+        Inst::Load(d, m) => return rm(code, &[0x8b], d as u8, m),
+        Inst::Store(m, s) => return rm(code, &[0x89], s as u8, m),
+        Inst::LoadByte(d, m) => return rm(code, &[0x0f, 0xb6], d as u8, m),
+        Inst::StoreByte(m, s) => return rm(code, &[0x88], s as u8, m),
+        Inst::Lea(d, m) => return rm(code, &[0x8d], d as u8, m),
+        Inst::Alu(op, d, s) => rr(code, true, &[op.digit() << 3 | 1], s as u8, d),
+        Inst::AluImm(op, d, n) => match i8::try_from(n) {
+            Ok(n) => {
+                rr(code, true, &[0x83], op.digit(), d);
+                code.push(n as u8);
+            }
+            Err(_) => {
+                rr(code, true, &[0x81], op.digit(), d);
+                code.extend_from_slice(&n.to_le_bytes());
+            }
+        },
+        Inst::Test(d, s) => rr(code, true, &[0x85], s as u8, d),
+        Inst::Imul(d, s) => rr(code, true, &[0x0f, 0xaf], d as u8, s),
+        Inst::Cqo => code.extend_from_slice(&[0x48, 0x99]),
+        Inst::Idiv(r) => rr(code, true, &[0xf7], 7, r),
+        Inst::Div(r) => rr(code, true, &[0xf7], 6, r),
+        Inst::Neg(r) => rr(code, true, &[0xf7], 3, r),
+        Inst::Zero(r) => rr(code, false, &[0x31], r as u8, r),
+        Inst::Setcc(c, r) => {
+            // a bare REX selects spl/bpl/sil/dil rather than ah/ch/dh/bh
+            rex(code, false, 0, r as u8);
+            code.extend_from_slice(&[0x0f, 0x90 + c.code()]);
+            code.push(0xc0 | r.code());
+        }
+        Inst::Movzx8(d, s) => rr(code, true, &[0x0f, 0xb6], d as u8, s),
+        Inst::Jmp(l) => {
+            code.push(0xe9);
+            code.extend_from_slice(&[0; 4]);
+            return Some(l);
+        }
+        Inst::Jcc(c, l) => {
+            code.extend_from_slice(&[0x0f, 0x80 + c.code()]);
+            code.extend_from_slice(&[0; 4]);
+            return Some(l);
+        }
+        Inst::Call(l) => {
+            code.push(0xe8);
+            code.extend_from_slice(&[0; 4]);
+            return Some(l);
+        }
+        Inst::CallReg(r) => rr(code, false, &[0xff], 2, r),
+        Inst::Ret => code.push(0xc3),
+        Inst::Syscall => code.extend_from_slice(&[0x0f, 0x05]),
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes(inst: Inst) -> Vec<u8> {
+        let mut code = vec![];
+        encode(&inst, &mut code);
+        code
+    }
+
+    #[test]
+    fn encodes_register_forms() {
+        assert_eq!(bytes(Inst::Push(Reg::Rbx)), [0x53]);
+        assert_eq!(bytes(Inst::Push(Reg::R12)), [0x41, 0x54]);
+        assert_eq!(bytes(Inst::Pop(Reg::R15)), [0x41, 0x5f]);
+        assert_eq!(bytes(Inst::Mov(Reg::Rbx, Reg::Rdi)), [0x48, 0x89, 0xfb]);
+        assert_eq!(
+            bytes(Inst::Alu(Alu::Add, Reg::Rax, Reg::Rcx)),
+            [0x48, 0x01, 0xc8]
+        );
+        assert_eq!(
+            bytes(Inst::Imul(Reg::Rax, Reg::Rcx)),
+            [0x48, 0x0f, 0xaf, 0xc1]
+        );
+        assert_eq!(bytes(Inst::Idiv(Reg::Rcx)), [0x48, 0xf7, 0xf9]);
+        assert_eq!(bytes(Inst::Cqo), [0x48, 0x99]);
+        assert_eq!(bytes(Inst::Zero(Reg::Rax)), [0x31, 0xc0]);
+        assert_eq!(bytes(Inst::Zero(Reg::R8)), [0x45, 0x31, 0xc0]);
+        assert_eq!(
+            bytes(Inst::Setcc(Cond::E, Reg::Rsi)),
+            [0x40, 0x0f, 0x94, 0xc6]
+        );
+        assert_eq!(bytes(Inst::CallReg(Reg::Rax)), [0xff, 0xd0]);
+        assert_eq!(bytes(Inst::Syscall), [0x0f, 0x05]);
+    }
+
+    #[test]
+    fn encodes_immediates_at_their_smallest_width() {
+        assert_eq!(
+            bytes(Inst::MovImm(Reg::Rax, 1)),
+            [0x48, 0xc7, 0xc0, 1, 0, 0, 0]
+        );
+        assert_eq!(
+            bytes(Inst::MovImm(Reg::Rax, 1 << 32)),
+            [0x48, 0xb8, 0, 0, 0, 0, 1, 0, 0, 0]
+        );
+        assert_eq!(
+            bytes(Inst::AluImm(Alu::And, Reg::Rsp, -16)),
+            [0x48, 0x83, 0xe4, 0xf0]
+        );
+        assert_eq!(
+            bytes(Inst::AluImm(Alu::Sub, Reg::Rsp, 0x100)),
+            [0x48, 0x81, 0xec, 0, 1, 0, 0]
+        );
+    }
+
+    #[test]
+    fn encodes_memory_operands() {
+        let store = Inst::Store(Mem::Base(Reg::Rbx, 8), Reg::Rcx);
+        assert_eq!(bytes(store), [0x48, 0x89, 0x4b, 0x08]);
+        // rsp and r12 as a base need a SIB byte
+        let load = Inst::Load(Reg::Rax, Mem::Base(Reg::Rsp, 0));
+        assert_eq!(bytes(load), [0x48, 0x8b, 0x44, 0x24, 0x00]);
+        let load = Inst::Load(Reg::Rax, Mem::Base(Reg::R12, 0x100));
+        assert_eq!(bytes(load), [0x49, 0x8b, 0x84, 0x24, 0, 1, 0, 0]);
+    }
+
+    #[test]
+    fn patches_jumps_and_reports_undefined_labels() {
+        let mut asm = Asm::new();
+        let (back, outside) = (asm.label(), asm.label());
+        asm.emit(Inst::Label(back));
+        asm.emit(Inst::Jmp(back));
+        asm.emit(Inst::Call(outside));
+        let assembled = asm.assemble();
+        assert_eq!(assembled.code[..5], [0xe9, 0xfb, 0xff, 0xff, 0xff]);
+        assert_eq!(assembled.labels, [Some(0), None]);
+        assert_eq!(assembled.relocs, [(6, outside)]);
+        // displacement from the end of the call to the start of the code
+        assert_eq!(assembled.code[6..], (-10i32).to_le_bytes());
+    }
+}